@@ -0,0 +1,1025 @@
+use crate::savestate::{Snapshot, StateError, StateReader, StateWriter};
+
+/// The NTSC CPU clock, which the APU runs from
+const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+/// The console's output stage has a high-pass filter, which also keeps the stream centred on zero
+const HIGH_PASS_CUTOFF: f64 = 90.0;
+
+const PULSE_1_START: u16 = 0x4000;
+const PULSE_2_START: u16 = 0x4004;
+const TRIANGLE_START: u16 = 0x4008;
+const NOISE_START: u16 = 0x400C;
+const DMC_START: u16 = 0x4010;
+const STATUS: u16 = 0x4015;
+const FRAME_COUNTER: u16 = 0x4017;
+
+const STATUS_PULSE_1: u8 = 0b00000001;
+const STATUS_PULSE_2: u8 = 0b00000010;
+const STATUS_TRIANGLE: u8 = 0b00000100;
+const STATUS_NOISE: u8 = 0b00001000;
+const STATUS_DMC: u8 = 0b00010000;
+const STATUS_FRAME_INTERRUPT: u8 = 0b01000000;
+const STATUS_DMC_INTERRUPT: u8 = 0b10000000;
+
+const FRAME_COUNTER_FIVE_STEP: u8 = 0b10000000;
+const FRAME_COUNTER_IRQ_INHIBIT: u8 = 0b01000000;
+
+/// When the frame counter clocks the envelopes and length counters, in CPU cycles. Ref:
+/// https://www.nesdev.org/wiki/APU_Frame_Counter
+const QUARTER_FRAME_1: u32 = 7457;
+const HALF_FRAME_1: u32 = 14913;
+const QUARTER_FRAME_3: u32 = 22371;
+const FOUR_STEP_HALF_FRAME_2: u32 = 29829;
+const FOUR_STEP_PERIOD: u32 = 29830;
+const FIVE_STEP_HALF_FRAME_2: u32 = 37281;
+const FIVE_STEP_PERIOD: u32 = 37282;
+
+/// Length counter values, indexed by the top five bits written to a channel's fourth register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30
+];
+
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1]
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+];
+
+/// Noise and DMC timer periods in CPU cycles
+const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+/// The volume envelope shared by the pulse and noise channels. It either holds a constant volume or decays
+/// from 15 to 0, optionally looping
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant_volume: bool,
+    /// The constant volume, or the envelope's period
+    volume: u8,
+    divider: u8,
+    decay_level: u8
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.looping = value & 0b00100000 != 0;
+        self.constant_volume = value & 0b00010000 != 0;
+        self.volume = value & 0b00001111;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.looping {
+                self.decay_level = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant_volume { self.volume } else { self.decay_level }
+    }
+}
+
+/// Silences a channel after a set time. Disabling the channel through $4015 clears it
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    value: u8
+}
+
+impl LengthCounter {
+    /// Loads the counter from the value written to the channel's fourth register
+    fn load(&mut self, register_value: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[register_value as usize >> 3];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halted && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.value > 0
+    }
+}
+
+#[derive(Default)]
+struct Pulse {
+    /// The two pulse channels' sweep units negate differently: pulse 1 uses ones' complement
+    is_pulse_1: bool,
+    duty: u8,
+    sequence_step: usize,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length_counter: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8
+}
+
+impl Pulse {
+    fn new(is_pulse_1: bool) -> Self {
+        Self { is_pulse_1, ..Default::default() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.length_counter.halted = value & 0b00100000 != 0;
+                self.envelope.write(value);
+            },
+            1 => {
+                self.sweep_enabled = value & 0b10000000 != 0;
+                self.sweep_period = (value >> 4) & 0b111;
+                self.sweep_negate = value & 0b00001000 != 0;
+                self.sweep_shift = value & 0b111;
+                self.sweep_reload = true;
+            },
+            2 => self.timer_period = (self.timer_period & 0xFF00) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length_counter.load(value);
+                self.sequence_step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    /// Clocked every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = if self.is_pulse_1 { change + 1 } else { change };
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    /// The sweep unit mutes the channel when the period is too short, or would overflow, even if it's disabled
+    fn is_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target_period() > 0x07FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted() {
+            self.timer_period = self.sweep_target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if DUTY_CYCLES[self.duty as usize][self.sequence_step] == 0 || !self.length_counter.is_active() || self.is_muted() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    sequence_step: usize,
+    timer_period: u16,
+    timer: u16,
+    length_counter: LengthCounter,
+    /// The control flag both halts the length counter and stops the linear counter reload flag being cleared
+    control: bool,
+    linear_counter_reload_value: u8,
+    linear_counter: u8,
+    linear_counter_reload: bool
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = value & 0b10000000 != 0;
+                self.length_counter.halted = self.control;
+                self.linear_counter_reload_value = value & 0b01111111;
+            },
+            1 => (),
+            2 => self.timer_period = (self.timer_period & 0xFF00) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length_counter.load(value);
+                self.linear_counter_reload = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length_counter.is_active() && self.linear_counter > 0 {
+                self.sequence_step = (self.sequence_step + 1) % TRIANGLE_SEQUENCE.len();
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear_counter(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.linear_counter_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_counter_reload = false;
+        }
+    }
+
+    /// A silenced triangle holds its last level rather than dropping to zero, which avoids pops
+    fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_step]
+    }
+}
+
+struct Noise {
+    /// Short mode takes the feedback from bit 6 instead of bit 1, giving a metallic tone
+    short_mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+    envelope: Envelope,
+    length_counter: LengthCounter
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            short_mode: false,
+            timer_period: NOISE_PERIODS[0],
+            timer: 0,
+            // The shift register is loaded with 1 on power up
+            shift_register: 1,
+            envelope: Envelope::default(),
+            length_counter: LengthCounter::default()
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.length_counter.halted = value & 0b00100000 != 0;
+                self.envelope.write(value);
+            },
+            1 => (),
+            2 => {
+                self.short_mode = value & 0b10000000 != 0;
+                self.timer_period = NOISE_PERIODS[value as usize & 0b1111];
+            },
+            _ => {
+                self.length_counter.load(value);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    /// Clocked every other CPU cycle. The periods are in CPU cycles, so they're halved here
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period / 2 - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.shift_register & 1 != 0 || !self.length_counter.is_active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+/// The delta modulation channel plays 1-bit delta-encoded samples fetched from CPU memory. Ref:
+/// https://www.nesdev.org/wiki/APU_DMC
+struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    interrupt: bool
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            looping: false,
+            rate: DMC_RATES[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            interrupt: false
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = value & 0b10000000 != 0;
+                if !self.irq_enabled {
+                    self.interrupt = false;
+                }
+                self.looping = value & 0b01000000 != 0;
+                self.rate = DMC_RATES[value as usize & 0b1111];
+            },
+            1 => self.output_level = value & 0b01111111,
+            2 => self.sample_address = 0xC000 | ((value as u16) << 6),
+            _ => self.sample_length = ((value as u16) << 4) + 1
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// The address the memory reader wants to fetch, if the sample buffer is empty and there's more to play
+    fn sample_address_needed(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn load_sample(&mut self, byte: u8) {
+        self.sample_buffer = Some(byte);
+        // The address wraps around to $8000 rather than $0000
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.interrupt = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle. The rates are in CPU cycles
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 { self.output_level += 2 }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift_register = byte;
+                },
+                None => self.silence = true
+            }
+        }
+    }
+}
+
+/// The 2A03's audio processing unit. It's clocked once per CPU cycle, and mixes its five channels into a
+/// stream of f32 samples at the host's sample rate. Ref: https://www.nesdev.org/wiki/APU
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    frame_interrupt: bool,
+    frame_counter: u32,
+    /// Writes to $4017 reset the frame counter after three or four CPU cycles
+    frame_counter_reset_delay: Option<u8>,
+    /// Pulse and noise timers tick on every other CPU cycle
+    odd_cycle: bool,
+    sample_rate: u32,
+    /// Resampling by averaging all the CPU-rate output levels that fall within each output sample
+    cycles_per_sample: f64,
+    cycles_until_sample: f64,
+    sample_sum: f64,
+    sample_count: u32,
+    high_pass_coefficient: f64,
+    previous_input: f64,
+    previous_output: f64,
+    samples: Vec<f32>
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut apu = Self {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_interrupt: false,
+            frame_counter: 0,
+            frame_counter_reset_delay: None,
+            odd_cycle: false,
+            sample_rate: 0,
+            cycles_per_sample: 0.0,
+            cycles_until_sample: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            high_pass_coefficient: 0.0,
+            previous_input: 0.0,
+            previous_output: 0.0,
+            samples: Vec::new()
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu
+    }
+
+    /// Sets the rate of the output stream, such as 44.1 or 48kHz
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.cycles_per_sample = CPU_CLOCK_RATE / sample_rate as f64;
+        self.cycles_until_sample = self.cycles_per_sample;
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * HIGH_PASS_CUTOFF);
+        let dt = 1.0 / sample_rate as f64;
+        self.high_pass_coefficient = rc / (rc + dt);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Hands over the samples generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// The frame counter and the DMC can both interrupt the CPU
+    pub(crate) fn irq_asserted(&self) -> bool {
+        self.frame_interrupt || self.dmc.interrupt
+    }
+
+    /// What reading $4015 would return, without clearing the frame interrupt
+    pub(crate) fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        if self.pulse_1.length_counter.is_active() { status |= STATUS_PULSE_1 }
+        if self.pulse_2.length_counter.is_active() { status |= STATUS_PULSE_2 }
+        if self.triangle.length_counter.is_active() { status |= STATUS_TRIANGLE }
+        if self.noise.length_counter.is_active() { status |= STATUS_NOISE }
+        if self.dmc.bytes_remaining > 0 { status |= STATUS_DMC }
+        if self.frame_interrupt { status |= STATUS_FRAME_INTERRUPT }
+        if self.dmc.interrupt { status |= STATUS_DMC_INTERRUPT }
+        status
+    }
+
+    /// Reads $4015. This acknowledges the frame interrupt
+    pub(crate) fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_interrupt = false;
+        status
+    }
+
+    pub(crate) fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            PULSE_1_START..=0x4003 => self.pulse_1.write(address - PULSE_1_START, value),
+            PULSE_2_START..=0x4007 => self.pulse_2.write(address - PULSE_2_START, value),
+            TRIANGLE_START..=0x400B => self.triangle.write(address - TRIANGLE_START, value),
+            NOISE_START..=0x400F => self.noise.write(address - NOISE_START, value),
+            DMC_START..=0x4013 => self.dmc.write(address - DMC_START, value),
+            STATUS => {
+                self.pulse_1.length_counter.set_enabled(value & STATUS_PULSE_1 != 0);
+                self.pulse_2.length_counter.set_enabled(value & STATUS_PULSE_2 != 0);
+                self.triangle.length_counter.set_enabled(value & STATUS_TRIANGLE != 0);
+                self.noise.length_counter.set_enabled(value & STATUS_NOISE != 0);
+                self.dmc.set_enabled(value & STATUS_DMC != 0);
+                self.dmc.interrupt = false;
+            },
+            FRAME_COUNTER => {
+                self.five_step_mode = value & FRAME_COUNTER_FIVE_STEP != 0;
+                self.irq_inhibit = value & FRAME_COUNTER_IRQ_INHIBIT != 0;
+                if self.irq_inhibit {
+                    self.frame_interrupt = false;
+                }
+                self.frame_counter_reset_delay = Some(if self.odd_cycle { 4 } else { 3 });
+            },
+            _ => ()
+        }
+    }
+
+    /// The address the DMC wants to read its next sample byte from. The bus does the read and hands the byte
+    /// back through `load_dmc_sample`
+    pub(crate) fn dmc_sample_address(&self) -> Option<u16> {
+        self.dmc.sample_address_needed()
+    }
+
+    pub(crate) fn load_dmc_sample(&mut self, byte: u8) {
+        self.dmc.load_sample(byte);
+    }
+
+    /// Advances the APU by one CPU cycle
+    pub(crate) fn tick(&mut self) {
+        self.clock_frame_counter();
+
+        self.triangle.clock_timer();
+        self.dmc.clock_timer();
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+            self.noise.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.generate_sample();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_counter_reset_delay {
+            if delay > 1 {
+                self.frame_counter_reset_delay = Some(delay - 1);
+            } else {
+                self.frame_counter_reset_delay = None;
+                self.frame_counter = 0;
+                // The five-step sequence clocks everything as soon as it starts
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+        }
+
+        self.frame_counter += 1;
+        match self.frame_counter {
+            QUARTER_FRAME_1 | QUARTER_FRAME_3 => self.clock_quarter_frame(),
+            HALF_FRAME_1 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            },
+            _ => ()
+        }
+        if self.five_step_mode {
+            if self.frame_counter == FIVE_STEP_HALF_FRAME_2 {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            } else if self.frame_counter == FIVE_STEP_PERIOD {
+                self.frame_counter = 0;
+            }
+        } else {
+            // The interrupt flag is set over the last three cycles of the sequence
+            if self.frame_counter >= FOUR_STEP_HALF_FRAME_2 - 1 && !self.irq_inhibit {
+                self.frame_interrupt = true;
+            }
+            if self.frame_counter == FOUR_STEP_HALF_FRAME_2 {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            } else if self.frame_counter == FOUR_STEP_PERIOD {
+                self.frame_counter = 0;
+            }
+        }
+    }
+
+    /// Envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    /// Length counters and sweeps
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.length_counter.clock();
+        self.pulse_2.length_counter.clock();
+        self.triangle.length_counter.clock();
+        self.noise.length_counter.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    /// The non-linear mixer, using the formulas from https://www.nesdev.org/wiki/APU_Mixer
+    fn mix(&self) -> f64 {
+        let pulse = (self.pulse_1.output() + self.pulse_2.output()) as f64;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+        let tnd = self.triangle.output() as f64 / 8227.0 + self.noise.output() as f64 / 12241.0 + self.dmc.output_level as f64 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+        pulse_out + tnd_out
+    }
+
+    fn generate_sample(&mut self) {
+        self.sample_sum += self.mix();
+        self.sample_count += 1;
+        self.cycles_until_sample -= 1.0;
+        if self.cycles_until_sample > 0.0 {
+            return;
+        }
+        self.cycles_until_sample += self.cycles_per_sample;
+
+        let input = self.sample_sum / self.sample_count as f64;
+        self.sample_sum = 0.0;
+        self.sample_count = 0;
+        let output = self.high_pass_coefficient * (self.previous_output + input - self.previous_input);
+        self.previous_input = input;
+        self.previous_output = output;
+        self.samples.push(output as f32);
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.start);
+        state.write_bool(self.looping);
+        state.write_bool(self.constant_volume);
+        state.write_u8(self.volume);
+        state.write_u8(self.divider);
+        state.write_u8(self.decay_level);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.start = state.read_bool()?;
+        self.looping = state.read_bool()?;
+        self.constant_volume = state.read_bool()?;
+        self.volume = state.read_u8()?;
+        self.divider = state.read_u8()?;
+        self.decay_level = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.halted);
+        state.write_u8(self.value);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.value = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.duty);
+        state.write_u8(self.sequence_step as u8);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        self.envelope.save_state(state);
+        self.length_counter.save_state(state);
+        state.write_bool(self.sweep_enabled);
+        state.write_u8(self.sweep_period);
+        state.write_bool(self.sweep_negate);
+        state.write_u8(self.sweep_shift);
+        state.write_bool(self.sweep_reload);
+        state.write_u8(self.sweep_divider);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.read_u8()? & 0b11;
+        self.sequence_step = state.read_u8()? as usize % 8;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.envelope.load_state(state)?;
+        self.length_counter.load_state(state)?;
+        self.sweep_enabled = state.read_bool()?;
+        self.sweep_period = state.read_u8()?;
+        self.sweep_negate = state.read_bool()?;
+        self.sweep_shift = state.read_u8()?;
+        self.sweep_reload = state.read_bool()?;
+        self.sweep_divider = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Triangle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.sequence_step as u8);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        self.length_counter.save_state(state);
+        state.write_bool(self.control);
+        state.write_u8(self.linear_counter_reload_value);
+        state.write_u8(self.linear_counter);
+        state.write_bool(self.linear_counter_reload);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sequence_step = state.read_u8()? as usize % TRIANGLE_SEQUENCE.len();
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.length_counter.load_state(state)?;
+        self.control = state.read_bool()?;
+        self.linear_counter_reload_value = state.read_u8()?;
+        self.linear_counter = state.read_u8()?;
+        self.linear_counter_reload = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.short_mode);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u16(self.shift_register);
+        self.envelope.save_state(state);
+        self.length_counter.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.short_mode = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.shift_register = state.read_u16()?;
+        self.envelope.load_state(state)?;
+        self.length_counter.load_state(state)?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Dmc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.looping);
+        state.write_u16(self.rate);
+        state.write_u16(self.timer);
+        state.write_u8(self.output_level);
+        state.write_u16(self.sample_address);
+        state.write_u16(self.sample_length);
+        state.write_u16(self.current_address);
+        state.write_u16(self.bytes_remaining);
+        state.write_bool(self.sample_buffer.is_some());
+        state.write_u8(self.sample_buffer.unwrap_or(0));
+        state.write_u8(self.shift_register);
+        state.write_u8(self.bits_remaining);
+        state.write_bool(self.silence);
+        state.write_bool(self.interrupt);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = state.read_bool()?;
+        self.looping = state.read_bool()?;
+        self.rate = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.output_level = state.read_u8()?;
+        self.sample_address = state.read_u16()?;
+        self.sample_length = state.read_u16()?;
+        self.current_address = state.read_u16()?;
+        self.bytes_remaining = state.read_u16()?;
+        let has_sample = state.read_bool()?;
+        let sample = state.read_u8()?;
+        self.sample_buffer = has_sample.then_some(sample);
+        self.shift_register = state.read_u8()?;
+        self.bits_remaining = state.read_u8()?;
+        self.silence = state.read_bool()?;
+        self.interrupt = state.read_bool()?;
+        Ok(())
+    }
+}
+
+/// The sample rate and samples already produced belong to the host, so they're left as they are
+impl Snapshot for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        state.write_bool(self.five_step_mode);
+        state.write_bool(self.irq_inhibit);
+        state.write_bool(self.frame_interrupt);
+        state.write_u32(self.frame_counter);
+        state.write_bool(self.frame_counter_reset_delay.is_some());
+        state.write_u8(self.frame_counter_reset_delay.unwrap_or(0));
+        state.write_bool(self.odd_cycle);
+        state.write_f64(self.previous_input);
+        state.write_f64(self.previous_output);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.five_step_mode = state.read_bool()?;
+        self.irq_inhibit = state.read_bool()?;
+        self.frame_interrupt = state.read_bool()?;
+        self.frame_counter = state.read_u32()?;
+        let has_reset_delay = state.read_bool()?;
+        let reset_delay = state.read_u8()?;
+        self.frame_counter_reset_delay = has_reset_delay.then_some(reset_delay);
+        self.odd_cycle = state.read_bool()?;
+        self.previous_input = state.read_f64()?;
+        self.previous_output = state.read_f64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_cycles(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn test_length_counters_show_in_status() {
+        let mut apu = Apu::new();
+        // Loading the length counter of a disabled channel does nothing
+        apu.write_register(0x4003, 0b00001000);
+        assert_eq!(apu.peek_status() & STATUS_PULSE_1, 0);
+
+        apu.write_register(STATUS, STATUS_PULSE_1 | STATUS_NOISE);
+        apu.write_register(0x4003, 0b00011000);
+        apu.write_register(0x400F, 0b00011000);
+        assert_eq!(apu.peek_status(), STATUS_PULSE_1 | STATUS_NOISE);
+
+        // A length of 2 runs out after two half frames
+        run_cycles(&mut apu, FOUR_STEP_HALF_FRAME_2);
+        assert_eq!(apu.peek_status() & (STATUS_PULSE_1 | STATUS_NOISE), 0);
+
+        apu.write_register(0x400F, 0b00011000);
+        apu.write_register(STATUS, 0);
+        assert_eq!(apu.peek_status() & STATUS_NOISE, 0);
+    }
+
+    #[test]
+    fn test_four_step_frame_interrupt() {
+        let mut apu = Apu::new();
+        run_cycles(&mut apu, FOUR_STEP_HALF_FRAME_2 - 2);
+        assert!(!apu.irq_asserted());
+        run_cycles(&mut apu, 1);
+        assert!(apu.irq_asserted());
+        // Reading the status acknowledges it
+        assert_eq!(apu.read_status() & STATUS_FRAME_INTERRUPT, STATUS_FRAME_INTERRUPT);
+        assert!(!apu.irq_asserted());
+
+        // Neither the inhibited four-step nor the five-step sequence interrupts
+        for mode in [FRAME_COUNTER_IRQ_INHIBIT, FRAME_COUNTER_FIVE_STEP] {
+            apu.write_register(FRAME_COUNTER, mode);
+            run_cycles(&mut apu, FIVE_STEP_PERIOD * 2);
+            assert!(!apu.irq_asserted());
+        }
+    }
+
+    #[test]
+    fn test_five_step_mode_clocks_immediately() {
+        let mut apu = Apu::new();
+        apu.write_register(STATUS, STATUS_TRIANGLE);
+        // Length index 3 is a length of 2
+        apu.write_register(0x400B, 3 << 3);
+        apu.write_register(FRAME_COUNTER, FRAME_COUNTER_FIVE_STEP);
+        run_cycles(&mut apu, 4);
+        assert_eq!(apu.triangle.length_counter.value, 1);
+    }
+
+    #[test]
+    fn test_pulse_sweep_mutes_on_overflow() {
+        let mut apu = Apu::new();
+        apu.write_register(STATUS, STATUS_PULSE_1);
+        apu.write_register(0x4000, 0b10111111);
+        apu.write_register(0x4002, 0xFF);
+        apu.write_register(0x4003, 0b00000011);
+        assert!(!apu.pulse_1.is_muted());
+        // With a shift of 0 the target period is double, beyond $7FF
+        apu.write_register(0x4003, 0b00000111);
+        assert!(apu.pulse_1.is_muted());
+        assert_eq!(apu.pulse_1.output(), 0);
+    }
+
+    #[test]
+    fn test_dmc_fetches_samples_and_interrupts() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4010, 0b10001111);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(STATUS, STATUS_DMC);
+        assert_eq!(apu.dmc_sample_address(), Some(0xC040));
+        apu.load_dmc_sample(0xFF);
+        // The single byte sample is finished as soon as it's fetched
+        assert_eq!(apu.dmc_sample_address(), None);
+        assert!(apu.irq_asserted());
+        assert_eq!(apu.peek_status() & STATUS_DMC, 0);
+
+        // Writing to $4015 acknowledges the interrupt
+        apu.write_register(STATUS, 0);
+        assert!(!apu.irq_asserted());
+    }
+
+    #[test]
+    fn test_samples_are_generated_at_the_sample_rate() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(48_000);
+        apu.write_register(STATUS, STATUS_PULSE_1);
+        apu.write_register(0x4000, 0b10111111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0b00000000);
+        run_cycles(&mut apu, CPU_CLOCK_RATE as u32 / 10);
+        let samples = apu.take_samples();
+        assert!((4799..=4801).contains(&samples.len()));
+        assert!(samples.iter().any(|sample| sample.abs() > 0.01));
+        assert!(apu.take_samples().is_empty());
+    }
+}
@@ -0,0 +1,732 @@
+use std::{collections::HashMap, fmt::Display, sync::OnceLock};
+
+use crate::{bus::Bus, instruction::{AddressingMode, CpuVariant, Instruction, Opcode}};
+
+#[derive(PartialEq, Debug)]
+pub enum AssemblyErrorKind {
+    /// Something that isn't a label, constant, directive or instruction
+    Syntax(String),
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    /// The instruction exists, but not with this addressing mode
+    UnsupportedAddressingMode(Opcode, AddressingMode),
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    /// A branch target more than 128 bytes away
+    BranchOutOfRange(i64),
+    /// A value too large for the byte or word it's going into
+    ValueOutOfRange(i64)
+}
+
+#[derive(PartialEq, Debug)]
+pub struct AssemblyError {
+    /// Starting from 1, as in an editor
+    pub line: usize,
+    pub kind: AssemblyErrorKind
+}
+
+impl Display for AssemblyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AssemblyErrorKind::Syntax(text) => write!(f, "can't parse {:?}", text),
+            AssemblyErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown instruction {}", mnemonic),
+            AssemblyErrorKind::UnknownDirective(directive) => write!(f, "unknown directive {}", directive),
+            AssemblyErrorKind::UnsupportedAddressingMode(opcode, mode) => write!(f, "{:?} can't use {:?} addressing", opcode, mode),
+            AssemblyErrorKind::UndefinedSymbol(symbol) => write!(f, "{} isn't defined", symbol),
+            AssemblyErrorKind::DuplicateSymbol(symbol) => write!(f, "{} is already defined", symbol),
+            AssemblyErrorKind::BranchOutOfRange(offset) => write!(f, "branch target is {} bytes away, out of range", offset),
+            AssemblyErrorKind::ValueOutOfRange(value) => write!(f, "{} doesn't fit", value)
+        }
+    }
+}
+
+impl std::error::Error for AssemblyError {}
+
+/// Bytes to be loaded at an address. Each .org starts a new segment
+#[derive(PartialEq, Debug)]
+pub struct Segment {
+    pub origin: u16,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Debug)]
+pub struct Assembly {
+    pub segments: Vec<Segment>,
+    pub labels: HashMap<String, u16>
+}
+
+impl Assembly {
+    /// Copies every segment into flat memory, such as a test bus. A segment running past $FFFF wraps around to
+    /// $0000, as the CPU's addresses do, and memory smaller than 64KiB is mirrored like any other flat bus
+    pub fn load_into(&self, memory: &mut [u8]) {
+        for segment in &self.segments {
+            for (offset, &byte) in segment.bytes.iter().enumerate() {
+                memory.write(segment.origin.wrapping_add(offset as u16), byte);
+            }
+        }
+    }
+}
+
+/// The encoding of every instruction on a chip, built by decoding each opcode byte, so the assembler and
+/// `Instruction::from_bytes_for` can't disagree. Where an instruction has more than one encoding, the official
+/// one is used
+struct OpcodeTable {
+    mnemonics: HashMap<String, Opcode>,
+    encodings: HashMap<(Opcode, AddressingMode), (u8, usize)>
+}
+
+impl OpcodeTable {
+    fn new(variant: CpuVariant) -> Self {
+        let mut mnemonics = HashMap::new();
+        let mut encodings: HashMap<(Opcode, AddressingMode), (u8, usize)> = HashMap::new();
+        let decode = |byte| Instruction::from_bytes_for(variant, byte, (0, 0));
+        for byte in 0..=u8::MAX {
+            let instruction = decode(byte);
+            mnemonics.insert(format!("{:?}", instruction.opcode), instruction.opcode);
+            let key = (instruction.opcode, instruction.addressing_mode);
+            let replaces_unofficial = encodings.get(&key).is_some_and(|&(existing, _)| {
+                decode(existing).is_unofficial()
+            });
+            if !encodings.contains_key(&key) || (replaces_unofficial && !instruction.is_unofficial()) {
+                encodings.insert(key, (byte, instruction.width));
+            }
+        }
+        OpcodeTable { mnemonics, encodings }
+    }
+
+    fn has(&self, opcode: Opcode, addressing_mode: AddressingMode) -> bool {
+        self.encodings.contains_key(&(opcode, addressing_mode))
+    }
+}
+
+/// The NMOS chips share an instruction set, so there's a table for them and one for the 65C02
+fn opcode_table(variant: CpuVariant) -> &'static OpcodeTable {
+    static NMOS_TABLE: OnceLock<OpcodeTable> = OnceLock::new();
+    static CMOS_TABLE: OnceLock<OpcodeTable> = OnceLock::new();
+    let table = match variant {
+        CpuVariant::Ricoh2A03 | CpuVariant::Nmos6502 => &NMOS_TABLE,
+        CpuVariant::Wdc65C02 => &CMOS_TABLE
+    };
+    table.get_or_init(|| OpcodeTable::new(variant))
+}
+
+#[derive(Clone, Copy, Debug)]
+enum UnaryOperator {
+    Negate,
+    Not,
+    LowByte,
+    HighByte
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BinaryOperator {
+    Or,
+    Xor,
+    And,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder
+}
+
+#[derive(Debug)]
+enum Expression {
+    Number(i64),
+    Symbol(String),
+    /// `*`, the address of the current statement
+    CurrentAddress,
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>)
+}
+
+impl Expression {
+    fn evaluate(&self, symbols: &HashMap<String, i64>, address: u16) -> Result<i64, AssemblyErrorKind> {
+        Ok(match self {
+            Expression::Number(value) => *value,
+            Expression::Symbol(name) => *symbols.get(name).ok_or_else(|| AssemblyErrorKind::UndefinedSymbol(name.clone()))?,
+            Expression::CurrentAddress => address as i64,
+            Expression::Unary(operator, operand) => {
+                let value = operand.evaluate(symbols, address)?;
+                match operator {
+                    UnaryOperator::Negate => value.checked_neg().ok_or(AssemblyErrorKind::ValueOutOfRange(value))?,
+                    UnaryOperator::Not => !value,
+                    UnaryOperator::LowByte => value & 0xFF,
+                    UnaryOperator::HighByte => (value >> 8) & 0xFF
+                }
+            },
+            Expression::Binary(operator, left, right) => {
+                let (left, right) = (left.evaluate(symbols, address)?, right.evaluate(symbols, address)?);
+                match operator {
+                    BinaryOperator::Or => left | right,
+                    BinaryOperator::Xor => left ^ right,
+                    BinaryOperator::And => left & right,
+                    BinaryOperator::ShiftLeft => left.checked_shl(right as u32).unwrap_or(0),
+                    BinaryOperator::ShiftRight => left.checked_shr(right as u32).unwrap_or(0),
+                    BinaryOperator::Add => left.wrapping_add(right),
+                    BinaryOperator::Subtract => left.wrapping_sub(right),
+                    BinaryOperator::Multiply => left.wrapping_mul(right),
+                    BinaryOperator::Divide | BinaryOperator::Remainder if right == 0 => {
+                        return Err(AssemblyErrorKind::ValueOutOfRange(right));
+                    },
+                    // Which leaves the most negative number divided by -1 as the only overflow
+                    BinaryOperator::Divide => left.checked_div(right).ok_or(AssemblyErrorKind::ValueOutOfRange(left))?,
+                    BinaryOperator::Remainder => left.checked_rem(right).ok_or(AssemblyErrorKind::ValueOutOfRange(left))?
+                }
+            }
+        })
+    }
+}
+
+/// A recursive descent parser for expressions, lowest precedence first: `|`, `^`, `&`, shifts, `+ -`, then
+/// `* / %`. Unary `-`, `~`, and `<` and `>` for the low and high bytes bind tightest
+struct ExpressionParser<'a> {
+    text: &'a [u8],
+    position: usize
+}
+
+impl<'a> ExpressionParser<'a> {
+    fn parse(text: &'a str) -> Result<Expression, AssemblyErrorKind> {
+        let mut parser = Self { text: text.as_bytes(), position: 0 };
+        let expression = parser.parse_binary(0)?;
+        parser.skip_whitespace();
+        if parser.position != parser.text.len() {
+            return Err(AssemblyErrorKind::Syntax(text.to_string()));
+        }
+        Ok(expression)
+    }
+
+    fn syntax_error(&self) -> AssemblyErrorKind {
+        AssemblyErrorKind::Syntax(String::from_utf8_lossy(self.text).into_owned())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn take(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.text[self.position..].starts_with(token.as_bytes()) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn operator_at(&mut self, level: usize) -> Option<BinaryOperator> {
+        let operators: &[(&str, BinaryOperator)] = match level {
+            0 => &[("|", BinaryOperator::Or)],
+            1 => &[("^", BinaryOperator::Xor)],
+            2 => &[("&", BinaryOperator::And)],
+            3 => &[("<<", BinaryOperator::ShiftLeft), (">>", BinaryOperator::ShiftRight)],
+            4 => &[("+", BinaryOperator::Add), ("-", BinaryOperator::Subtract)],
+            _ => &[("*", BinaryOperator::Multiply), ("/", BinaryOperator::Divide), ("%", BinaryOperator::Remainder)]
+        };
+        operators.iter().find(|(token, _)| self.take(token)).map(|&(_, operator)| operator)
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expression, AssemblyErrorKind> {
+        const LEVELS: usize = 6;
+        if level == LEVELS {
+            return self.parse_unary();
+        }
+        let mut expression = self.parse_binary(level + 1)?;
+        while let Some(operator) = self.operator_at(level) {
+            let right = self.parse_binary(level + 1)?;
+            expression = Expression::Binary(operator, Box::new(expression), Box::new(right));
+        }
+        Ok(expression)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, AssemblyErrorKind> {
+        let operators = [("-", UnaryOperator::Negate), ("~", UnaryOperator::Not), ("<", UnaryOperator::LowByte), (">", UnaryOperator::HighByte)];
+        for (token, operator) in operators {
+            if self.take(token) {
+                return Ok(Expression::Unary(operator, Box::new(self.parse_unary()?)));
+            }
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, AssemblyErrorKind> {
+        if self.take("(") {
+            let expression = self.parse_binary(0)?;
+            return if self.take(")") { Ok(expression) } else { Err(self.syntax_error()) };
+        }
+        if self.take("*") {
+            return Ok(Expression::CurrentAddress);
+        }
+        if self.take("'") {
+            let character = *self.text.get(self.position).ok_or_else(|| self.syntax_error())?;
+            self.position += 1;
+            return if self.take("'") { Ok(Expression::Number(character as i64)) } else { Err(self.syntax_error()) };
+        }
+
+        let (radix, start) = if self.take("$") {
+            (16, self.position)
+        } else if self.take("%") {
+            (2, self.position)
+        } else {
+            (10, self.position)
+        };
+        let end = start + self.text[start..].iter().take_while(|&&c| c.is_ascii_alphanumeric() || c == b'_').count();
+        let token = std::str::from_utf8(&self.text[start..end]).unwrap();
+        self.position = end;
+        if token.is_empty() {
+            return Err(self.syntax_error());
+        }
+        if radix == 10 && !token.as_bytes()[0].is_ascii_digit() {
+            return Ok(Expression::Symbol(token.to_string()));
+        }
+        i64::from_str_radix(token, radix).map(Expression::Number).map_err(|_| self.syntax_error())
+    }
+}
+
+/// The operand as written, before an addressing mode is chosen. A bare address can be zero page,
+/// absolute or relative depending on the instruction and the value, and the 65C02's `(zp)` and `(abs,X)`
+/// are written the same as the NMOS indirect modes
+#[derive(Debug)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expression),
+    Address(Expression),
+    AddressX(Expression),
+    AddressY(Expression),
+    Indirect(Expression),
+    IndexedIndirect(Expression),
+    IndirectIndexed(Expression),
+    /// A zero page address and a branch target, for BBR and BBS
+    AddressAndTarget(Expression, Expression)
+}
+
+impl Operand {
+    fn parse(text: &str) -> Result<Self, AssemblyErrorKind> {
+        let text = text.trim();
+        // Spacing around the index register doesn't matter, so the shape is matched without it
+        let shape: String = text.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_uppercase();
+        let before_last = |c: char| &text[..text.rfind(c).unwrap()];
+        Ok(if text.is_empty() {
+            Operand::None
+        } else if shape == "A" {
+            Operand::Accumulator
+        } else if let Some(value) = text.strip_prefix('#') {
+            Operand::Immediate(ExpressionParser::parse(value)?)
+        } else if shape.starts_with('(') && shape.ends_with(",X)") {
+            Operand::IndexedIndirect(ExpressionParser::parse(&before_last(',')[1..])?)
+        } else if shape.starts_with('(') && shape.ends_with("),Y") {
+            Operand::IndirectIndexed(ExpressionParser::parse(&before_last(')')[1..])?)
+        } else if shape.starts_with('(') && shape.ends_with(')') {
+            Operand::Indirect(ExpressionParser::parse(&text[1..text.len() - 1])?)
+        } else if shape.ends_with(",X") {
+            Operand::AddressX(ExpressionParser::parse(before_last(','))?)
+        } else if shape.ends_with(",Y") {
+            Operand::AddressY(ExpressionParser::parse(before_last(','))?)
+        } else if let Some((address, target)) = text.split_once(',') {
+            Operand::AddressAndTarget(ExpressionParser::parse(address)?, ExpressionParser::parse(target)?)
+        } else {
+            Operand::Address(ExpressionParser::parse(text)?)
+        })
+    }
+
+    fn expression(&self) -> Option<&Expression> {
+        match self {
+            Operand::None | Operand::Accumulator => None,
+            Operand::Immediate(expression) | Operand::Address(expression) | Operand::AddressX(expression)
+            | Operand::AddressY(expression) | Operand::Indirect(expression) | Operand::IndexedIndirect(expression)
+            | Operand::IndirectIndexed(expression) | Operand::AddressAndTarget(expression, _) => Some(expression)
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Statement {
+    Org(Expression),
+    Bytes(Vec<Expression>),
+    Words(Vec<Expression>),
+    Constant(String, Expression),
+    Instruction(Opcode, Operand)
+}
+
+struct ParsedLine {
+    number: usize,
+    label: Option<String>,
+    statement: Option<Statement>
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut characters = text.chars();
+    characters.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && characters.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits on commas outside of quotes, so strings and characters can contain them
+fn split_list(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (_, Some(open)) if c == open => quote = None,
+            (',', None) => {
+                items.push(&text[start..index]);
+                start = index + 1;
+            },
+            _ => ()
+        }
+    }
+    items.push(&text[start..]);
+    items
+}
+
+/// Values for .byte and .word. A string in a list of bytes is a byte per character
+fn parse_list(text: &str) -> Result<Vec<Expression>, AssemblyErrorKind> {
+    let mut expressions = Vec::new();
+    for item in split_list(text) {
+        let item = item.trim();
+        if item.len() >= 2 && item.starts_with('"') && item.ends_with('"') {
+            expressions.extend(item[1..item.len() - 1].bytes().map(|byte| Expression::Number(byte as i64)));
+        } else {
+            expressions.push(ExpressionParser::parse(item)?);
+        }
+    }
+    Ok(expressions)
+}
+
+fn parse_line(table: &OpcodeTable, number: usize, line: &str) -> Result<ParsedLine, AssemblyErrorKind> {
+    let mut text = line.split(';').next().unwrap().trim();
+    let mut label = None;
+    if let Some((name, rest)) = text.split_once(':') {
+        if is_identifier(name.trim()) {
+            label = Some(name.trim().to_string());
+            text = rest.trim();
+        }
+    }
+    if text.is_empty() {
+        return Ok(ParsedLine { number, label, statement: None });
+    }
+
+    if let Some((name, value)) = text.split_once('=') {
+        if is_identifier(name.trim()) {
+            let statement = Statement::Constant(name.trim().to_string(), ExpressionParser::parse(value)?);
+            return Ok(ParsedLine { number, label, statement: Some(statement) });
+        }
+    }
+
+    let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let statement = if let Some(directive) = word.strip_prefix('.') {
+        match directive.to_ascii_lowercase().as_str() {
+            "org" => Statement::Org(ExpressionParser::parse(rest)?),
+            "byte" | "db" => Statement::Bytes(parse_list(rest)?),
+            "word" | "dw" => Statement::Words(parse_list(rest)?),
+            _ => return Err(AssemblyErrorKind::UnknownDirective(word.to_string()))
+        }
+    } else {
+        let opcode = *table.mnemonics.get(&word.to_ascii_uppercase())
+            .ok_or_else(|| AssemblyErrorKind::UnknownMnemonic(word.to_string()))?;
+        Statement::Instruction(opcode, Operand::parse(rest)?)
+    };
+    Ok(ParsedLine { number, label, statement: Some(statement) })
+}
+
+/// Picks the addressing mode for an instruction. Addresses that aren't known yet, like forward references,
+/// are assumed to be absolute, and the same choice is kept when the program is encoded
+fn choose_addressing_mode(table: &OpcodeTable, opcode: Opcode, operand: &Operand, value: Option<i64>) -> Result<(AddressingMode, u8, usize), AssemblyErrorKind> {
+    let has = |mode| table.has(opcode, mode);
+    let fits_zero_page = value.is_some_and(|value| (0..=0xFF).contains(&value));
+    let mode = match operand {
+        Operand::None if !has(AddressingMode::Implied) && has(AddressingMode::Accumulator) => AddressingMode::Accumulator,
+        Operand::None => AddressingMode::Implied,
+        Operand::Accumulator => AddressingMode::Accumulator,
+        Operand::Immediate(_) => AddressingMode::Immediate,
+        Operand::Address(_) if has(AddressingMode::Relative) => AddressingMode::Relative,
+        Operand::Address(_) if fits_zero_page && has(AddressingMode::ZeroPage) => AddressingMode::ZeroPage,
+        Operand::Address(_) => AddressingMode::Absolute,
+        Operand::AddressX(_) if fits_zero_page && has(AddressingMode::ZeroPageIndexedX) => AddressingMode::ZeroPageIndexedX,
+        Operand::AddressX(_) => AddressingMode::AbsoluteIndexedX,
+        Operand::AddressY(_) if fits_zero_page && has(AddressingMode::ZeroPageIndexedY) => AddressingMode::ZeroPageIndexedY,
+        Operand::AddressY(_) => AddressingMode::AbsoluteIndexedY,
+        Operand::Indirect(_) if fits_zero_page && has(AddressingMode::ZeroPageIndirect) => AddressingMode::ZeroPageIndirect,
+        Operand::Indirect(_) => AddressingMode::Indirect,
+        Operand::IndexedIndirect(_) if has(AddressingMode::AbsoluteIndexedIndirect) => AddressingMode::AbsoluteIndexedIndirect,
+        Operand::IndexedIndirect(_) => AddressingMode::IndexedIndirect,
+        Operand::IndirectIndexed(_) => AddressingMode::IndirectIndexed,
+        Operand::AddressAndTarget(..) => AddressingMode::ZeroPageRelative
+    };
+    let &(byte, width) = table.encodings.get(&(opcode, mode)).ok_or(AssemblyErrorKind::UnsupportedAddressingMode(opcode, mode))?;
+    Ok((mode, byte, width))
+}
+
+fn to_byte(value: i64) -> Result<u8, AssemblyErrorKind> {
+    if (-0x80..=0xFF).contains(&value) { Ok(value as u8) } else { Err(AssemblyErrorKind::ValueOutOfRange(value)) }
+}
+
+fn to_word(value: i64) -> Result<[u8; 2], AssemblyErrorKind> {
+    if (-0x8000..=0xFFFF).contains(&value) { Ok((value as u16).to_le_bytes()) } else { Err(AssemblyErrorKind::ValueOutOfRange(value)) }
+}
+
+/// Assembles 6502 source. Each line can have a `label:`, then either an instruction, a directive (`.org`,
+/// `.byte` or `.word`) or a constant (`NAME = value`). Comments start with `;`. Numbers are decimal, `$hex`,
+/// `%binary` or `'c'`, and `*` is the current address. Operands are written as in the disassembler, e.g.
+/// `#$10`, `$10,X`, `($10),Y`, `(label)`. Code starts at $0000 until the first .org.
+pub fn assemble(source: &str) -> Result<Assembly, AssemblyError> {
+    assemble_for(CpuVariant::default(), source)
+}
+
+/// Assembles source for a particular chip's instruction set. The 65C02 adds `($10)` and `($1234,X)`, and
+/// BBR and BBS take a zero page address and a branch target, as in `BBR0 $10,label`
+pub fn assemble_for(variant: CpuVariant, source: &str) -> Result<Assembly, AssemblyError> {
+    let table = opcode_table(variant);
+    let lines = source.lines().enumerate()
+        .map(|(index, line)| parse_line(table, index + 1, line).map_err(|kind| AssemblyError { line: index + 1, kind }))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // The first pass works out where everything goes, and with that the value of every label
+    let mut symbols: HashMap<String, i64> = HashMap::new();
+    let mut labels = HashMap::new();
+    let mut chosen_modes = HashMap::new();
+    let mut address: u16 = 0;
+    for line in &lines {
+        let error = |kind| AssemblyError { line: line.number, kind };
+        if let Some(label) = &line.label {
+            if symbols.insert(label.clone(), address as i64).is_some() {
+                return Err(error(AssemblyErrorKind::DuplicateSymbol(label.clone())));
+            }
+            labels.insert(label.clone(), address);
+        }
+        match &line.statement {
+            Some(Statement::Org(origin)) => address = to_word(origin.evaluate(&symbols, address).map_err(error)?).map(u16::from_le_bytes).map_err(error)?,
+            Some(Statement::Bytes(values)) => address = address.wrapping_add(values.len() as u16),
+            Some(Statement::Words(values)) => address = address.wrapping_add(2 * values.len() as u16),
+            Some(Statement::Constant(name, value)) => {
+                let value = value.evaluate(&symbols, address).map_err(error)?;
+                if symbols.insert(name.clone(), value).is_some() {
+                    return Err(error(AssemblyErrorKind::DuplicateSymbol(name.clone())));
+                }
+            },
+            Some(Statement::Instruction(opcode, operand)) => {
+                let value = operand.expression().and_then(|expression| expression.evaluate(&symbols, address).ok());
+                let chosen = choose_addressing_mode(table, *opcode, operand, value).map_err(error)?;
+                address = address.wrapping_add(chosen.2 as u16);
+                chosen_modes.insert(line.number, chosen);
+            },
+            None => ()
+        }
+    }
+
+    // The second pass encodes everything, now that every symbol is known
+    let mut segments = vec![Segment { origin: 0, bytes: Vec::new() }];
+    let mut address: u16 = 0;
+    for line in &lines {
+        let error = |kind| AssemblyError { line: line.number, kind };
+        let evaluate = |expression: &Expression| expression.evaluate(&symbols, address).map_err(error);
+        let mut output = Vec::new();
+        match &line.statement {
+            Some(Statement::Org(origin)) => {
+                address = u16::from_le_bytes(to_word(evaluate(origin)?).map_err(error)?);
+                segments.push(Segment { origin: address, bytes: Vec::new() });
+                continue;
+            },
+            Some(Statement::Bytes(values)) => {
+                for value in values {
+                    output.push(to_byte(evaluate(value)?).map_err(error)?);
+                }
+            },
+            Some(Statement::Words(values)) => {
+                for value in values {
+                    output.extend(to_word(evaluate(value)?).map_err(error)?);
+                }
+            },
+            Some(Statement::Instruction(_, operand)) => {
+                let (mode, byte, width) = chosen_modes[&line.number];
+                // Branches are relative to the end of the instruction
+                let branch_offset = |target: i64| {
+                    let offset = target - (address as i64 + width as i64);
+                    if (-0x80..=0x7F).contains(&offset) { Ok(offset as u8) } else { Err(error(AssemblyErrorKind::BranchOutOfRange(offset))) }
+                };
+                output.push(byte);
+                if let Some(expression) = operand.expression() {
+                    let value = evaluate(expression)?;
+                    match operand {
+                        _ if mode == AddressingMode::Relative => output.push(branch_offset(value)?),
+                        Operand::AddressAndTarget(_, target) => {
+                            output.push(to_byte(value).map_err(error)?);
+                            output.push(branch_offset(evaluate(target)?)?);
+                        },
+                        _ if width == 2 => output.push(to_byte(value).map_err(error)?),
+                        _ => output.extend(to_word(value).map_err(error)?)
+                    }
+                }
+            },
+            Some(Statement::Constant(..)) | None => ()
+        }
+        address = address.wrapping_add(output.len() as u16);
+        segments.last_mut().unwrap().bytes.extend(output);
+    }
+
+    segments.retain(|segment| !segment.bytes.is_empty());
+    Ok(Assembly { segments, labels })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble_for;
+
+    fn assemble_bytes(source: &str) -> Vec<u8> {
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.segments.len(), 1);
+        assembly.segments.into_iter().next().unwrap().bytes
+    }
+
+    fn error_kind(source: &str) -> AssemblyErrorKind {
+        assemble(source).unwrap_err().kind
+    }
+
+    #[test]
+    fn test_every_addressing_mode() {
+        let source = "
+            .org $8000
+            ASL
+            ROL A
+            LDA #$10
+            LDA $10
+            LDA $10,X
+            LDX $10,Y
+            LDA $1234
+            LDA $1234,X
+            LDA $1234,Y
+            JMP ($FFFC)
+            LDA ($40,X)
+            LDA ($40),Y
+            BNE *
+        ";
+        assert_eq!(assemble_bytes(source), [
+            0x0A, 0x2A, 0xA9, 0x10, 0xA5, 0x10, 0xB5, 0x10, 0xB6, 0x10, 0xAD, 0x34, 0x12, 0xBD, 0x34, 0x12,
+            0xB9, 0x34, 0x12, 0x6C, 0xFC, 0xFF, 0xA1, 0x40, 0xB1, 0x40, 0xD0, 0xFE
+        ]);
+    }
+
+    #[test]
+    fn test_labels_constants_and_expressions() {
+        let source = "
+            SCREEN = $2000
+            COUNT = 4 * 2 + 1  ; a comment
+                .org $C000
+            start:  LDX #COUNT
+            loop:   STA SCREEN + 1, X
+                    DEX
+                    BNE loop
+                    JSR later
+                    LDA #<later
+                    LDY #>later
+            later:  RTS
+                    .byte 1, $FF, %101, 'A', -1, \"hi\"
+                    .word start, later - start
+        ";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.labels["later"], 0xC00F);
+        assert_eq!(assembly.segments, [Segment {
+            origin: 0xC000,
+            bytes: vec![
+                0xA2, 0x09, 0x9D, 0x01, 0x20, 0xCA, 0xD0, 0xFA, 0x20, 0x0F, 0xC0, 0xA9, 0x0F, 0xA0, 0xC0, 0x60,
+                0x01, 0xFF, 0x05, 0x41, 0xFF, 0x68, 0x69, 0x00, 0xC0, 0x0F, 0x00
+            ]
+        }]);
+    }
+
+    #[test]
+    fn test_forward_references_stay_absolute() {
+        // The assembler can't know `zero` is on the zero page until it gets there, so it uses absolute addressing
+        assert_eq!(assemble_bytes("LDA zero\nzero = $10\nLDA zero"), [0xAD, 0x10, 0x00, 0xA5, 0x10]);
+    }
+
+    #[test]
+    fn test_org_starts_segments() {
+        let assembly = assemble(".org $FFFC\n.word $8000\n.org $8000\nNOP").unwrap();
+        assert_eq!(assembly.segments, [
+            Segment { origin: 0xFFFC, bytes: vec![0x00, 0x80] },
+            Segment { origin: 0x8000, bytes: vec![0xEA] }
+        ]);
+        let mut memory = vec![0; 0x10000];
+        assembly.load_into(&mut memory);
+        assert_eq!(&memory[0xFFFC..], &[0x00, 0x80, 0x00, 0x00]);
+        assert_eq!(memory[0x8000], 0xEA);
+
+        // Segments wrap around the top of memory, and smaller memory is mirrored
+        let assembly = assemble(".org $FFFE\n.byte 1, 2, 3").unwrap();
+        let mut memory = vec![0; 0x10000];
+        assembly.load_into(&mut memory);
+        assert_eq!((memory[0xFFFE], memory[0xFFFF], memory[0x0000]), (1, 2, 3));
+        let mut memory = vec![0; 0x100];
+        assembly.load_into(&mut memory);
+        assert_eq!((memory[0xFE], memory[0xFF], memory[0x00]), (1, 2, 3));
+        assembly.load_into(&mut []);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error_kind("LDZ #1"), AssemblyErrorKind::UnknownMnemonic("LDZ".into()));
+        assert_eq!(error_kind(".fill 4"), AssemblyErrorKind::UnknownDirective(".fill".into()));
+        assert_eq!(error_kind("STX $1234,X"), AssemblyErrorKind::UnsupportedAddressingMode(Opcode::STX, AddressingMode::AbsoluteIndexedX));
+        assert_eq!(error_kind("JMP nowhere"), AssemblyErrorKind::UndefinedSymbol("nowhere".into()));
+        assert_eq!(error_kind("a: NOP\na: NOP"), AssemblyErrorKind::DuplicateSymbol("a".into()));
+        assert_eq!(error_kind("BNE far\n.org $100\nfar: NOP"), AssemblyErrorKind::BranchOutOfRange(0xFE));
+        assert_eq!(error_kind("LDA #$100"), AssemblyErrorKind::ValueOutOfRange(0x100));
+        assert_eq!(error_kind("LDA #(1"), AssemblyErrorKind::Syntax("(1".into()));
+        assert_eq!(error_kind("LDA #-(1 << 63)"), AssemblyErrorKind::ValueOutOfRange(i64::MIN));
+        assert_eq!(error_kind("LDA #(1 << 63) / -1"), AssemblyErrorKind::ValueOutOfRange(i64::MIN));
+        assert_eq!(error_kind("LDA #(1 << 63) % -1"), AssemblyErrorKind::ValueOutOfRange(i64::MIN));
+        let error = assemble("NOP\nLDA #$100").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: 256 doesn't fit");
+    }
+
+    #[test]
+    fn test_65c02_operands() {
+        let source = "
+            .org $8000
+            LDA ($10)
+            JMP ($1234)
+            JMP ($1234,X)
+            STZ $10,X
+            loop: BBR3 $10,loop
+            BBS7 $FF,*
+            BRA loop
+        ";
+        let assembly = assemble_for(CpuVariant::Wdc65C02, source).unwrap();
+        assert_eq!(assembly.segments[0].bytes, [
+            0xB2, 0x10, 0x6C, 0x34, 0x12, 0x7C, 0x34, 0x12, 0x74, 0x10, 0x3F, 0x10, 0xFD, 0xFF, 0xFF, 0xFD, 0x80, 0xF8
+        ]);
+        // The NMOS chips don't have them
+        assert_eq!(error_kind("BRA *"), AssemblyErrorKind::UnknownMnemonic("BRA".into()));
+        assert_eq!(error_kind("LDA ($10)"), AssemblyErrorKind::UnsupportedAddressingMode(Opcode::LDA, AddressingMode::Indirect));
+        let error = assemble_for(CpuVariant::Wdc65C02, "LAX $10").unwrap_err();
+        assert_eq!(error.kind, AssemblyErrorKind::UnknownMnemonic("LAX".into()));
+    }
+
+    #[test]
+    fn test_every_opcode_round_trips_through_the_disassembler() {
+        for variant in [CpuVariant::Ricoh2A03, CpuVariant::Wdc65C02] {
+            for byte in 0..=u8::MAX {
+                let line = &disassemble_for(variant, &[byte, 0x12, 0x34], 0x8000)[0];
+                let source = format!(".org $8000\n{}", line.text);
+                let bytes = assemble_for(variant, &source).unwrap().segments.remove(0).bytes;
+                if line.is_data() {
+                    assert_eq!(bytes, [byte]);
+                    continue;
+                }
+                // Unofficial duplicates of official instructions assemble to the official encoding
+                let original = Instruction::from_bytes_for(variant, byte, (0x12, 0x34));
+                let assembled = Instruction::from_bytes_for(variant, bytes[0], (0x12, 0x34));
+                assert_eq!((assembled.opcode, assembled.addressing_mode), (original.opcode, original.addressing_mode), "{}", line.text);
+                assert_eq!(&bytes[1..], &[0x12, 0x34][..original.width - 1], "{}", line.text);
+            }
+        }
+    }
+}
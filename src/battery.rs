@@ -0,0 +1,109 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+/// The NES runs at a little over 60 frames a second, which is close enough for timing saves
+pub const FRAMES_PER_SECOND: u64 = 60;
+
+/// Battery-backed work RAM, kept in a .sav file next to the ROM. The file is a raw dump of the RAM, the same
+/// as other emulators use, so saves can be moved between them
+pub struct BatterySave {
+    path: PathBuf,
+    /// How many frames to leave between flushes while running. None only flushes on shutdown
+    interval: Option<u64>,
+    last_flush_frame: u64,
+    /// What was last written, so that flushes only touch the disk when the game has saved something
+    flushed: Vec<u8>
+}
+
+impl BatterySave {
+    pub fn for_rom<P: AsRef<Path>>(rom_path: P, interval: Option<u64>) -> Self {
+        Self {
+            path: rom_path.as_ref().with_extension("sav"),
+            interval,
+            last_flush_frame: 0,
+            flushed: Vec::new()
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Fills the RAM from the save file, returning whether there was one. Saves of a different size are
+    /// loaded as far as they fit
+    pub fn load(&mut self, ram: &mut [u8]) -> io::Result<bool> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error)
+        };
+        let length = contents.len().min(ram.len());
+        ram[..length].copy_from_slice(&contents[..length]);
+        self.flushed = ram.to_vec();
+        Ok(true)
+    }
+
+    /// Writes the RAM to the save file if it has changed since it was last written
+    pub fn flush(&mut self, ram: &[u8]) -> io::Result<()> {
+        if ram.is_empty() || self.flushed == ram {
+            return Ok(());
+        }
+        fs::write(&self.path, ram)?;
+        self.flushed = ram.to_vec();
+        Ok(())
+    }
+
+    /// Flushes if the interval has passed since the last flush. Called as frames are completed
+    pub fn flush_if_due(&mut self, frame: u64, ram: &[u8]) -> io::Result<()> {
+        match self.interval {
+            Some(interval) if frame >= self.last_flush_frame + interval => {
+                self.last_flush_frame = frame;
+                self.flush(ram)
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_rom_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rust-nes-{}-{}.nes", name, std::process::id()));
+        let _ = fs::remove_file(path.with_extension("sav"));
+        path
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let rom_path = temporary_rom_path("round-trip");
+        let mut save = BatterySave::for_rom(&rom_path, None);
+        assert_eq!(save.path(), rom_path.with_extension("sav"));
+
+        let mut ram = vec![0; 0x2000];
+        assert!(!save.load(&mut ram).unwrap());
+        ram[0x123] = 0x45;
+        save.flush(&ram).unwrap();
+
+        let mut reloaded = vec![0; 0x2000];
+        assert!(BatterySave::for_rom(&rom_path, None).load(&mut reloaded).unwrap());
+        assert_eq!(reloaded, ram);
+        fs::remove_file(save.path()).unwrap();
+    }
+
+    #[test]
+    fn test_flushes_at_the_interval() {
+        let rom_path = temporary_rom_path("interval");
+        let mut save = BatterySave::for_rom(&rom_path, Some(10 * FRAMES_PER_SECOND));
+        let ram = vec![0x11; 0x2000];
+        save.flush_if_due(599, &ram).unwrap();
+        assert!(!save.path().exists());
+        save.flush_if_due(600, &ram).unwrap();
+        assert_eq!(fs::read(save.path()).unwrap(), ram);
+        fs::remove_file(save.path()).unwrap();
+
+        // Nothing's written again until the RAM changes
+        save.flush_if_due(1200, &ram).unwrap();
+        assert!(!save.path().exists());
+    }
+}
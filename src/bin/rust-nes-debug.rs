@@ -0,0 +1,25 @@
+use rust_nes::{mapper, Cartridge, Debugger, NesBus};
+
+const USAGE: &str = "Usage: rust-nes-debug <rom.nes>";
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
+
+/// Starts the interactive debugger on a ROM, stopped at the reset vector
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [path] = args.as_slice() else {
+        exit_with_usage();
+    };
+    let mapper = Cartridge::load(path).and_then(|cartridge| mapper::from_cartridge(&cartridge)).unwrap_or_else(|error| {
+        eprintln!("Couldn't load {}: {}", path, error);
+        std::process::exit(1);
+    });
+    let mut debugger = Debugger::new(NesBus::new(mapper));
+    if let Err(error) = debugger.run_repl(std::io::stdin().lock(), std::io::stdout()) {
+        eprintln!("Debugger stopped: {}", error);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,43 @@
+use rust_nes::{disassemble, Cartridge};
+
+const USAGE: &str = "Usage: rust-nes-disasm <rom.nes> [--bank <number>]";
+/// PRG-ROM is disassembled in 16KiB banks, the size most boards switch
+const DISASSEMBLY_BANK_SIZE: usize = 0x4000;
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
+
+/// Prints the disassembly of a ROM's PRG banks, or just one of them
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(path) = args.first() else {
+        exit_with_usage();
+    };
+    let only_bank = match (args.get(1).map(String::as_str), args.get(2)) {
+        (None, _) => None,
+        (Some("--bank"), Some(bank)) if args.len() == 3 => Some(bank.parse::<usize>().unwrap_or_else(|_| exit_with_usage())),
+        _ => exit_with_usage()
+    };
+
+    let cartridge = Cartridge::load(path).unwrap_or_else(|error| {
+        eprintln!("Couldn't load {}: {}", path, error);
+        std::process::exit(1);
+    });
+    let banks: Vec<&[u8]> = cartridge.prg_rom.chunks(DISASSEMBLY_BANK_SIZE).collect();
+    if let Some(bank) = only_bank.filter(|&bank| bank >= banks.len()) {
+        eprintln!("Can't disassemble bank {}: {} only has {} PRG banks", bank, path, banks.len());
+        std::process::exit(1);
+    }
+
+    for (index, bank) in banks.iter().enumerate().filter(|(index, _)| only_bank.is_none_or(|bank| bank == *index)) {
+        // The last bank is usually the one fixed at $C000, where the vectors are
+        let origin = if index == banks.len() - 1 { 0xC000 } else { 0x8000 };
+        println!("; PRG bank {} at ${:04X}", index, origin);
+        for line in disassemble(bank, origin) {
+            println!("{}", line);
+        }
+        println!();
+    }
+}
@@ -0,0 +1,114 @@
+/// The CPU sees everything - RAM, hardware registers and the cartridge - through a 16-bit address
+/// bus. Implementors decide what lives at each address.
+pub trait Bus {
+    /// Reads a byte from the bus. Reads can have side effects on hardware registers, so this takes a
+    /// mutable reference
+    fn read(&mut self, address: u16) -> u8;
+
+    /// Writes a byte to the bus
+    fn write(&mut self, address: u16, value: u8);
+
+    /// Reads a byte from the bus without any side effects. This is for tracing and debugging, where
+    /// looking at memory mustn't change the state of the machine
+    fn peek(&self, address: u16) -> u8;
+
+    /// Called once for every CPU cycle, so that the rest of the system can be clocked alongside it. In
+    /// cycle-accurate mode this happens at the start of each cycle, otherwise the bus catches up after each
+    /// instruction
+    fn tick(&mut self) {}
+
+    /// The page a write to the OAM DMA register asked to be copied to the PPU. The 2A03 does the copy
+    /// itself, so the CPU takes the request after each instruction
+    fn take_oam_dma_request(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// The address of the sample the DMC is waiting for. The 2A03 halts the CPU to fetch it, so the CPU
+    /// checks for a request before each step and hands the byte back through `load_dmc_sample`
+    fn dmc_dma_request(&self) -> Option<u16> {
+        None
+    }
+
+    fn load_dmc_sample(&mut self, _byte: u8) {}
+
+    /// The scanline and dot the PPU is on, for trace logs. Buses without a PPU stay at the start of the frame
+    fn ppu_position(&self) -> (u16, u16) {
+        (0, 0)
+    }
+
+    /// Whether a device on the bus is pulling the NMI line. The CPU detects the rising edge
+    fn nmi_asserted(&self) -> bool {
+        false
+    }
+
+    /// Whether any device on the bus is pulling the IRQ line
+    fn irq_asserted(&self) -> bool {
+        false
+    }
+}
+
+/// A flat array of bytes is the simplest possible bus, where every address is plain RAM. This is
+/// what the nestest binary is run against. Less than 64KiB is mirrored through the address space, as if the
+/// upper address lines weren't connected, and an empty slice reads as zeros.
+impl Bus for [u8] {
+    fn read(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if let Some(index) = mirrored_index(self, address) {
+            self[index] = value;
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        mirrored_index(self, address).map_or(0, |index| self[index])
+    }
+}
+
+fn mirrored_index(memory: &[u8], address: u16) -> Option<usize> {
+    (!memory.is_empty()).then(|| address as usize % memory.len())
+}
+
+/// Lets the CPU borrow a bus rather than own it
+impl<B: Bus + ?Sized> Bus for &mut B {
+    fn read(&mut self, address: u16) -> u8 {
+        (**self).read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        (**self).write(address, value)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        (**self).peek(address)
+    }
+
+    fn tick(&mut self) {
+        (**self).tick()
+    }
+
+    fn take_oam_dma_request(&mut self) -> Option<u8> {
+        (**self).take_oam_dma_request()
+    }
+
+    fn dmc_dma_request(&self) -> Option<u16> {
+        (**self).dmc_dma_request()
+    }
+
+    fn load_dmc_sample(&mut self, byte: u8) {
+        (**self).load_dmc_sample(byte)
+    }
+
+    fn ppu_position(&self) -> (u16, u16) {
+        (**self).ppu_position()
+    }
+
+    fn nmi_asserted(&self) -> bool {
+        (**self).nmi_asserted()
+    }
+
+    fn irq_asserted(&self) -> bool {
+        (**self).irq_asserted()
+    }
+}
@@ -0,0 +1,288 @@
+use std::{fmt::Display, fs, io, path::Path};
+
+/// Every iNES file starts with "NES" followed by an MS-DOS end-of-file character
+const MAGIC: [u8; 4] = [b'N', b'E', b'S', 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_UNIT: usize = 0x4000;
+const CHR_ROM_UNIT: usize = 0x2000;
+const PRG_RAM_UNIT: usize = 0x2000;
+
+/// How the two physical nametables are laid out in the PPU's four nametable slots
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Mirroring {
+    /// $2000 and $2400 share a nametable, as do $2800 and $2C00. Used by vertically scrolling games
+    Horizontal,
+    /// $2000 and $2800 share a nametable, as do $2400 and $2C00. Used by horizontally scrolling games
+    Vertical,
+    /// The cartridge provides extra VRAM so that all four nametables are distinct
+    FourScreen,
+    /// All four slots show the first nametable. Only mappers can select this
+    SingleScreenLower,
+    /// All four slots show the second nametable
+    SingleScreenUpper
+}
+
+/// The console timing the ROM was built for
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum TvSystem {
+    Ntsc,
+    Pal,
+    /// Runs on both NTSC and PAL consoles
+    MultiRegion,
+    Dendy
+}
+
+/// Which revision of the header the ROM was parsed from
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum RomFormat {
+    INes,
+    Nes20
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    /// The file is shorter than the 16 byte header
+    MissingHeader,
+    /// The file doesn't start with "NES\x1A"
+    InvalidMagic,
+    /// The header claims no PRG-ROM, so there would be nothing to execute
+    NoPrgRom,
+    /// The file is shorter than the sections the header says it contains
+    Truncated { expected: usize, actual: usize },
+    /// There's no implementation of the board the header asks for
+    UnsupportedMapper(u16)
+}
+
+impl Display for CartridgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CartridgeError::Io(error) => write!(f, "couldn't read ROM file: {}", error),
+            CartridgeError::MissingHeader => write!(f, "file is too short to contain an iNES header"),
+            CartridgeError::InvalidMagic => write!(f, "file is not an iNES ROM"),
+            CartridgeError::NoPrgRom => write!(f, "header declares no PRG-ROM"),
+            CartridgeError::Truncated { expected, actual } => write!(f, "file should be {} bytes long but is {}", expected, actual),
+            CartridgeError::UnsupportedMapper(mapper) => write!(f, "mapper {} isn't supported", mapper)
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(error: io::Error) -> Self {
+        CartridgeError::Io(error)
+    }
+}
+
+/// The contents of a ROM file in [iNES](https://www.nesdev.org/wiki/INES) or
+/// [NES 2.0](https://www.nesdev.org/wiki/NES_2.0) format
+pub struct Cartridge {
+    pub format: RomFormat,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    /// Size of the CHR-RAM on the board. Boards without CHR-ROM always have some
+    pub chr_ram_size: usize,
+    /// Size of the volatile PRG-RAM (work RAM) on the board
+    pub prg_ram_size: usize,
+    /// Size of the battery-backed PRG-RAM on the board
+    pub prg_nvram_size: usize,
+    /// 512 bytes that some copier devices load into $7000-$71FF
+    pub trainer: Option<Vec<u8>>,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub tv_system: TvSystem
+}
+
+impl Cartridge {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError> {
+        let bytes = fs::read(path)?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CartridgeError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(CartridgeError::MissingHeader);
+        }
+        let header = &bytes[..HEADER_SIZE];
+        if header[0..4] != MAGIC {
+            return Err(CartridgeError::InvalidMagic);
+        }
+
+        let flags_6 = header[6];
+        let flags_7 = header[7];
+        // NES 2.0 is identified by bits 2 and 3 of flags 7 being 0b10
+        let format = if (flags_7 & 0b00001100) == 0b00001000 { RomFormat::Nes20 } else { RomFormat::INes };
+
+        let mirroring = if (flags_6 & 0b00001000) != 0 {
+            Mirroring::FourScreen
+        } else if (flags_6 & 0b00000001) != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let has_battery = (flags_6 & 0b00000010) != 0;
+        let has_trainer = (flags_6 & 0b00000100) != 0;
+
+        let (prg_rom_size, chr_rom_size, chr_ram_size, prg_ram_size, prg_nvram_size, mapper, submapper, tv_system) = match format {
+            RomFormat::Nes20 => {
+                let mapper = (flags_6 >> 4) as u16 | (flags_7 & 0xF0) as u16 | (((header[8] & 0x0F) as u16) << 8);
+                let submapper = header[8] >> 4;
+                let prg_rom_size = Self::nes20_rom_size(header[4], header[9] & 0x0F, PRG_ROM_UNIT);
+                let chr_rom_size = Self::nes20_rom_size(header[5], header[9] >> 4, CHR_ROM_UNIT);
+                let prg_ram_size = Self::nes20_ram_size(header[10] & 0x0F);
+                let prg_nvram_size = Self::nes20_ram_size(header[10] >> 4);
+                // CHR-RAM and CHR-NVRAM are both just RAM as far as the PPU is concerned
+                let chr_ram_size = Self::nes20_ram_size(header[11] & 0x0F) + Self::nes20_ram_size(header[11] >> 4);
+                let tv_system = match header[12] & 0b11 {
+                    0 => TvSystem::Ntsc,
+                    1 => TvSystem::Pal,
+                    2 => TvSystem::MultiRegion,
+                    _ => TvSystem::Dendy
+                };
+                (prg_rom_size, chr_rom_size, chr_ram_size, prg_ram_size, prg_nvram_size, mapper, submapper, tv_system)
+            },
+            RomFormat::INes => {
+                // Old ripping tools wrote their name into bytes 12-15, and in that case the upper nibble of
+                // the mapper number in flags 7 can't be trusted either
+                let upper_mapper_nibble = if header[12..16].iter().any(|&byte| byte != 0) { 0 } else { flags_7 & 0xF0 };
+                let mapper = ((flags_6 >> 4) | upper_mapper_nibble) as u16;
+                let prg_rom_size = header[4] as usize * PRG_ROM_UNIT;
+                let chr_rom_size = header[5] as usize * CHR_ROM_UNIT;
+                // iNES can't describe CHR-RAM, so boards without CHR-ROM are assumed to have 8KiB of it
+                let chr_ram_size = if chr_rom_size == 0 { CHR_ROM_UNIT } else { 0 };
+                // A value of 0 infers 8KiB for compatibility
+                let work_ram_size = header[8].max(1) as usize * PRG_RAM_UNIT;
+                let (prg_ram_size, prg_nvram_size) = if has_battery { (0, work_ram_size) } else { (work_ram_size, 0) };
+                let tv_system = if (header[9] & 0x01) != 0 { TvSystem::Pal } else { TvSystem::Ntsc };
+                (prg_rom_size, chr_rom_size, chr_ram_size, prg_ram_size, prg_nvram_size, mapper, 0, tv_system)
+            }
+        };
+
+        if prg_rom_size == 0 {
+            return Err(CartridgeError::NoPrgRom);
+        }
+
+        let trainer_size = if has_trainer { TRAINER_SIZE } else { 0 };
+        // Exponent-multiplier sizes can be absurdly large, so saturate rather than overflow
+        let expected = (HEADER_SIZE + trainer_size).saturating_add(prg_rom_size).saturating_add(chr_rom_size);
+        if bytes.len() < expected {
+            return Err(CartridgeError::Truncated { expected, actual: bytes.len() });
+        }
+
+        let prg_start = HEADER_SIZE + trainer_size;
+        let chr_start = prg_start + prg_rom_size;
+        Ok(Self {
+            format,
+            prg_rom: bytes[prg_start..chr_start].to_vec(),
+            chr_rom: bytes[chr_start..chr_start + chr_rom_size].to_vec(),
+            chr_ram_size,
+            prg_ram_size,
+            prg_nvram_size,
+            trainer: if has_trainer { Some(bytes[HEADER_SIZE..prg_start].to_vec()) } else { None },
+            mirroring,
+            has_battery,
+            mapper,
+            submapper,
+            tv_system
+        })
+    }
+
+    /// NES 2.0 ROM sizes are a 12-bit count of units, unless the most significant nibble is 0xF, in which case
+    /// the least significant byte is an exponent-multiplier pair of the form EEEEEEMM giving 2^E * (MM * 2 + 1)
+    fn nes20_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0b11) as usize * 2 + 1;
+            2usize.saturating_pow(exponent).saturating_mul(multiplier)
+        } else {
+            (((msb as usize) << 8) | lsb as usize) * unit
+        }
+    }
+
+    /// NES 2.0 RAM sizes are shift counts, where the size is 64 << shift, and a shift of 0 means no RAM
+    fn nes20_ram_size(shift: u8) -> usize {
+        if shift == 0 { 0 } else { 64 << shift }
+    }
+}
+
+impl Display for Cartridge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} mapper {}.{}, {}KiB PRG-ROM, {}KiB CHR-ROM, {}KiB CHR-RAM, {:?} mirroring, {:?}{}",
+            self.format, self.mapper, self.submapper, self.prg_rom.len() / 1024, self.chr_rom.len() / 1024,
+            self.chr_ram_size / 1024, self.mirroring, self.tv_system, if self.has_battery { ", battery" } else { "" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(header: [u8; HEADER_SIZE], body_size: usize) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        bytes.extend((0..body_size).map(|i| i as u8));
+        bytes
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let header = [b'N', b'E', b'S', 0x1A, 2, 1, 0b0001_0011, 0b0100_0000, 0, 1, 0, 0, 0, 0, 0, 0];
+        let cartridge = Cartridge::from_bytes(&rom(header, 2 * PRG_ROM_UNIT + CHR_ROM_UNIT)).unwrap();
+        assert_eq!(cartridge.format, RomFormat::INes);
+        assert_eq!(cartridge.prg_rom.len(), 0x8000);
+        assert_eq!(cartridge.chr_rom.len(), 0x2000);
+        assert_eq!(cartridge.chr_ram_size, 0);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert!(cartridge.has_battery);
+        assert_eq!(cartridge.prg_nvram_size, 0x2000);
+        assert_eq!(cartridge.mapper, 0x41);
+        assert_eq!(cartridge.tv_system, TvSystem::Pal);
+        assert!(cartridge.trainer.is_none());
+    }
+
+    #[test]
+    fn test_ines_header_with_trainer_and_dirty_padding() {
+        let header = [b'N', b'E', b'S', 0x1A, 1, 0, 0b0010_0100, 0b0100_0000, 0, 0, 0, 0, b'D', b'i', b's', b'k'];
+        let cartridge = Cartridge::from_bytes(&rom(header, TRAINER_SIZE + PRG_ROM_UNIT)).unwrap();
+        assert_eq!(cartridge.trainer.as_ref().map(Vec::len), Some(TRAINER_SIZE));
+        assert_eq!(cartridge.prg_rom[0], TRAINER_SIZE as u8);
+        assert_eq!(cartridge.mapper, 2);
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+        assert_eq!(cartridge.mirroring, Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_nes20_header() {
+        let header = [b'N', b'E', b'S', 0x1A, 0x08, 0x00, 0b0001_1010, 0b0000_1000, 0x51, 0x00, 0x70, 0x07, 0x03, 0, 0, 0];
+        let cartridge = Cartridge::from_bytes(&rom(header, 8 * PRG_ROM_UNIT)).unwrap();
+        assert_eq!(cartridge.format, RomFormat::Nes20);
+        assert_eq!(cartridge.mapper, 0x101);
+        assert_eq!(cartridge.submapper, 5);
+        assert_eq!(cartridge.prg_rom.len(), 0x20000);
+        assert_eq!(cartridge.prg_ram_size, 0);
+        assert_eq!(cartridge.prg_nvram_size, 0x2000);
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+        assert_eq!(cartridge.mirroring, Mirroring::FourScreen);
+        assert_eq!(cartridge.tv_system, TvSystem::Dendy);
+    }
+
+    #[test]
+    fn test_nes20_exponent_multiplier_size() {
+        // 2^10 * (1 * 2 + 1) = 3KiB
+        assert_eq!(Cartridge::nes20_rom_size(0b0010_1001, 0x0F, PRG_ROM_UNIT), 3072);
+    }
+
+    #[test]
+    fn test_malformed_files_are_rejected() {
+        assert!(matches!(Cartridge::from_bytes(b"NES"), Err(CartridgeError::MissingHeader)));
+        assert!(matches!(Cartridge::from_bytes(&[0; HEADER_SIZE]), Err(CartridgeError::InvalidMagic)));
+        let header = [b'N', b'E', b'S', 0x1A, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(Cartridge::from_bytes(&header), Err(CartridgeError::NoPrgRom)));
+        let header = [b'N', b'E', b'S', 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(Cartridge::from_bytes(&rom(header, 100)),
+            Err(CartridgeError::Truncated { expected: 0xA010, actual: 116 })));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::savestate::{Snapshot, StateError, StateReader, StateWriter};
+
+/// The buttons on a standard controller, in the order they're shifted out
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right
+}
+
+impl Button {
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// A standard controller. While the strobe is high the shift register is continuously reloaded from the
+/// buttons, and once it goes low each read shifts out the next button. Ref:
+/// https://www.nesdev.org/wiki/Standard_controller
+pub struct Controller {
+    /// The buttons currently held, one bit each in `Button` order
+    buttons: u8,
+    shift_register: u8,
+    strobe: bool
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Self {
+            buttons: 0,
+            shift_register: 0,
+            strobe: false
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.buttons |= button.mask();
+        } else {
+            self.buttons &= !button.mask();
+        }
+        self.reload_if_strobed();
+    }
+
+    /// Sets every button at once, with bit 0 as A through to bit 7 as Right. Handy for replaying scripted
+    /// input a frame at a time
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        self.reload_if_strobed();
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.buttons & button.mask() != 0
+    }
+
+    /// Handles a write to $4016. Only bit 0 is the strobe
+    pub(crate) fn write_strobe(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        self.reload_if_strobed();
+    }
+
+    fn reload_if_strobed(&mut self) {
+        if self.strobe {
+            self.shift_register = self.buttons;
+        }
+    }
+
+    /// Reads the next button into bit 0. After all eight, official controllers return 1
+    pub(crate) fn read(&mut self) -> u8 {
+        let bit = self.peek();
+        if !self.strobe {
+            self.shift_register = (self.shift_register >> 1) | 0b10000000;
+        }
+        bit
+    }
+
+    pub(crate) fn peek(&self) -> u8 {
+        if self.strobe { self.buttons & 1 } else { self.shift_register & 1 }
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot for Controller {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.buttons);
+        state.write_u8(self.shift_register);
+        state.write_bool(self.strobe);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = state.read_u8()?;
+        self.shift_register = state.read_u8()?;
+        self.strobe = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buttons_are_shifted_out_in_order() {
+        let mut controller = Controller::new();
+        controller.set_button(Button::A, true);
+        controller.set_button(Button::Start, true);
+        controller.set_button(Button::Left, true);
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_set_button_matches_bitmask_order() {
+        let buttons = [Button::A, Button::B, Button::Select, Button::Start, Button::Up, Button::Down, Button::Left, Button::Right];
+        let mut controller = Controller::new();
+        for (bit, button) in buttons.into_iter().enumerate() {
+            controller.set_button(button, true);
+            assert_eq!(controller.buttons(), 1 << bit);
+            controller.set_button(button, false);
+        }
+    }
+
+    #[test]
+    fn test_strobe_high_keeps_returning_a() {
+        let mut controller = Controller::new();
+        controller.write_strobe(1);
+        controller.set_buttons(0b00000001);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+        controller.set_button(Button::A, false);
+        assert_eq!(controller.read(), 0);
+        assert!(!controller.is_pressed(Button::A));
+    }
+}
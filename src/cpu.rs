@@ -0,0 +1,2367 @@
+use std::fmt::Display;
+
+use crate::{
+    bus::Bus,
+    instruction::{AddressingMode, CpuVariant, Instruction, Opcode},
+    savestate::{Snapshot, StateError, StateReader, StateWriter},
+    utils::{is_negative, is_zero, to_address_from_bytes, to_bytes_from_address, was_page_boundary_crossed}
+};
+
+/// The 6502 uses two bytes for memory addresses. A flat bus of this size treats every address as
+/// RAM, which is enough for running test binaries. The real memory map is modelled by `NesBus`
+pub const MEMORY_SIZE: usize = u16::MAX as usize + 1;
+const STACK_PAGE : u16 = 0x0100;
+
+/// Interrupt vectors. Each holds the little-endian address the CPU jumps to when servicing
+/// the corresponding interrupt
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+/// Servicing an interrupt takes as long as a BRK
+const INTERRUPT_CYCLES: usize = 7;
+/// OAM DMA copies a page to the PPU through OAMDATA, a read and a write per byte, after a cycle to halt
+/// the CPU. If it starts on an odd cycle it takes another to line up its reads and writes
+const OAM_DATA: u16 = 0x2004;
+const OAM_DMA_CYCLES: usize = 513;
+/// A DMC sample fetch halts the CPU for a cycle, waits up to two more for a read cycle to line up, then reads
+/// the byte. Ref: https://www.nesdev.org/wiki/DMA#DMC_DMA
+const DMC_DMA_CYCLES: usize = 4;
+
+/// How an instruction accesses its operand. Indexed addressing modes only make a dummy read for reads when
+/// the index crosses a page, but writes and read-modify-writes always make one, as the write can't be undone
+#[derive(PartialEq, Clone, Copy)]
+enum Access {
+    Read,
+    Write
+}
+
+/// The processor status register, one field per flag
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct CPUFlags {
+    pub carry: bool,
+    pub zero: bool,
+    pub interrupt_disable: bool,
+    pub decimal_mode: bool,
+    pub break_command: bool,
+    pub overflow: bool,
+    pub negative: bool
+}
+
+impl CPUFlags {
+    pub fn new() -> Self {
+        Self {
+            carry: false,
+            zero: false,
+            interrupt_disable: false,
+            decimal_mode: false,
+            break_command: false,
+            overflow: false,
+            negative: false
+        }
+    }
+
+    pub fn set_from_byte(&mut self, byte: u8) {
+        self.carry = (0b00000001 & byte) == 1;
+        self.zero = ((0b00000010 & byte) >> 1) == 1;
+        self.interrupt_disable = ((0b00000100 & byte) >> 2) == 1;
+        self.decimal_mode = ((0b00001000 & byte) >> 3) == 1;
+        self.break_command = ((0b00010000 & byte) >> 4) == 1;
+        // Bit 5 is ignored
+        self.overflow = ((0b01000000 & byte) >> 6) == 1;
+        self.negative = ((0b10000000 & byte) >> 7) == 1;
+    }
+
+    pub fn as_byte(&self) -> u8 {
+        let mut byte = self.negative as u8;
+        byte = (byte << 1) | self.overflow as u8;
+        // Bit 5 is always 1
+        byte = (byte << 1) | 1;
+        byte = (byte << 1) | self.break_command as u8;
+        byte = (byte << 1) | self.decimal_mode as u8;
+        byte = (byte << 1) | self.interrupt_disable as u8;
+        byte = (byte << 1) | self.zero as u8;
+        (byte << 1) | self.carry as u8
+    }
+}
+
+/// Why the CPU couldn't carry on. Each error has the address of the instruction that caused it
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum CpuError {
+    /// The CPU ran a JAM and has locked up until it's reset. This is only returned by the step that runs
+    /// it. The CPU is halted after that, but stepping it keeps the clock going
+    Jam { address: u16, opcode: u8 },
+    /// The instruction table gave an instruction an operand it can't use. That's a bug in the emulator rather
+    /// than the program, but it's reported rather than taking the host down with it
+    InvalidAddressingMode { address: u16, opcode: u8, addressing_mode: AddressingMode }
+}
+
+impl Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuError::Jam { address, opcode } => write!(f, "CPU jammed by opcode ${:02X} at ${:04X}", opcode, address),
+            CpuError::InvalidAddressingMode { address, opcode, addressing_mode } => {
+                write!(f, "opcode ${:02X} at ${:04X} can't use addressing mode {:?}", opcode, address, addressing_mode)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+/// What a call to `step` did
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct StepInfo {
+    /// The PC at the start of the step
+    pub pc: u16,
+    /// The instruction executed, or None if an interrupt was serviced or the CPU was waiting
+    pub instruction: Option<Instruction>,
+    /// The cycles the step took, including any OAM DMA it started and DMC sample fetch it waited on
+    pub cycles: usize
+}
+
+/// A 6502 and the bus it's attached to. Create one with `new`, `reset` it so it starts from the reset
+/// vector, then call `step` for each instruction
+pub struct CPU6502<B: Bus> {
+    x: u8,
+    y: u8,
+    a: u8,
+    pc: u16,
+    sp: u8,
+    cycles: usize,
+    flags: CPUFlags,
+    bus: B,
+    /// Interrupt inputs driven by the host. These are combined with the lines driven by devices on the bus
+    nmi_line: bool,
+    irq_line: bool,
+    /// The NMI line level when it was last sampled, for edge detection
+    previous_nmi_level: bool,
+    /// Set on a rising edge of the NMI line, and cleared when the NMI is serviced
+    nmi_pending: bool,
+    /// Whether the IRQ line was asserted while interrupts were enabled when it was last polled
+    irq_pending: bool,
+    /// In cycle-accurate mode every cycle makes exactly one bus access, including the dummy reads and writes
+    /// the 6502 makes, and ticks the bus once. Cycles are counted as they happen rather than added up from
+    /// the instruction timing table
+    cycle_accurate: bool,
+    variant: CpuVariant,
+    /// Set by the 65C02's WAI until an interrupt comes in
+    waiting: bool,
+    /// Set by the 65C02's STP, or a JAM on the NMOS chips. Only a reset starts the CPU again
+    halted: bool
+}
+
+impl<B: Bus> CPU6502<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            a: 0,
+            pc: 0,
+            cycles: 0,
+            // The stack pointer powers up as 0. Reset then decrements it by 3, leaving it at 0xFD
+            sp: 0x00,
+            flags: CPUFlags::new(),
+            bus,
+            nmi_line: false,
+            irq_line: false,
+            previous_nmi_level: false,
+            nmi_pending: false,
+            irq_pending: false,
+            cycle_accurate: false,
+            variant: CpuVariant::default(),
+            waiting: false,
+            halted: false
+        }
+    }
+
+    pub fn set_cycle_accurate(&mut self, cycle_accurate: bool) {
+        self.cycle_accurate = cycle_accurate;
+    }
+
+    pub fn set_variant(&mut self, variant: CpuVariant) {
+        self.variant = variant;
+    }
+
+    /// Whether the CPU has stopped executing instructions until it's reset. Test ROMs often JAM on purpose
+    /// once they've finished
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn variant(&self) -> CpuVariant {
+        self.variant
+    }
+
+    pub fn a(&self) -> u8 {
+        self.a
+    }
+
+    pub fn x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn y(&self) -> u8 {
+        self.y
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    /// Cycles run since the CPU was created, counting interrupts, DMA and time spent halted
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn flags(&self) -> &CPUFlags {
+        &self.flags
+    }
+
+    pub fn set_a(&mut self, value: u8) {
+        self.a = value;
+    }
+
+    pub fn set_x(&mut self, value: u8) {
+        self.x = value;
+    }
+
+    pub fn set_y(&mut self, value: u8) {
+        self.y = value;
+    }
+
+    /// Moves execution somewhere else. The next step runs the instruction at the new PC
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    pub fn set_sp(&mut self, value: u8) {
+        self.sp = value;
+    }
+
+    pub fn flags_mut(&mut self) -> &mut CPUFlags {
+        &mut self.flags
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    /// The bus, for hosts to reach the devices on it. Accesses made through it don't take any CPU cycles
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// Decodes the instruction at the PC, which `step` will run next unless an interrupt comes first
+    pub fn next_instruction(&self) -> Instruction {
+        Instruction::decode(&self.bus, self.pc, self.variant)
+    }
+
+    /// Starts a new CPU cycle in cycle-accurate mode. The interrupt lines are polled first, so at the end of
+    /// an instruction they reflect their state at the end of its penultimate cycle, as on the real chip
+    fn begin_cycle(&mut self) {
+        if self.cycle_accurate {
+            self.poll_interrupts(self.flags.interrupt_disable);
+            self.bus.tick();
+            self.cycles += 1;
+        }
+    }
+
+    /// Every bus access the CPU makes while executing goes through here, so that cycle-accurate mode can
+    /// count it
+    fn read(&mut self, address: u16) -> u8 {
+        self.begin_cycle();
+        self.bus.read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.begin_cycle();
+        self.bus.write(address, value);
+    }
+
+    /// A read whose value the CPU throws away. These only happen in cycle-accurate mode, but they're real
+    /// reads, so they can trigger side effects on hardware registers
+    fn dummy_read(&mut self, address: u16) {
+        if self.cycle_accurate {
+            self.read(address);
+        }
+    }
+
+    /// Runs the reset sequence. The CPU goes through the motions of an interrupt, but the stack writes are
+    /// turned into reads, so only the stack pointer changes. Interrupts are disabled and execution continues
+    /// from the address in the reset vector. Ref: https://www.nesdev.org/wiki/CPU_power_up_state
+    pub fn reset(&mut self) {
+        let start_cycles = self.cycles;
+        self.dummy_read(self.pc);
+        self.dummy_read(self.pc);
+        for _ in 0..3 {
+            self.dummy_read(STACK_PAGE + self.sp as u16);
+            self.sp = self.sp.wrapping_sub(1);
+        }
+        self.enter_interrupt_handler();
+        self.pc = self.read_vector(RESET_VECTOR);
+        self.nmi_pending = false;
+        self.irq_pending = false;
+        self.waiting = false;
+        self.halted = false;
+        if !self.cycle_accurate {
+            self.cycles += INTERRUPT_CYCLES;
+        }
+        self.catch_up_bus(start_cycles);
+    }
+
+    /// Outside cycle-accurate mode instructions run all at once, so the bus is ticked for the cycles they
+    /// took afterwards
+    fn catch_up_bus(&mut self, start_cycles: usize) {
+        if !self.cycle_accurate {
+            for _ in start_cycles..self.cycles {
+                self.bus.tick();
+            }
+        }
+    }
+
+    /// Sets the level of the host's NMI input. NMIs are edge-triggered, so holding the line high only
+    /// results in a single interrupt
+    pub fn set_nmi_line(&mut self, asserted: bool) {
+        self.nmi_line = asserted;
+        self.sample_nmi_line();
+    }
+
+    /// Sets the level of the host's IRQ input. IRQs are level-triggered, so the interrupt will keep firing
+    /// for as long as the line is held and interrupts are enabled
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    fn sample_nmi_line(&mut self) {
+        let level = self.nmi_line || self.bus.nmi_asserted();
+        if level && !self.previous_nmi_level {
+            self.nmi_pending = true;
+        }
+        self.previous_nmi_level = level;
+    }
+
+    /// Samples the interrupt lines. The 6502 polls them before the last cycle of each instruction, so the
+    /// interrupt disable flag that matters is the one at that point
+    fn poll_interrupts(&mut self, interrupt_disable: bool) {
+        self.sample_nmi_line();
+        self.irq_pending = (self.irq_line || self.bus.irq_asserted()) && !interrupt_disable;
+    }
+
+    /// Services a pending NMI or IRQ, returning whether one was serviced. An NMI that arrives while an IRQ
+    /// is being serviced hijacks it, which we get for free by sampling the NMI line first
+    fn service_interrupt(&mut self) -> bool {
+        self.sample_nmi_line();
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR
+        } else if self.irq_pending {
+            IRQ_VECTOR
+        } else {
+            return false;
+        };
+        self.irq_pending = false;
+
+        // The CPU fetches the next opcode as usual, but throws it away
+        self.dummy_read(self.pc);
+        self.dummy_read(self.pc);
+        let return_address_bytes = to_bytes_from_address(self.pc);
+        self.push_on_stack(return_address_bytes.1);
+        self.push_on_stack(return_address_bytes.0);
+        // Hardware interrupts push the flags with the break flag clear
+        // Ref: https://www.nesdev.org/wiki/Status_flags#The_B_flag
+        self.push_on_stack(self.flags.as_byte() & !0b00010000);
+        self.enter_interrupt_handler();
+        self.pc = self.read_vector(vector);
+        if !self.cycle_accurate {
+            self.cycles += INTERRUPT_CYCLES;
+        }
+        true
+    }
+
+    /// Interrupts are disabled while one is being handled. The 65C02 also clears the decimal flag, so
+    /// handlers don't have to
+    fn enter_interrupt_handler(&mut self) {
+        self.flags.interrupt_disable = true;
+        if self.variant == CpuVariant::Wdc65C02 {
+            self.flags.decimal_mode = false;
+        }
+    }
+
+    fn read_vector(&mut self, vector: u16) -> u16 {
+        let lo_byte = self.read(vector);
+        to_address_from_bytes((lo_byte, self.read(vector.wrapping_add(1))))
+    }
+
+    fn push_on_stack(&mut self, byte: u8) {
+        let address = STACK_PAGE + self.sp as u16;
+        self.write(address, byte);
+        // Stack is addressed top-down - i.e. stack pointer of 0xFF means empty stack
+        // and a stack pointer of 0x00 means a full stack - so we decrement the pointer
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pop_from_stack(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        let address = STACK_PAGE + self.sp as u16;
+        self.read(address)
+    }
+
+    /// Instructions that pull from the stack spend a cycle reading the current top of the stack before
+    /// incrementing the stack pointer
+    fn dummy_stack_read(&mut self) {
+        self.dummy_read(STACK_PAGE + self.sp as u16);
+    }
+
+    /// Writes bytes through the bus starting at `location`, such as a program to run. It doesn't take any cycles
+    pub fn load_memory(&mut self, location: u16, data: &[u8]) {
+        for (index, byte) in data.iter().enumerate() {
+            self.bus.write(location.wrapping_add(index as u16), *byte);
+        }
+    }
+
+    /// Services a pending interrupt if there is one, otherwise executes the next instruction. A JAM, or an
+    /// instruction the emulator can't execute, is returned as an error, with the CPU left on it
+    pub fn step(&mut self) -> Result<StepInfo, CpuError> {
+        let (pc, start_cycles) = (self.pc, self.cycles);
+        self.run_dmc_dma();
+        // WAI wakes on an IRQ even with interrupts disabled, in which case it carries on without servicing it
+        if self.waiting {
+            self.sample_nmi_line();
+            self.waiting = !(self.nmi_pending || self.irq_line || self.bus.irq_asserted());
+        }
+        if self.waiting || self.halted {
+            self.catch_up_bus(start_cycles);
+            self.idle();
+            return Ok(StepInfo { pc, instruction: None, cycles: self.cycles - start_cycles });
+        }
+
+        if self.service_interrupt() {
+            self.catch_up_bus(start_cycles);
+            return Ok(StepInfo { pc, instruction: None, cycles: self.cycles - start_cycles });
+        }
+        let mut instruction = Instruction::decode(&self.bus, self.pc, self.variant);
+        let opcode = instruction.opcode;
+        let interrupt_disable = self.flags.interrupt_disable;
+        if self.cycle_accurate {
+            self.fetch_instruction(&mut instruction);
+        }
+        self.execute_instruction(instruction)?;
+        self.run_oam_dma();
+        self.catch_up_bus(start_cycles);
+        // CLI, SEI and PLP change the interrupt disable flag after the interrupt lines have been polled, so
+        // their effect is delayed by an instruction. RTI changes it in time. Cycle-accurate mode polls on
+        // every cycle, which gets this right by itself
+        if !self.cycle_accurate {
+            match opcode {
+                Opcode::CLI | Opcode::SEI | Opcode::PLP => self.poll_interrupts(interrupt_disable),
+                _ => self.poll_interrupts(self.flags.interrupt_disable)
+            }
+        }
+        if opcode == Opcode::JAM {
+            return Err(CpuError::Jam { address: pc, opcode: instruction.opcode_byte });
+        }
+        Ok(StepInfo { pc, instruction: Some(instruction), cycles: self.cycles - start_cycles })
+    }
+
+    /// Lets a cycle go by while the CPU is waiting or halted. The rest of the system keeps running
+    fn idle(&mut self) {
+        if self.cycle_accurate {
+            self.begin_cycle();
+        } else {
+            self.cycles += 1;
+            self.bus.tick();
+            self.poll_interrupts(self.flags.interrupt_disable);
+        }
+    }
+
+    /// Runs an OAM DMA if the last instruction asked for one, stalling the CPU while it copies the page. Ref:
+    /// https://www.nesdev.org/wiki/PPU_registers#OAMDMA
+    fn run_oam_dma(&mut self) {
+        let Some(page) = self.bus.take_oam_dma_request() else {
+            return;
+        };
+        let source = (page as u16) << 8;
+        let stall_cycles = if self.cycles % 2 == 1 { OAM_DMA_CYCLES + 1 } else { OAM_DMA_CYCLES };
+        if self.cycle_accurate {
+            // The halt and alignment cycles are spent repeating the read the CPU was about to make
+            for _ in 0..stall_cycles - 2 * 256 {
+                self.read(self.pc);
+            }
+            for offset in 0..=0xFF {
+                let byte = self.read(source + offset);
+                self.write(OAM_DATA, byte);
+            }
+        } else {
+            for offset in 0..=0xFF {
+                let byte = self.bus.read(source + offset);
+                self.bus.write(OAM_DATA, byte);
+            }
+            self.cycles += stall_cycles;
+        }
+    }
+
+    /// Fetches a sample for the DMC if it's waiting for one, stalling the CPU for the read. The stall runs
+    /// before the next instruction, and even while the CPU is waiting or halted, as the APU keeps playing
+    fn run_dmc_dma(&mut self) {
+        let Some(address) = self.bus.dmc_dma_request() else {
+            return;
+        };
+        if self.cycle_accurate {
+            // As with OAM DMA, the stall cycles repeat the read the CPU was about to make
+            for _ in 0..DMC_DMA_CYCLES - 1 {
+                self.read(self.pc);
+            }
+            let byte = self.read(address);
+            self.bus.load_dmc_sample(byte);
+        } else {
+            let byte = self.bus.read(address);
+            self.bus.load_dmc_sample(byte);
+            self.cycles += DMC_DMA_CYCLES;
+        }
+    }
+
+    /// Makes the bus reads for the opcode and operand bytes in cycle-accurate mode. The second cycle of
+    /// every instruction reads the byte after the opcode, even for one-byte instructions. JSR fetches the
+    /// high byte of its target last, so that's left to the instruction itself
+    fn fetch_instruction(&mut self, instruction: &mut Instruction) {
+        instruction.opcode_byte = self.read(self.pc);
+        instruction.data.0 = self.read(self.pc.wrapping_add(1));
+        if instruction.width == 3 && instruction.opcode != Opcode::JSR {
+            instruction.data.1 = self.read(self.pc.wrapping_add(2));
+        }
+    }
+
+    fn set_flags(&mut self, byte: u8) {
+        self.flags.zero = is_zero(byte);
+        self.flags.negative = is_negative(byte);
+    }
+
+    fn compare_and_set_flags(&mut self, register_byte: u8, memory_byte: u8) {
+        let result = register_byte.wrapping_sub(memory_byte);
+        self.set_flags(result);
+        self.flags.carry = register_byte >= memory_byte;
+    }
+
+    fn branch_on_condition(&mut self, condition: bool, instruction: &Instruction) -> Result<(), CpuError> {
+        if condition {
+            let (branch_address, page_boundary_crossed) = self.get_address_operand(instruction.data, instruction.addressing_mode)?;
+            // The CPU reads the next opcode while it adds the offset, and again from the wrong page while
+            // it fixes up the high byte
+            let next_instruction_address = self.pc.wrapping_add(instruction.width as u16);
+            self.dummy_read(next_instruction_address);
+            if page_boundary_crossed {
+                self.dummy_read((next_instruction_address & 0xFF00) | (branch_address & 0x00FF));
+            }
+            // Pre-decrement the PC with the width, because the execution loop will increment it afterwards
+            self.pc = branch_address.wrapping_sub(instruction.width as u16);
+            if !self.cycle_accurate {
+                self.cycles += 1;
+                if page_boundary_crossed { self.cycles += 1 }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_extra_cycles(&mut self, addressing_mode: &AddressingMode, page_boundary_crossed: bool) {
+        if self.cycle_accurate {
+            return;
+        }
+        match addressing_mode {
+            AddressingMode::AbsoluteIndexedX | AddressingMode::AbsoluteIndexedY | AddressingMode::IndirectIndexed
+                if page_boundary_crossed => self.cycles += 1,
+            _ => ()
+        }
+    }
+
+    /// The 65C02 takes an extra cycle over decimal arithmetic, reading the next opcode while it fixes up the flags
+    fn add_decimal_cycle(&mut self, instruction: &Instruction) {
+        if self.variant == CpuVariant::Wdc65C02 && self.flags.decimal_mode {
+            self.dummy_read(self.pc.wrapping_add(instruction.width as u16));
+            if !self.cycle_accurate {
+                self.cycles += 1;
+            }
+        }
+    }
+
+    /// Whether ADC and SBC work in binary-coded decimal
+    fn decimal_arithmetic(&self) -> bool {
+        self.flags.decimal_mode && self.variant != CpuVariant::Ricoh2A03
+    }
+
+    fn add_with_carry(&mut self, operand: u8) {
+        if self.decimal_arithmetic() {
+            self.add_decimal(operand);
+        } else {
+            self.add_binary(operand);
+        }
+    }
+
+    /// The 65C02 sets N and Z properly after decimal arithmetic
+    fn fix_decimal_flags(&mut self) {
+        if self.decimal_arithmetic() && self.variant == CpuVariant::Wdc65C02 {
+            self.set_flags(self.a);
+        }
+    }
+
+    fn subtract_with_borrow(&mut self, operand: u8) {
+        let (a, borrow) = (self.a, !self.flags.carry as i16);
+        // The NMOS 6502 sets every flag from the binary subtraction, even in decimal mode
+        self.add_binary(!operand);
+        if self.decimal_arithmetic() {
+            let mut lo = (a & 0x0F) as i16 - (operand & 0x0F) as i16 - borrow;
+            let mut hi = (a >> 4) as i16 - (operand >> 4) as i16;
+            if lo < 0 {
+                lo -= 6;
+                hi -= 1;
+            }
+            if hi < 0 {
+                hi -= 6;
+            }
+            self.a = ((hi << 4) | (lo & 0x0F)) as u8;
+            self.fix_decimal_flags();
+        }
+    }
+
+    /// Decimal addition on the NMOS 6502. Each digit is adjusted in turn, and N and V are taken from the sum
+    /// before the high digit is adjusted, so they don't mean much. Z comes from the binary sum. Ref:
+    /// http://www.6502.org/tutorials/decimal_mode.html#A
+    fn add_decimal(&mut self, operand: u8) {
+        let carry = self.flags.carry as u16;
+        let mut lo = (self.a & 0x0F) as u16 + (operand & 0x0F) as u16 + carry;
+        if lo > 0x09 {
+            lo += 0x06;
+        }
+        let mut hi = (self.a >> 4) as u16 + (operand >> 4) as u16 + (lo > 0x0F) as u16;
+        let unadjusted = ((hi << 4) | (lo & 0x0F)) as u8;
+        self.flags.zero = is_zero(self.a.wrapping_add(operand).wrapping_add(carry as u8));
+        self.flags.negative = is_negative(unadjusted);
+        self.flags.overflow = (!(self.a ^ operand) & (self.a ^ unadjusted) & 0x80) == 0x80;
+        if hi > 0x09 {
+            hi += 0x06;
+        }
+        self.flags.carry = hi > 0x0F;
+        self.a = ((hi << 4) | (lo & 0x0F)) as u8;
+        self.fix_decimal_flags();
+    }
+
+    fn add_binary(&mut self, operand: u8) {
+        let sum = self.a as u16 + operand as u16 + self.flags.carry as u16;
+        let result = sum as u8;
+        self.flags.carry = sum > 0xFF;
+        // Overflow occurs when the operands have the same sign bit, but the result does not
+        self.flags.overflow = ((!(self.a ^ operand)) & 0x80  // true when operands have same sign
+                            & (operand ^ result)) == 0x80; // and result is different 
+        self.a = result;
+        self.set_flags(self.a)
+    }
+
+    fn shift_left(&mut self, byte: u8) -> u8 {
+        self.flags.carry = (byte & 0b10000000) == 0b10000000;
+        let result = byte << 1;
+        self.set_flags(result);
+        result
+    }
+
+    fn shift_right(&mut self, byte: u8) -> u8 {
+        self.flags.carry = (byte & 0x01) == 1;
+        let result = byte >> 1;
+        self.set_flags(result);
+        result
+    }
+
+    fn rotate_left(&mut self, byte: u8) -> u8 {
+        let carry = self.flags.carry as u8;
+        self.flags.carry = (byte & 0b10000000) == 0b10000000;
+        let result = (byte << 1) + carry;
+        self.set_flags(result);
+        result
+    }
+
+    fn rotate_right(&mut self, byte: u8) -> u8 {
+        let carry = self.flags.carry as u8;
+        self.flags.carry = (byte & 0x01) == 1;
+        let result = (byte >> 1) + (carry << 7);
+        self.set_flags(result);
+        result
+    }
+
+    /// Reads the byte at the address, applies the operation and writes the result back, returning it. The
+    /// 6502 writes the unmodified byte back while it does the operation, which is visible in cycle-accurate mode
+    fn read_modify_write(&mut self, address: u16, operation: impl FnOnce(&mut Self, u8) -> u8) -> u8 {
+        let byte = self.read(address);
+        if self.cycle_accurate {
+            self.write(address, byte);
+        }
+        let result = operation(self, byte);
+        self.write(address, result);
+        result
+    }
+
+    /// The unstable SHA/SHX/SHY/TAS stores AND the value with the high byte of the base address plus
+    /// one. If indexing crossed a page, the high byte of the target address is replaced by the stored
+    /// value. Ref: https://www.nesdev.org/wiki/CPU_unofficial_opcodes
+    fn store_and_high_byte(&mut self, value: u8, instruction_data: (u8, u8), addressing_mode: AddressingMode, index: u8) -> Result<(), CpuError> {
+        let (address, page_boundary_crossed) = self.get_address_operand(instruction_data, addressing_mode)?;
+        let base_high_byte = (address.wrapping_sub(index as u16) >> 8) as u8;
+        let value = value & base_high_byte.wrapping_add(1);
+        let address = if page_boundary_crossed {
+            ((value as u16) << 8) | (address & 0xFF)
+        } else {
+            address
+        };
+        self.write(address, value);
+        Ok(())
+    }
+
+    fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        // Add variable bindings here to keep the execution switch statement (reasonably)
+        // concise and readable
+        let addressing_mode = instruction.addressing_mode;
+        let opcode = instruction.opcode;
+        let instruction_data = instruction.data;
+
+        match opcode {
+            Opcode::ADC => {
+                let (operand, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.add_with_carry(operand);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+                self.add_decimal_cycle(&instruction);
+            },
+
+            Opcode::AND => {
+                let (operand, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a &= operand;
+                self.set_flags(self.a);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            },
+
+            Opcode::ASL => {
+                if addressing_mode != AddressingMode::Accumulator {
+                    let address = self.get_shift_address_operand(instruction_data, addressing_mode)?;
+                    self.read_modify_write(address, Self::shift_left);
+                } else {
+                    self.a = self.shift_left(self.a);
+                }
+            }
+
+            Opcode::BCC => self.branch_on_condition(!self.flags.carry, &instruction)?,
+            Opcode::BCS => self.branch_on_condition(self.flags.carry, &instruction)?,
+            Opcode::BEQ => self.branch_on_condition(self.flags.zero, &instruction)?,
+
+            Opcode::BIT => {
+                let (byte, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                // The 65C02's BIT immediate only sets Z, as there's no memory for N and V to come from
+                if addressing_mode != AddressingMode::Immediate {
+                    self.flags.negative = ((0b10000000 & byte) >> 7) == 1;
+                    self.flags.overflow = ((0b01000000 & byte) >> 6) == 1;
+                }
+                self.flags.zero = (self.a & byte) == 0;
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            },
+
+            Opcode::BMI => self.branch_on_condition(self.flags.negative, &instruction)?,
+            Opcode::BNE => self.branch_on_condition(!self.flags.zero, &instruction)?,
+            Opcode::BPL => self.branch_on_condition(!self.flags.negative, &instruction)?,
+
+            Opcode::BRK => {
+                // BRK skips a padding byte, so the return address is the PC plus 2
+                let return_address_bytes = to_bytes_from_address(self.pc.wrapping_add(2));
+                self.push_on_stack(return_address_bytes.1);
+                self.push_on_stack(return_address_bytes.0);
+                // An NMI arriving before the vector is fetched hijacks the BRK. The flags are still pushed
+                // with the break flag set, but execution continues from the NMI vector
+                self.sample_nmi_line();
+                let vector = if self.nmi_pending {
+                    self.nmi_pending = false;
+                    NMI_VECTOR
+                } else {
+                    IRQ_VECTOR
+                };
+                self.push_on_stack(self.flags.as_byte() | 0b00010000);
+                self.enter_interrupt_handler();
+                let new_address = self.read_vector(vector);
+                // Pre-decrement the PC with the width, because the execution loop will increment it afterwards
+                self.pc = new_address.wrapping_sub(instruction.width as u16);
+            },
+            Opcode::BVC => self.branch_on_condition(!self.flags.overflow, &instruction)?,
+            Opcode::BVS => self.branch_on_condition(self.flags.overflow, &instruction)?,
+
+            Opcode::CLC => self.flags.carry = false,
+            Opcode::CLD => self.flags.decimal_mode = false,
+            Opcode::CLI => self.flags.interrupt_disable = false,
+            Opcode::CLV => self.flags.overflow = false,
+
+            Opcode::CMP => {
+                let (operand, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.compare_and_set_flags(self.a, operand);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            },
+            Opcode::CPX => {
+                let (operand, _) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.compare_and_set_flags(self.x, operand);
+            },
+            Opcode::CPY => {
+                let (operand, _) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.compare_and_set_flags(self.y, operand);
+            },
+
+            Opcode::DEC => {
+                if addressing_mode != AddressingMode::Accumulator {
+                    let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                    let result = self.read_modify_write(address, |_, byte| byte.wrapping_sub(1));
+                    self.set_flags(result);
+                } else {
+                    self.a = self.a.wrapping_sub(1);
+                    self.set_flags(self.a);
+                }
+            },
+            Opcode::DEX => {
+                self.x = self.x.wrapping_sub(1);
+                self.set_flags(self.x);
+            },
+            Opcode::DEY => {
+                self.y = self.y.wrapping_sub(1);
+                self.set_flags(self.y);
+            },
+
+            Opcode::EOR => {
+                let (operand, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a ^= operand;
+                self.set_flags(self.a);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            },
+
+            Opcode::INC => {
+                if addressing_mode != AddressingMode::Accumulator {
+                    let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                    let result = self.read_modify_write(address, |_, byte| byte.wrapping_add(1));
+                    self.set_flags(result);
+                } else {
+                    self.a = self.a.wrapping_add(1);
+                    self.set_flags(self.a);
+                }
+            },
+            Opcode::INX => {
+                self.x = self.x.wrapping_add(1);
+                self.set_flags(self.x);
+            },
+            Opcode::INY => {
+                self.y = self.y.wrapping_add(1);
+                self.set_flags(self.y);
+            },
+
+            Opcode::JMP => {
+                let (new_address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                // Pre-decrement the PC with the width, because the execution loop will increment it afterwards
+                self.pc = new_address.wrapping_sub(instruction.width as u16);
+            },
+            Opcode::JSR => {
+                self.dummy_stack_read();
+                // Return address is next instruction - or PC plus 2
+                let return_address_bytes = to_bytes_from_address(self.pc.wrapping_add(2));
+                self.push_on_stack(return_address_bytes.1);
+                self.push_on_stack(return_address_bytes.0);
+                let mut instruction_data = instruction_data;
+                if self.cycle_accurate {
+                    instruction_data.1 = self.read(self.pc.wrapping_add(2));
+                }
+                let (new_address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                // Pre-decrement the PC with the width, because the execution loop will increment it afterwards
+                self.pc = new_address.wrapping_sub(instruction.width as u16);
+            },
+            Opcode::LDA => {
+                let (byte, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a = byte;
+                self.set_flags(self.a);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            },
+            Opcode::LDX => {
+                let (byte, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.set_flags(byte);
+                self.x = byte;      
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            },
+            Opcode::LDY => {
+                let (byte, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.set_flags(byte);
+                self.y = byte;            
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed); 
+            },
+
+            Opcode::LSR => {
+                if addressing_mode != AddressingMode::Accumulator {
+                    let address = self.get_shift_address_operand(instruction_data, addressing_mode)?;
+                    self.read_modify_write(address, Self::shift_right);
+                }
+                else {
+                    self.a = self.shift_right(self.a);
+                }
+            }
+
+            Opcode::NOP => {
+                // Unofficial NOPs can take an operand, which they read (and pay the page crossing penalty for)
+                if addressing_mode != AddressingMode::Implied {
+                    let (_, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                    self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+                }
+            },
+
+            Opcode::ORA => {
+                let (operand, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a |= operand;
+                self.set_flags(self.a);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            }
+
+            Opcode::PHA => self.push_on_stack(self.a),
+            // PHP sets the break flag on the value pushed to the stack
+            // Ref: https://www.nesdev.org/wiki/Status_flags#The_B_flag
+            Opcode::PHP => self.push_on_stack(self.flags.as_byte() | 0b00010000),
+            Opcode::PLA => {
+                self.dummy_stack_read();
+                self.a = self.pop_from_stack();
+                self.set_flags(self.a);  
+            },
+            Opcode::PLP => {
+                self.dummy_stack_read();
+                let new_flags = self.pop_from_stack();
+                self.flags.set_from_byte(new_flags);
+                self.flags.break_command = false;  
+            },
+
+            Opcode::ROL => {
+                if addressing_mode != AddressingMode::Accumulator {
+                    let address = self.get_shift_address_operand(instruction_data, addressing_mode)?;
+                    self.read_modify_write(address, Self::rotate_left);
+                } else {
+                    self.a = self.rotate_left(self.a);
+                }
+            },
+            Opcode::ROR => {
+                if addressing_mode != AddressingMode::Accumulator {
+                    let address = self.get_shift_address_operand(instruction_data, addressing_mode)?;
+                    self.read_modify_write(address, Self::rotate_right);
+                } else {
+                    self.a = self.rotate_right(self.a);
+                }
+            }
+
+            Opcode::RTI => {
+                self.dummy_stack_read();
+                let new_flags = self.pop_from_stack();
+                self.flags.set_from_byte(new_flags);
+                self.flags.break_command = false;  
+                let lo_byte = self.pop_from_stack();
+                let address = to_address_from_bytes((lo_byte, self.pop_from_stack()));
+                // Pre-decrement address, as it's incremented again in the execution loop
+                self.pc = address.wrapping_sub(1);  
+            },
+
+            Opcode::RTS => {
+                self.dummy_stack_read();
+                let lo_byte = self.pop_from_stack();
+                let address = to_address_from_bytes((lo_byte, self.pop_from_stack()));
+                // The pulled address is one less than the return address, and the CPU reads from it while
+                // incrementing
+                self.dummy_read(address);
+                self.pc = address;  
+            },
+
+            Opcode::SBC => {
+                let (operand, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.subtract_with_borrow(operand);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+                self.add_decimal_cycle(&instruction);
+            },
+
+            Opcode::SEC => self.flags.carry = true,
+            Opcode::SED => self.flags.decimal_mode = true,
+            Opcode::SEI => self.flags.interrupt_disable = true,
+
+            Opcode::STA => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.write(address, self.a);
+            },
+            Opcode::STX => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.write(address, self.x);
+            },
+            Opcode::STY => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.write(address, self.y);
+            },
+
+            Opcode::TAX => {
+                self.x = self.a;
+                self.set_flags(self.x);
+            },
+            Opcode::TAY => {
+                self.y = self.a;
+                self.set_flags(self.y);
+            },
+            Opcode::TSX => {
+                self.x = self.sp;
+                self.set_flags(self.x);
+            },
+            Opcode::TXA => {
+                self.a = self.x;
+                self.set_flags(self.a);
+            },
+            Opcode::TXS => self.sp = self.x,
+            Opcode::TYA => {
+                self.a = self.y;
+                self.set_flags(self.a);
+            },
+
+            // Unofficial opcodes
+            Opcode::ALR => {
+                let (operand, _) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a = self.shift_right(self.a & operand);
+            },
+            Opcode::ANC => {
+                let (operand, _) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a &= operand;
+                self.set_flags(self.a);
+                self.flags.carry = self.flags.negative;
+            },
+            Opcode::ANE => {
+                // The result depends on analogue effects in the chip. 0xEE is the most commonly observed
+                // "magic" constant
+                let (operand, _) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a = (self.a | 0xEE) & self.x & operand;
+                self.set_flags(self.a);
+            },
+            Opcode::ARR => {
+                let (operand, _) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a = self.rotate_right(self.a & operand);
+                // Carry and overflow come from bits 6 and 5 of the result rather than the rotation
+                self.flags.carry = (self.a & 0b01000000) == 0b01000000;
+                self.flags.overflow = (((self.a >> 6) ^ (self.a >> 5)) & 0x01) == 1;
+            },
+            Opcode::DCP => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                let result = self.read_modify_write(address, |_, byte| byte.wrapping_sub(1));
+                self.compare_and_set_flags(self.a, result);
+            },
+            Opcode::ISB => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                let result = self.read_modify_write(address, |_, byte| byte.wrapping_add(1));
+                self.subtract_with_borrow(result);
+            },
+            Opcode::JAM => {
+                // The PC stays on the JAM, so it shows where the CPU locked up. Pre-decrement the PC with the
+                // width, because the execution loop will increment it afterwards
+                self.pc = self.pc.wrapping_sub(instruction.width as u16);
+                self.halted = true;
+            },
+            Opcode::LAS => {
+                let (operand, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                let result = operand & self.sp;
+                self.a = result;
+                self.x = result;
+                self.sp = result;
+                self.set_flags(result);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            },
+            Opcode::LAX => {
+                let (byte, page_boundary_crossed) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a = byte;
+                self.x = byte;
+                self.set_flags(byte);
+                self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            },
+            Opcode::LXA => {
+                // As with ANE, 0xEE is the commonly observed "magic" constant
+                let (operand, _) = self.get_value_operand(instruction_data, addressing_mode)?;
+                self.a = (self.a | 0xEE) & operand;
+                self.x = self.a;
+                self.set_flags(self.a);
+            },
+            Opcode::RLA => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                let result = self.read_modify_write(address, Self::rotate_left);
+                self.a &= result;
+                self.set_flags(self.a);
+            },
+            Opcode::RRA => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                let result = self.read_modify_write(address, Self::rotate_right);
+                self.add_with_carry(result);
+            },
+            Opcode::SAX => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.write(address, self.a & self.x);
+            },
+            Opcode::SBX => {
+                let (operand, _) = self.get_value_operand(instruction_data, addressing_mode)?;
+                let register_byte = self.a & self.x;
+                self.compare_and_set_flags(register_byte, operand);
+                self.x = register_byte.wrapping_sub(operand);
+            },
+            Opcode::SHA => self.store_and_high_byte(self.a & self.x, instruction_data, addressing_mode, self.y)?,
+            Opcode::SHX => self.store_and_high_byte(self.x, instruction_data, addressing_mode, self.y)?,
+            Opcode::SHY => self.store_and_high_byte(self.y, instruction_data, addressing_mode, self.x)?,
+            Opcode::SLO => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                let result = self.read_modify_write(address, Self::shift_left);
+                self.a |= result;
+                self.set_flags(self.a);
+            },
+            Opcode::SRE => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                let result = self.read_modify_write(address, Self::shift_right);
+                self.a ^= result;
+                self.set_flags(self.a);
+            },
+            Opcode::TAS => {
+                self.sp = self.a & self.x;
+                self.store_and_high_byte(self.sp, instruction_data, addressing_mode, self.y)?;
+            },
+
+            // 65C02 instructions
+            Opcode::BRA => self.branch_on_condition(true, &instruction)?,
+            Opcode::PHX => self.push_on_stack(self.x),
+            Opcode::PHY => self.push_on_stack(self.y),
+            Opcode::PLX => {
+                self.dummy_stack_read();
+                self.x = self.pop_from_stack();
+                self.set_flags(self.x);
+            },
+            Opcode::PLY => {
+                self.dummy_stack_read();
+                self.y = self.pop_from_stack();
+                self.set_flags(self.y);
+            },
+            Opcode::STZ => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.write(address, 0);
+            },
+            // TRB and TSB set Z like BIT, from the accumulator AND the memory before it's changed
+            Opcode::TRB => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.read_modify_write(address, |cpu, byte| {
+                    cpu.flags.zero = is_zero(cpu.a & byte);
+                    byte & !cpu.a
+                });
+            },
+            Opcode::TSB => {
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.read_modify_write(address, |cpu, byte| {
+                    cpu.flags.zero = is_zero(cpu.a & byte);
+                    byte | cpu.a
+                });
+            },
+            Opcode::WAI => {
+                self.dummy_read(self.pc.wrapping_add(1));
+                self.waiting = true;
+            },
+            Opcode::STP => {
+                self.dummy_read(self.pc.wrapping_add(1));
+                self.halted = true;
+            },
+            // The bit instructions have the bit number in the high nibble of the opcode
+            Opcode::RMB0 | Opcode::RMB1 | Opcode::RMB2 | Opcode::RMB3 | Opcode::RMB4 | Opcode::RMB5 | Opcode::RMB6 | Opcode::RMB7 => {
+                let mask = 1 << ((instruction.opcode_byte >> 4) & 0x07);
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.read_modify_write(address, |_, byte| byte & !mask);
+            },
+            Opcode::SMB0 | Opcode::SMB1 | Opcode::SMB2 | Opcode::SMB3 | Opcode::SMB4 | Opcode::SMB5 | Opcode::SMB6 | Opcode::SMB7 => {
+                let mask = 1 << ((instruction.opcode_byte >> 4) & 0x07);
+                let (address, _) = self.get_address_operand(instruction_data, addressing_mode)?;
+                self.read_modify_write(address, |_, byte| byte | mask);
+            },
+            Opcode::BBR0 | Opcode::BBR1 | Opcode::BBR2 | Opcode::BBR3 | Opcode::BBR4 | Opcode::BBR5 | Opcode::BBR6 | Opcode::BBR7
+            | Opcode::BBS0 | Opcode::BBS1 | Opcode::BBS2 | Opcode::BBS3 | Opcode::BBS4 | Opcode::BBS5 | Opcode::BBS6 | Opcode::BBS7 => {
+                let mask = 1 << ((instruction.opcode_byte >> 4) & 0x07);
+                let branch_if_set = instruction.opcode_byte & 0x80 != 0;
+                let byte = self.read(instruction_data.0 as u16);
+                self.dummy_read(instruction_data.0 as u16);
+                self.branch_on_condition((byte & mask != 0) == branch_if_set, &instruction)?;
+            },
+        }
+        
+        self.pc = self.pc.wrapping_add(instruction.width as u16);
+        if !self.cycle_accurate {
+            self.cycles += instruction.cycles;
+        }
+        Ok(())
+    }
+
+    /// For instructions which take values as an operand. Takes the two bytes following the opcode and the addressing mode, and returns a tuple containing
+    /// the intended the intended operand for the instruction and a bool representing whether a page boundary
+    /// has been crossed
+    fn get_value_operand(&mut self, instruction_data: (u8, u8), addressing_mode: AddressingMode) -> Result<(u8, bool), CpuError> {
+        match addressing_mode {
+            // Immediate instructions just take the next byte as an operand
+            AddressingMode::Immediate => Ok((instruction_data.0, false)),
+
+            // Accumulator instructions just need the value in the accumulator
+            AddressingMode::Accumulator => Ok((self.a, false)),
+
+            // An implied addressing mode means there is no operand
+            AddressingMode::Implied => Err(self.invalid_addressing_mode(addressing_mode)),
+
+            // Other addressing modes need the value at the memory address indicated by the data and
+            // the addressing mode
+            _ => {
+                let (address, page_boundary_crossed) = self.resolve_address_operand(instruction_data, addressing_mode, Access::Read)?;
+                Ok((self.read(address), page_boundary_crossed))
+            }
+        }
+    }
+
+    /// For instructions which take an address as an operand. Takes the two bytes following the opcode and the addressing
+    /// mode, and returns a tuple containing the address and a bool indicating whether a page boundary has been crossed.
+    /// Instructions which write to the address use this, so indexed addressing always makes a dummy read
+    fn get_address_operand(&mut self, instruction_data: (u8, u8), addressing_mode: AddressingMode) -> Result<(u16, bool), CpuError> {
+        self.resolve_address_operand(instruction_data, addressing_mode, Access::Write)
+    }
+
+    /// The address for ASL, LSR, ROL and ROR. The 65C02 only makes the dummy read for abs,X when indexing crosses a
+    /// page, so they take a cycle less unless it does
+    fn get_shift_address_operand(&mut self, instruction_data: (u8, u8), addressing_mode: AddressingMode) -> Result<u16, CpuError> {
+        if self.variant == CpuVariant::Wdc65C02 && addressing_mode == AddressingMode::AbsoluteIndexedX {
+            let (address, page_boundary_crossed) = self.resolve_address_operand(instruction_data, addressing_mode, Access::Read)?;
+            self.add_extra_cycles(&addressing_mode, page_boundary_crossed);
+            Ok(address)
+        } else {
+            self.get_address_operand(instruction_data, addressing_mode).map(|(address, _)| address)
+        }
+    }
+
+    /// Resolves the operand address, making the reads the 6502 makes along the way in cycle-accurate mode. Those are
+    /// pointer fetches for the indirect modes, and dummy reads while the CPU adds the index. Ref:
+    /// https://www.nesdev.org/6502_cpu.txt
+    fn resolve_address_operand(&mut self, instruction_data: (u8, u8), addressing_mode: AddressingMode, access: Access) -> Result<(u16, bool), CpuError> {
+        let (address, page_boundary_crossed) = self.peek_address_operand(instruction_data, addressing_mode)
+            .ok_or_else(|| self.invalid_addressing_mode(addressing_mode))?;
+        if !self.cycle_accurate {
+            return Ok((address, page_boundary_crossed));
+        }
+
+        // Before fixing up the high byte, indexed modes read from the address in the wrong page
+        let uncorrected_address = if page_boundary_crossed { address.wrapping_sub(0x0100) } else { address };
+        let needs_fix_up_read = page_boundary_crossed || access == Access::Write;
+        match addressing_mode {
+            AddressingMode::ZeroPageIndexedX | AddressingMode::ZeroPageIndexedY => self.dummy_read(instruction_data.0 as u16),
+            AddressingMode::AbsoluteIndexedX | AddressingMode::AbsoluteIndexedY if needs_fix_up_read => {
+                self.dummy_read(uncorrected_address)
+            },
+            AddressingMode::Indirect => {
+                let lo_address = to_address_from_bytes(instruction_data);
+                if self.variant == CpuVariant::Wdc65C02 {
+                    // The fix costs a cycle
+                    self.dummy_read(lo_address);
+                    self.read(lo_address);
+                    self.read(lo_address.wrapping_add(1));
+                } else {
+                    self.read(lo_address);
+                    self.read(to_address_from_bytes((instruction_data.0.wrapping_add(1), instruction_data.1)));
+                }
+            },
+            AddressingMode::ZeroPageIndirect => {
+                self.read(instruction_data.0 as u16);
+                self.read(instruction_data.0.wrapping_add(1) as u16);
+            },
+            AddressingMode::AbsoluteIndexedIndirect => {
+                let pointer = to_address_from_bytes(instruction_data).wrapping_add(self.x as u16);
+                self.dummy_read(to_address_from_bytes(instruction_data));
+                self.read(pointer);
+                self.read(pointer.wrapping_add(1));
+            },
+            AddressingMode::IndexedIndirect => {
+                self.dummy_read(instruction_data.0 as u16);
+                let indirect_address = instruction_data.0.wrapping_add(self.x);
+                self.read(indirect_address as u16);
+                self.read(indirect_address.wrapping_add(1) as u16);
+            },
+            AddressingMode::IndirectIndexed => {
+                self.read(instruction_data.0 as u16);
+                self.read(instruction_data.0.wrapping_add(1) as u16);
+                if needs_fix_up_read { self.dummy_read(uncorrected_address) }
+            },
+            _ => ()
+        }
+        Ok((address, page_boundary_crossed))
+    }
+
+    /// The error for an instruction whose addressing mode doesn't make sense for it. The PC is still on the
+    /// instruction when operands are resolved
+    fn invalid_addressing_mode(&self, addressing_mode: AddressingMode) -> CpuError {
+        CpuError::InvalidAddressingMode { address: self.pc, opcode: self.bus.peek(self.pc), addressing_mode }
+    }
+
+    /// Works out the address an operand refers to without touching the bus, other than peeking at pointers. Returns a
+    /// tuple containing the address and a bool indicating whether a page boundary has been crossed, or None for
+    /// addressing modes that don't refer to memory
+    fn peek_address_operand(&self, instruction_data: (u8, u8), addressing_mode: AddressingMode) -> Option<(u16, bool)> {
+        let operand = match addressing_mode {
+            // Absolute instructions need the value in memory at the address given by the data
+            // bytes (little-endian)
+            AddressingMode::Absolute => {
+                let address = to_address_from_bytes(instruction_data);
+                (address, false)
+            },
+
+            // Absolute instructions need the value in memory at the address given by the data
+            // bytes (little-endian) plus the value in register X
+            AddressingMode::AbsoluteIndexedX => {
+                let address = to_address_from_bytes(instruction_data);
+                let indexed_address = address.wrapping_add(self.x as u16);
+                (indexed_address, was_page_boundary_crossed(address, indexed_address))
+            },
+
+            // Absolute instructions need the value in memory at the address given by the data
+            // bytes (little-endian) plus the value in register Y
+            AddressingMode::AbsoluteIndexedY => {
+                let address = to_address_from_bytes(instruction_data);
+                let indexed_address = address.wrapping_add(self.y as u16);
+                (indexed_address, was_page_boundary_crossed(address, indexed_address))
+            },
+
+            // Returns the byte on the zero page at the address given by the first byte of
+            // instruction data
+            AddressingMode::ZeroPage => {
+                let address = instruction_data.0 as u16;
+                (address, false)  
+              },
+  
+              // Returns the byte on the zero page at the address given by indexing the first byte
+              // of instruction data with the contents of the X register. This may overflow, which
+              // is intended behaviour
+              AddressingMode::ZeroPageIndexedX => {
+                  let address = (instruction_data.0.wrapping_add(self.x)) as u16;
+                  (address, false)
+              },
+  
+              // Returns the byte on the zero page at the address given by indexing the first byte
+              // of instruction data with the contents of the Y register. This may overflow, which
+              // is intended behaviour
+              AddressingMode::ZeroPageIndexedY => {
+                  let address = (instruction_data.0.wrapping_add(self.y)) as u16;
+                  (address, false)
+              },
+
+              // Indirect is word at address given by reading two bytes from address given by instruction data
+            // The NMOS 6502 doesn't carry into the high byte when it fetches the second byte of the pointer, so
+            // JMP ($xxFF) takes its high byte from $xx00. The 65C02 fixed this
+            AddressingMode::Indirect => {
+                let lo_address = to_address_from_bytes(instruction_data);
+                let hi_address = if self.variant == CpuVariant::Wdc65C02 {
+                    lo_address.wrapping_add(1)
+                } else {
+                    to_address_from_bytes((instruction_data.0.wrapping_add(1), instruction_data.1))
+                };
+                let address = to_address_from_bytes((self.bus.peek(lo_address), self.bus.peek(hi_address)));
+                (address, false)
+            }
+
+            // Zero page indirect retrieves two bytes from the zero page to get an address
+            AddressingMode::ZeroPageIndirect => {
+                let address = to_address_from_bytes((self.bus.peek(instruction_data.0 as u16),
+                    self.bus.peek(instruction_data.0.wrapping_add(1) as u16)));
+                (address, false)
+            }
+
+            // Absolute indexed indirect adds X to the address given by the data bytes, and retrieves the
+            // address stored there
+            AddressingMode::AbsoluteIndexedIndirect => {
+                let pointer = to_address_from_bytes(instruction_data).wrapping_add(self.x as u16);
+                let address = to_address_from_bytes((self.bus.peek(pointer), self.bus.peek(pointer.wrapping_add(1))));
+                (address, false)
+            }
+
+            // Indexed indirect retrieves two bytes from the zero page indexed by X to get an address,
+            // then returns the word at that address
+            AddressingMode::IndexedIndirect => {
+                let indirect_address = instruction_data.0.wrapping_add(self.x);
+                let address = to_address_from_bytes((self.bus.peek(indirect_address as u16), self.bus.peek(indirect_address.wrapping_add(1) as u16)));
+                (address, false)
+            }
+
+            // Indirect indexed retrieves two bytes from the zero page to get an address, which is indexed
+            // by Y with carry, and the word at that address is returned
+            AddressingMode::IndirectIndexed => {
+                let address = to_address_from_bytes((self.bus.peek(instruction_data.0 as u16),
+                    self.bus.peek(instruction_data.0.wrapping_add(1) as u16)));
+                let indexed_address = address.wrapping_add(self.y as u16);
+                (indexed_address, was_page_boundary_crossed(address, indexed_address))
+            },
+
+            // Relative addressing mode takes the address of the next instruction and adds a signed
+            // offset given by the next byte
+            AddressingMode::Relative => {
+                let offset = instruction_data.0 as i8;
+                // Relative instructions are always two bytes wide, so the next instruction is always
+                // the PC plus 2
+                let pc = self.pc.wrapping_add(2);
+                let address = pc.wrapping_add_signed(offset as i16);
+                (address, was_page_boundary_crossed(pc, address))
+            },
+
+            // The branch target of the 65C02's BBR and BBS, which have the zero page address before the offset
+            AddressingMode::ZeroPageRelative => {
+                let offset = instruction_data.1 as i8;
+                let pc = self.pc.wrapping_add(3);
+                let address = pc.wrapping_add_signed(offset as i16);
+                (address, was_page_boundary_crossed(pc, address))
+            },
+
+            // All other addressing modes don't refer to an address in memory but a register (or none at all)
+            _ => return None
+        };
+        Some(operand)
+    }
+}
+
+impl<B: Bus + Snapshot> CPU6502<B> {
+    /// Snapshots the whole machine: the CPU, then everything on the bus
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.section(b"CPU ", |state| {
+            state.write_u8(self.a);
+            state.write_u8(self.x);
+            state.write_u8(self.y);
+            state.write_u16(self.pc);
+            state.write_u8(self.sp);
+            state.write_u8(self.flags.as_byte());
+            state.write_u64(self.cycles as u64);
+            state.write_bool(self.nmi_line);
+            state.write_bool(self.irq_line);
+            state.write_bool(self.previous_nmi_level);
+            state.write_bool(self.nmi_pending);
+            state.write_bool(self.irq_pending);
+            state.write_bool(self.waiting);
+            state.write_bool(self.halted);
+        });
+        self.bus.save_state(&mut state);
+        state.finish()
+    }
+
+    /// Restores a snapshot from `save_state`. If the state can't be loaded the machine is left as it was
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let previous_state = self.save_state();
+        let result = self.restore_state(bytes);
+        if result.is_err() {
+            self.restore_state(&previous_state).expect("a state that was just saved should load");
+        }
+        result
+    }
+
+    fn restore_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::open(bytes)?;
+        state.section(b"CPU ", |state| {
+            self.a = state.read_u8()?;
+            self.x = state.read_u8()?;
+            self.y = state.read_u8()?;
+            self.pc = state.read_u16()?;
+            self.sp = state.read_u8()?;
+            self.flags.set_from_byte(state.read_u8()?);
+            self.cycles = state.read_u64()? as usize;
+            self.nmi_line = state.read_bool()?;
+            self.irq_line = state.read_bool()?;
+            self.previous_nmi_level = state.read_bool()?;
+            self.nmi_pending = state.read_bool()?;
+            self.irq_pending = state.read_bool()?;
+            // Version 1 was from before the 65C02's WAI and STP
+            if state.version() >= 2 {
+                self.waiting = state.read_bool()?;
+                self.halted = state.read_bool()?;
+            } else {
+                self.waiting = false;
+                self.halted = false;
+            }
+            Ok(())
+        })?;
+        self.bus.load_state(&mut state)?;
+        state.finish()
+    }
+}
+
+impl<B: Bus> Display for CPU6502<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // First half is instruction information
+        let instruction = self.next_instruction();
+
+        let bytes_fragment = match instruction.width {
+            1 => format!("{:02X}       ", instruction.opcode_byte),
+            2 => format!("{:02X} {:02X}    ", instruction.opcode_byte, instruction.data.0),
+            _ => format!("{:02X} {:02X} {:02X} ", instruction.opcode_byte, instruction.data.0, instruction.data.1)
+        };
+
+        // Unofficial opcodes are marked with an asterisk in the nestest logs
+        let official_marker = if instruction.is_unofficial() { "*" } else { " " };
+
+        let operand_fragment: String;
+        match instruction.addressing_mode {
+            AddressingMode::Implied => operand_fragment = format!("{:?}", instruction.opcode),
+            AddressingMode::Immediate => operand_fragment = format!("{:?} #${:02X}", instruction.opcode, instruction.data.0),
+            AddressingMode::Absolute => {
+                // Infuriatingly most instructions have special logging requirements, where the value at the address is included.
+                // Only the jumps, which don't touch the value at the address, are logged without it
+                match instruction.opcode {
+                    Opcode::JMP | Opcode::JSR => operand_fragment = format!("{:?} ${:02X}{:02X}", instruction.opcode, instruction.data.1, instruction.data.0),
+                    _ => {
+                        let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                        let byte = self.bus.peek(address); 
+                        operand_fragment = format!("{:?} ${:02X}{:02X} = {:02X}", instruction.opcode, instruction.data.1, instruction.data.0, byte)
+                    }
+                }
+            },
+            AddressingMode::AbsoluteIndexedX => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let initial_address = to_address_from_bytes(instruction.data);
+                operand_fragment = format!("{:?} ${:04X},X @ {:04X} = {:02X}", instruction.opcode, initial_address, address, self.bus.peek(address));
+            },
+            AddressingMode::AbsoluteIndexedY => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let initial_address = to_address_from_bytes(instruction.data);
+                operand_fragment = format!("{:?} ${:04X},Y @ {:04X} = {:02X}", instruction.opcode, initial_address, address, self.bus.peek(address));
+            },
+            AddressingMode::IndexedIndirect => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let byte = self.bus.peek(address); 
+                operand_fragment = format!("{:?} (${:02X},X) @ {:02X} = {:04X} = {:02X}", instruction.opcode, instruction.data.0, self.x.wrapping_add(instruction.data.0), address, byte);
+            },
+            AddressingMode::ZeroPage => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let byte = self.bus.peek(address);
+                operand_fragment = format!("{:?} ${:02X} = {:02X}", instruction.opcode, instruction.data.0, byte);
+            },
+            AddressingMode::ZeroPageIndexedX => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let byte = self.bus.peek(address);
+                operand_fragment = format!("{:?} ${:02X},X @ {:02X} = {:02X}", instruction.opcode, instruction.data.0, address, byte);
+            },
+            AddressingMode::ZeroPageIndexedY => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let byte = self.bus.peek(address);
+                operand_fragment = format!("{:?} ${:02X},Y @ {:02X} = {:02X}", instruction.opcode, instruction.data.0, address, byte);
+            },
+            AddressingMode::IndirectIndexed => {
+                let address = to_address_from_bytes((self.bus.peek(instruction.data.0 as u16),
+                    self.bus.peek(instruction.data.0.wrapping_add(1) as u16)));
+                let (end_address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let byte = self.bus.peek(end_address); 
+                operand_fragment = format!("{:?} (${:02X}),Y = {:04X} @ {:04X} = {:02X}", instruction.opcode, instruction.data.0, address, end_address, byte);
+            },
+            AddressingMode::Accumulator => operand_fragment = format!("{:?} A", instruction.opcode),
+            AddressingMode::Relative => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                operand_fragment = format!("{:?} ${:02X}", instruction.opcode, address);
+            },
+            AddressingMode::Indirect => {
+                let (indirect_address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                operand_fragment = format!("{:?} (${:02X}{:02X}) = {:04X}", instruction.opcode, instruction.data.1, instruction.data.0, indirect_address);
+            },
+            AddressingMode::ZeroPageIndirect => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let byte = self.bus.peek(address);
+                operand_fragment = format!("{:?} (${:02X}) = {:04X} = {:02X}", instruction.opcode, instruction.data.0, address, byte);
+            },
+            AddressingMode::AbsoluteIndexedIndirect => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                operand_fragment = format!("{:?} (${:02X}{:02X},X) = {:04X}", instruction.opcode, instruction.data.1, instruction.data.0, address);
+            },
+            AddressingMode::ZeroPageRelative => {
+                let (address, _) = self.peek_address_operand(instruction.data, instruction.addressing_mode).unwrap_or_default();
+                let byte = self.bus.peek(instruction.data.0 as u16);
+                operand_fragment = format!("{:?} ${:02X} = {:02X},${:04X}", instruction.opcode, instruction.data.0, byte, address);
+            },
+        };
+
+        let mut first_half = format!("{:04X}  {}{}{}", self.pc, bytes_fragment, official_marker, operand_fragment);
+        let padding_required = 48 - first_half.len();
+        first_half += (0..padding_required).map(|_| " ").collect::<String>().as_str();
+
+        // Second is processor state
+        write!(f, "{}{}", first_half, self.processor_state())
+    }
+}
+
+impl<B: Bus> CPU6502<B> {
+    /// The registers and timing, in the nestest log format
+    fn processor_state(&self) -> String {
+        let (ppu_scanline, ppu_dot) = self.bus.ppu_position();
+        format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+                self.a, self.x, self.y, self.flags.as_byte(), self.sp, ppu_scanline, ppu_dot, self.cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::{self, BufRead}};
+
+    use super::*;
+    use crate::{assembler, mapper::{self, test_cartridge, Nrom}, nes_bus::NesBus, rewind, savestate};
+
+    #[test]
+    fn test_legal_instructions_with_nestest() {
+        run_nestest(false);
+    }
+
+    #[test]
+    fn test_nestest_cycle_accurate() {
+        // Counting the cycles one bus access at a time must agree with the timing table
+        run_nestest(true);
+    }
+
+    fn run_nestest(cycle_accurate: bool) {
+        // We need something against which we can compare our execution of the nestest binary. Fortunately there are
+        // log files available. So we open the nestest.log file into a line-by-line iterator
+        let log_file = File::open("nestest.log").unwrap();
+        let logs = io::BufReader::new(log_file).lines();
+
+        // ...then we set up the CPU on a NES, so the PPU runs alongside it. nestest is a 16KiB NROM cartridge
+        let cartridge = test_cartridge(0, include_bytes!("../nestest.bin").to_vec(), vec![]);
+        let mut cpu = CPU6502::new(NesBus::new(Box::new(Nrom::new(&cartridge))));
+        cpu.set_cycle_accurate(cycle_accurate);
+
+        // ... and boot it as if from a cartridge. The automated version of nestest starts at 0xC000 rather
+        // than the reset vector
+        cpu.reset();
+        cpu.pc = 0xC000;
+
+        // ...and iterate through the log lines, executing instructions as we go
+        for line in logs.enumerate() {
+            if let (line_no, Ok(log)) = line {
+                let cpu_log = cpu.to_string();
+                if log.trim() == cpu_log {
+                    println!("Instruction {} ✓ - {} ", line_no, cpu_log);
+                    cpu.step().unwrap();
+                } else {
+                    std::panic!("Expected\n{},\ngot\n{}", log, cpu_log)
+                }
+            }
+        }
+    }
+
+    /// Builds a CPU over flat memory with the given program at 0x8000 and all three vectors pointing at
+    /// different handlers: NMI at 0x9000, reset at 0x8000 and IRQ/BRK at 0xA000. The handlers are NOPs
+    fn cpu_with_program<B: Bus>(bus: B, program: &[u8]) -> CPU6502<B> {
+        let mut cpu = CPU6502::new(bus);
+        cpu.load_memory(0x8000, program);
+        cpu.load_memory(0x9000, &[0xEA; 4]);
+        cpu.load_memory(0xA000, &[0xEA; 4]);
+        cpu.load_memory(NMI_VECTOR, &[0x00, 0x90, 0x00, 0x80, 0x00, 0xA0]);
+        cpu.reset();
+        cpu
+    }
+
+    /// The same as `cpu_with_program`, but on an NES with the program in an NROM cartridge
+    fn nes_with_program(program: &[u8]) -> CPU6502<NesBus> {
+        nes_with_board(0, program)
+    }
+
+    /// A console with 32KiB of PRG-ROM and 8KiB of CHR-RAM on the given mapper
+    fn nes_with_board(mapper: u16, program: &[u8]) -> CPU6502<NesBus> {
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x7FFA..].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0xA0]);
+        let mapper = mapper::from_cartridge(&test_cartridge(mapper, prg_rom, vec![])).unwrap();
+        let mut cpu = CPU6502::new(NesBus::new(mapper));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut memory = [0; MEMORY_SIZE];
+        let cpu = cpu_with_program(memory.as_mut_slice(), &[]);
+        assert_eq!(cpu.pc, 0x8000);
+        assert_eq!(cpu.sp, 0xFD);
+        assert_eq!(cpu.flags.as_byte(), 0x24);
+        assert_eq!(cpu.cycles, 7);
+    }
+
+    #[test]
+    fn test_nmi_is_edge_triggered() {
+        let mut memory = [0; MEMORY_SIZE];
+        // NOP; NOP
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xEA, 0xEA]);
+        cpu.set_nmi_line(true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(cpu.cycles, 14);
+        // Return address, then the flags with the break flag clear
+        assert_eq!(&memory_at(&cpu, 0x01FB, 3), &[0x24, 0x00, 0x80]);
+
+        // Holding the line doesn't trigger another NMI
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x9001);
+    }
+
+    #[test]
+    fn test_irq_respects_interrupt_disable() {
+        let mut memory = [0; MEMORY_SIZE];
+        // CLI; NOP; NOP
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0x58, 0xEA, 0xEA]);
+        cpu.set_irq_line(true);
+        cpu.step().unwrap();
+        // CLI only takes effect after the next instruction
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x8002);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xA000);
+        assert!(cpu.flags.interrupt_disable);
+        assert_eq!(memory_at(&cpu, 0x01FB, 1)[0] & 0b00010000, 0);
+    }
+
+    #[test]
+    fn test_brk_and_rti() {
+        let mut memory = [0; MEMORY_SIZE];
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0x00, 0xFF, 0xEA]);
+        // RTI at the IRQ handler
+        cpu.load_memory(0xA000, &[0x40]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xA000);
+        // Break flag is set in the pushed flags, and the return address skips the padding byte
+        assert_eq!(&memory_at(&cpu, 0x01FB, 3), &[0x34, 0x02, 0x80]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x8002);
+    }
+
+    #[test]
+    fn test_assembled_programs_run() {
+        // Multiplies 7 by 6 by repeated addition in a subroutine, and stores the result through a pointer
+        let assembly = assembler::assemble("
+            RESULT = $0300
+            POINTER = $10
+                    .org $8000
+                    LDA #<RESULT
+                    STA POINTER
+                    LDA #>RESULT
+                    STA POINTER + 1
+                    LDX #6
+                    JSR multiply
+                    LDY #0
+                    STA (POINTER),Y
+            done:   JMP done
+
+            multiply:
+                    LDA #0
+                    CLC
+            loop:   ADC #7
+                    DEX
+                    BNE loop
+                    RTS
+        ").unwrap();
+        let mut memory = [0; MEMORY_SIZE];
+        assembly.load_into(&mut memory);
+        let program = memory[0x8000..0x8100].to_vec();
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &program);
+        while cpu.pc != assembly.labels["done"] {
+            cpu.step().unwrap();
+        }
+        assert_eq!(memory_at(&cpu, 0x0300, 1), [42]);
+    }
+
+    /// Runs `SED; SEC/CLC; LDA #a; ADC/SBC #operand`, returning A and the N, V, Z and C flags
+    fn decimal_arithmetic(variant: CpuVariant, opcode: u8, a: u8, operand: u8, carry: bool) -> (u8, u8) {
+        let mut memory = [0; MEMORY_SIZE];
+        let set_carry = if carry { 0x38 } else { 0x18 };
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xF8, set_carry, 0xA9, a, opcode, operand]);
+        cpu.set_variant(variant);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        (cpu.a, cpu.flags.as_byte() & 0b11000011)
+    }
+
+    #[test]
+    fn test_nmos_decimal_mode() {
+        const ADC: u8 = 0x69;
+        const SBC: u8 = 0xE9;
+        let nmos = CpuVariant::Nmos6502;
+        assert_eq!(decimal_arithmetic(nmos, ADC, 0x09, 0x01, false), (0x10, 0x00));
+        assert_eq!(decimal_arithmetic(nmos, ADC, 0x58, 0x46, true), (0x05, 0xC1));
+        // Z comes from the binary sum, $9A, and N from the high digit before it's adjusted
+        assert_eq!(decimal_arithmetic(nmos, ADC, 0x99, 0x01, false), (0x00, 0x81));
+        assert_eq!(decimal_arithmetic(nmos, ADC, 0x79, 0x00, true), (0x80, 0xC0));
+
+        // SBC's flags are all from the binary subtraction
+        assert_eq!(decimal_arithmetic(nmos, SBC, 0x46, 0x12, true), (0x34, 0x01));
+        assert_eq!(decimal_arithmetic(nmos, SBC, 0x32, 0x02, false), (0x29, 0x01));
+        assert_eq!(decimal_arithmetic(nmos, SBC, 0x12, 0x21, true), (0x91, 0x80));
+    }
+
+    #[test]
+    fn test_2a03_ignores_decimal_mode() {
+        assert_eq!(CpuVariant::default(), CpuVariant::Ricoh2A03);
+        assert_eq!(decimal_arithmetic(CpuVariant::Ricoh2A03, 0x69, 0x58, 0x46, true), (0x9F, 0xC0));
+        assert_eq!(decimal_arithmetic(CpuVariant::Ricoh2A03, 0xE9, 0x12, 0x21, true), (0xF1, 0x80));
+    }
+
+    fn run_instructions<B: Bus>(cpu: &mut CPU6502<B>, count: usize) {
+        for _ in 0..count {
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_65c02_instructions() {
+        let mut memory = [0; MEMORY_SIZE];
+        let program = [
+            0xA2, 0x11, 0xA0, 0x22, // LDX #$11; LDY #$22
+            0xDA, 0x5A, 0xFA, 0x7A, // PHX; PHY; PLX; PLY
+            0xA9, 0xFF, 0x1A, 0x3A, // LDA #$FF; INC A; DEC A
+            0x85, 0x10, 0x64, 0x10, // STA $10; STZ $10
+            0xA9, 0x0F, 0x04, 0x10, // LDA #$0F; TSB $10
+            0xA9, 0x03, 0x14, 0x10, // LDA #$03; TRB $10
+            0xF7, 0x10, 0x27, 0x10, // SMB7 $10; RMB2 $10
+            0xA9, 0x10, 0x85, 0x20, 0x64, 0x21, 0xB2, 0x20, // LDA #$10; STA $20; STZ $21; LDA ($20)
+            0x7F, 0x10, 0x01, 0xFF, 0x10, 0x01, 0xDB, // BBR7 $10,+1; BBS7 $10,+1; STP
+            0x80, 0x01, 0xDB, // BRA +1; STP
+            0x89, 0x00, 0xEA // BIT #$00; NOP
+        ];
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &program);
+        cpu.set_variant(CpuVariant::Wdc65C02);
+
+        run_instructions(&mut cpu, 9);
+        assert_eq!((cpu.a, cpu.x, cpu.y, cpu.sp), (0xFF, 0x22, 0x11, 0xFD));
+        assert!(cpu.flags.negative);
+        run_instructions(&mut cpu, 4);
+        // TSB and TRB set Z from the bits that were already set
+        assert_eq!(memory_at(&cpu, 0x10, 1), [0x0F]);
+        assert!(cpu.flags.zero);
+        run_instructions(&mut cpu, 2);
+        assert_eq!(memory_at(&cpu, 0x10, 1), [0x0C]);
+        assert!(!cpu.flags.zero);
+        run_instructions(&mut cpu, 2);
+        assert_eq!(memory_at(&cpu, 0x10, 1), [0x88]);
+
+        // The branches skip both STPs
+        run_instructions(&mut cpu, 8);
+        assert_eq!(cpu.pc, 0x8030);
+        assert_eq!(cpu.a, 0x88);
+        // BIT immediate only changes Z
+        assert!(cpu.flags.zero && cpu.flags.negative);
+
+        // Otherwise the official instructions are the same as on the NMOS 6502
+        for byte in 0..=u8::MAX {
+            let nmos = Instruction::from_bytes(byte, (0, 0));
+            if nmos.is_unofficial() {
+                continue;
+            }
+            let cmos = Instruction::from_bytes_for(CpuVariant::Wdc65C02, byte, (0, 0));
+            assert_eq!((cmos.opcode, cmos.addressing_mode, cmos.width), (nmos.opcode, nmos.addressing_mode, nmos.width));
+        }
+    }
+
+    #[test]
+    fn test_65c02_fixes_nmos_bugs() {
+        // JMP ($10FF) takes the high byte of its target from $1000 on the NMOS 6502, and $1100 on the 65C02
+        for (variant, target) in [(CpuVariant::Nmos6502, 0x5634), (CpuVariant::Wdc65C02, 0x1234)] {
+            let mut memory = [0; MEMORY_SIZE];
+            let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0x6C, 0xFF, 0x10]);
+            cpu.set_variant(variant);
+            cpu.load_memory(0x1000, &[0x56]);
+            cpu.load_memory(0x10FF, &[0x34, 0x12]);
+            cpu.step().unwrap();
+            assert_eq!(cpu.pc, target);
+        }
+
+        // Interrupts clear the decimal flag on the 65C02, though the pushed flags still have it set
+        for (variant, decimal_mode) in [(CpuVariant::Nmos6502, true), (CpuVariant::Wdc65C02, false)] {
+            let mut memory = [0; MEMORY_SIZE];
+            // SED; BRK
+            let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xF8, 0x00, 0xFF]);
+            cpu.set_variant(variant);
+            run_instructions(&mut cpu, 2);
+            assert_eq!(cpu.pc, 0xA000);
+            assert_eq!(cpu.flags.decimal_mode, decimal_mode);
+            assert_eq!(memory_at(&cpu, 0x01FB, 1)[0] & 0b00001000, 0b00001000);
+        }
+
+        // Its decimal arithmetic sets N and Z from the result
+        assert_eq!(decimal_arithmetic(CpuVariant::Wdc65C02, 0x69, 0x99, 0x01, false), (0x00, 0x03));
+    }
+
+    #[test]
+    fn test_65c02_timing() {
+        // SED; ADC #$01; SBC $10; CLD; ADC #$01; LDX #$01; ASL $1000,X; ROR $10FF,X; INC $1000,X
+        let program = [
+            0xF8, 0x69, 0x01, 0xE5, 0x10, 0xD8, 0x69, 0x01, 0xA2, 0x01, 0x1E, 0x00, 0x10, 0x7E, 0xFF, 0x10, 0xFE, 0x00, 0x10
+        ];
+        // Decimal ADC and SBC take a cycle longer, and shifts and rotates on abs,X a cycle less unless they cross a page
+        let timings = [
+            (CpuVariant::Nmos6502, [2, 2, 3, 2, 2, 2, 7, 7, 7]),
+            (CpuVariant::Wdc65C02, [2, 3, 4, 2, 2, 2, 6, 7, 7])
+        ];
+        for cycle_accurate in [false, true] {
+            for (variant, cycles) in timings {
+                let mut memory = [0; MEMORY_SIZE];
+                let mut cpu = cpu_with_program(memory.as_mut_slice(), &program);
+                cpu.set_variant(variant);
+                cpu.set_cycle_accurate(cycle_accurate);
+                let taken: Vec<usize> = (0..cycles.len()).map(|_| cpu.step().unwrap().cycles).collect();
+                assert_eq!(taken, cycles, "{:?}", variant);
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_reports_what_it_did() {
+        let mut memory = [0; MEMORY_SIZE];
+        // LDA #$42; then an interrupt
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xA9, 0x42]);
+        let info = cpu.step().unwrap();
+        assert_eq!((info.pc, info.cycles), (0x8000, 2));
+        assert_eq!(info.instruction.map(|instruction| instruction.opcode), Some(Opcode::LDA));
+        cpu.set_nmi_line(true);
+        assert_eq!(cpu.step(), Ok(StepInfo { pc: 0x8002, instruction: None, cycles: 7 }));
+
+        // Instructions at the top of memory take their operands from the bottom
+        cpu.load_memory(0xFFFF, &[0xA9]);
+        cpu.load_memory(0x0000, &[0x24]);
+        cpu.pc = 0xFFFF;
+        cpu.step().unwrap();
+        assert_eq!((cpu.a, cpu.pc), (0x24, 0x0001));
+    }
+
+    #[test]
+    fn test_memory_smaller_than_the_address_space_is_mirrored() {
+        // 256 bytes appear in every page, so the vectors and the top of the stack share its last few bytes
+        let mut memory = [0; 0x100];
+        memory[0xFC..].copy_from_slice(&[0x10, 0x80, 0x00, 0x00]);
+        // LDA #$42; PHA; STA $1234
+        memory[0x10..0x16].copy_from_slice(&[0xA9, 0x42, 0x48, 0x8D, 0x34, 0x12]);
+        let mut cpu = CPU6502::new(memory.as_mut_slice());
+        cpu.reset();
+        run_instructions(&mut cpu, 3);
+        assert_eq!(cpu.pc, 0x8016);
+        assert_eq!((memory[0xFD], memory[0x34]), (0x42, 0x42));
+
+        // With no memory at all, everything reads as zero
+        let mut cpu = CPU6502::new(&mut [][..]);
+        cpu.reset();
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.step().map(|step| step.instruction.map(|instruction| instruction.opcode)), Ok(Some(Opcode::BRK)));
+    }
+
+    #[test]
+    fn test_step_returns_jams_as_errors() {
+        let jams = [0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2];
+        for cycle_accurate in [false, true] {
+            for opcode in jams {
+                let mut memory = [0; MEMORY_SIZE];
+                // LDA #$01, then the JAM
+                let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xA9, 0x01, opcode, 0xA9, 0x02]);
+                cpu.set_cycle_accurate(cycle_accurate);
+                cpu.step().unwrap();
+                let error = cpu.step().unwrap_err();
+                assert_eq!(error, CpuError::Jam { address: 0x8002, opcode });
+                assert_eq!(error.to_string(), format!("CPU jammed by opcode ${:02X} at $8002", opcode));
+                // The CPU is left on the JAM, without running anything after it
+                assert_eq!((cpu.pc, cpu.a), (0x8002, 0x01));
+                assert_eq!(cpu.next_instruction().opcode, Opcode::JAM);
+            }
+        }
+
+        // They're NOPs on the 65C02
+        let mut memory = [0; MEMORY_SIZE];
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0x02, 0x00]);
+        cpu.set_variant(CpuVariant::Wdc65C02);
+        assert_eq!(cpu.step().map(|step| step.pc), Ok(0x8000));
+        assert_eq!(cpu.pc, 0x8002);
+    }
+
+    #[test]
+    fn test_invalid_addressing_modes_are_errors() {
+        // The instruction tables never give an instruction a mode it can't use, so step can't reach this error.
+        // A made-up instruction is run the way step runs it instead
+        let mut memory = [0; MEMORY_SIZE];
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xA9, 0x01]);
+        let mut instruction = cpu.next_instruction();
+        instruction.addressing_mode = AddressingMode::Implied;
+        let error = cpu.execute_instruction(instruction).unwrap_err();
+        assert_eq!(error, CpuError::InvalidAddressingMode { address: 0x8000, opcode: 0xA9, addressing_mode: AddressingMode::Implied });
+        assert_eq!(error.to_string(), "opcode $A9 at $8000 can't use addressing mode Implied");
+        assert_eq!((cpu.pc, cpu.a), (0x8000, 0x00));
+        // Stepping runs the real instruction
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.a), (0x8002, 0x01));
+    }
+
+    #[test]
+    fn test_jam_halts_until_reset() {
+        let mut memory = [0; MEMORY_SIZE];
+        // LDX #$01; JAM
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xA2, 0x01, 0x12]);
+        cpu.step().unwrap();
+        let cycles = cpu.cycles;
+        assert_eq!(cpu.step(), Err(CpuError::Jam { address: 0x8002, opcode: 0x12 }));
+        assert!(cpu.is_halted());
+        assert_eq!((cpu.pc, cpu.cycles), (0x8002, cycles + 2));
+        assert!(cpu.to_string().starts_with("8002  12       *JAM"));
+
+        // The clock keeps going, but nothing else happens, not even interrupts
+        cpu.set_nmi_line(true);
+        for _ in 0..3 {
+            assert_eq!(cpu.step(), Ok(StepInfo { pc: 0x8002, instruction: None, cycles: 1 }));
+        }
+        assert_eq!(cpu.x, 0x01);
+        cpu.reset();
+        assert!(!cpu.is_halted());
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x8002);
+
+        // The rest of the console keeps running, and a save state remembers the CPU is halted
+        let mut nes = nes_with_program(&[0x02]);
+        assert_eq!(nes.step(), Err(CpuError::Jam { address: 0x8000, opcode: 0x02 }));
+        run_frames(&mut nes, 2);
+        assert_eq!(nes.bus.ppu().frame(), 2);
+        let mut restored = nes_with_program(&[0x02]);
+        restored.load_state(&nes.save_state()).unwrap();
+        assert!(restored.is_halted());
+    }
+
+    #[test]
+    fn test_wai_and_stp() {
+        // WAI; NOP. Interrupts are disabled after reset, so the IRQ wakes the CPU without being serviced
+        let mut memory = [0; MEMORY_SIZE];
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xCB, 0xEA]);
+        cpu.set_variant(CpuVariant::Wdc65C02);
+        run_instructions(&mut cpu, 4);
+        assert_eq!((cpu.pc, cpu.cycles), (0x8001, 13));
+        cpu.set_irq_line(true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x8002);
+
+        // CLI; WAI. With interrupts enabled, the IRQ is serviced
+        let mut memory = [0; MEMORY_SIZE];
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0x58, 0xCB]);
+        cpu.set_variant(CpuVariant::Wdc65C02);
+        run_instructions(&mut cpu, 3);
+        cpu.set_irq_line(true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xA000);
+
+        // STP ignores interrupts, and only a reset starts it again
+        let mut memory = [0; MEMORY_SIZE];
+        let mut cpu = cpu_with_program(memory.as_mut_slice(), &[0xDB]);
+        cpu.set_variant(CpuVariant::Wdc65C02);
+        cpu.step().unwrap();
+        cpu.set_nmi_line(true);
+        cpu.set_irq_line(true);
+        run_instructions(&mut cpu, 4);
+        assert_eq!((cpu.pc, cpu.cycles), (0x8001, 14));
+        cpu.reset();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x8001);
+    }
+
+    /// A flat bus which raises NMI as soon as anything is pushed on the stack
+    struct NmiOnStackWrite {
+        memory: Vec<u8>,
+        nmi: bool
+    }
+
+    impl Bus for NmiOnStackWrite {
+        fn read(&mut self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.nmi |= (address & 0xFF00) == STACK_PAGE;
+            self.memory[address as usize] = value;
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn nmi_asserted(&self) -> bool {
+            self.nmi
+        }
+    }
+
+    #[test]
+    fn test_nmi_hijacks_brk() {
+        let mut cpu = cpu_with_program(NmiOnStackWrite { memory: vec![0; MEMORY_SIZE], nmi: false }, &[0x00, 0xFF]);
+        cpu.step().unwrap();
+        // Execution continues at the NMI handler, but the pushed flags still have the break flag set
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(memory_at(&cpu, 0x01FB, 1)[0] & 0b00010000, 0b00010000);
+        // ...and the NMI isn't serviced a second time
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x9001);
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq_interrupts_the_cpu() {
+        let program = [
+            0xA9, 0x08, 0x8D, 0x00, 0x20, // LDA #$08; STA $2000 (sprites at $1000)
+            0xA9, 0x18, 0x8D, 0x01, 0x20, // LDA #$18; STA $2001
+            0xA9, 0x04, 0x8D, 0x00, 0xC0, // LDA #$04; STA $C000
+            0x8D, 0x01, 0xC0,             // STA $C001
+            0x8D, 0x01, 0xE0,             // STA $E001
+            0x58,                         // CLI
+            0x4C, 0x16, 0x80              // JMP $8016
+        ];
+        let mut cpu = nes_with_board(4, &program);
+        while cpu.pc != 0xA000 && cpu.cycles < 10000 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, 0xA000);
+        // The counter is reloaded on the first scanline and reaches zero four scanlines later
+        let (scanline, _) = cpu.bus.ppu_position();
+        assert_eq!(scanline, 4);
+    }
+
+    /// A program that keeps every part of the machine busy: rendering, audio, and the stack and RAM
+    const BUSY_PROGRAM: [u8; 18] = [
+        0xA9, 0x1E, 0x8D, 0x01, 0x20, // LDA #$1E; STA $2001
+        0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF; STA $4000
+        0x8D, 0x15, 0x40,             // STA $4015
+        0xE6, 0x10,                   // INC $10
+        0x4C, 0x0D, 0x80              // JMP $800D
+    ];
+
+    fn run_frames(cpu: &mut CPU6502<NesBus>, frames: u64) {
+        let target = cpu.bus.ppu().frame() + frames;
+        while cpu.bus.ppu().frame() < target {
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_save_states_resume_exactly() {
+        let mut cpu = nes_with_program(&BUSY_PROGRAM);
+        run_frames(&mut cpu, 2);
+        let state = cpu.save_state();
+        run_frames(&mut cpu, 3);
+        let expected = cpu.save_state();
+
+        // Loading into a fresh machine and running the same frames ends up in exactly the same place
+        let mut restored = nes_with_program(&BUSY_PROGRAM);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        run_frames(&mut restored, 3);
+        assert_eq!(restored.save_state(), expected);
+        assert_eq!(restored.bus.ppu().framebuffer(), cpu.bus.ppu().framebuffer());
+    }
+
+    #[test]
+    fn test_rewinding_steps_the_machine_back() {
+        let mut cpu = nes_with_program(&BUSY_PROGRAM);
+        let mut rewind = rewind::RewindBuffer::new(1 << 20);
+        let mut frames = Vec::new();
+        for _ in 0..5 {
+            run_frames(&mut cpu, 1);
+            rewind.push(&cpu.save_state());
+            frames.push((cpu.bus.ppu().frame(), cpu.cycles, memory_at(&cpu, 0x10, 1)));
+        }
+
+        while let Some(state) = rewind.pop() {
+            cpu.load_state(&state).unwrap();
+            assert_eq!(Some((cpu.bus.ppu().frame(), cpu.cycles, memory_at(&cpu, 0x10, 1))), frames.pop());
+        }
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn test_bad_save_states_leave_the_machine_alone() {
+        let mut cpu = nes_with_program(&BUSY_PROGRAM);
+        run_frames(&mut cpu, 1);
+        let before = cpu.save_state();
+
+        // A state from a board with different registers fails part way through loading
+        let mut mmc3 = nes_with_board(4, &BUSY_PROGRAM);
+        run_frames(&mut mmc3, 2);
+        assert!(matches!(cpu.load_state(&mmc3.save_state()), Err(StateError::Incompatible(_))));
+        assert_eq!(cpu.save_state(), before);
+
+        let mut newer = before.clone();
+        newer[8..10].copy_from_slice(&(savestate::VERSION + 1).to_le_bytes());
+        assert_eq!(cpu.load_state(&newer), Err(StateError::UnsupportedVersion(savestate::VERSION + 1)));
+        assert_eq!(cpu.load_state(&before[..before.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn test_version_1_save_states_still_load() {
+        let mut cpu = nes_with_program(&BUSY_PROGRAM);
+        run_frames(&mut cpu, 1);
+        let state = cpu.save_state();
+
+        // Version 1 didn't have the WAI and STP flags at the end of the CPU section, which comes first
+        let cpu_length = u32::from_le_bytes(state[14..18].try_into().unwrap()) as usize;
+        let mut old = state[..18 + cpu_length - 2].to_vec();
+        old.extend_from_slice(&state[18 + cpu_length..]);
+        old[8..10].copy_from_slice(&1u16.to_le_bytes());
+        old[14..18].copy_from_slice(&(cpu_length as u32 - 2).to_le_bytes());
+
+        let mut restored = nes_with_program(&BUSY_PROGRAM);
+        assert_eq!(restored.load_state(&old), Ok(()));
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_the_cpu() {
+        for cycle_accurate in [false, true] {
+            // LDA #$02; STA $4014; STA $00; STA $4014
+            let mut cpu = nes_with_program(&[0xA9, 0x02, 0x8D, 0x14, 0x40, 0x85, 0x00, 0x8D, 0x14, 0x40]);
+            cpu.set_cycle_accurate(cycle_accurate);
+            let page: Vec<u8> = (0..=0xFF).collect();
+            cpu.load_memory(0x0200, &page);
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            // Reset and the two instructions take 13 cycles, so the DMA starts on an odd cycle
+            assert_eq!(cpu.cycles, 7 + 2 + 4 + 514);
+            assert_eq!(cpu.bus.ppu().peek_oam(0x00), 0x00);
+            assert_eq!(cpu.bus.ppu().peek_oam(0x7F), 0x7F);
+            assert_eq!(cpu.bus.ppu().peek_oam(0xFF), 0xFF);
+            // ...and the second on an even one
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            assert_eq!(cpu.cycles, 7 + 2 + 4 + 514 + 3 + 4 + 513);
+        }
+    }
+
+    #[test]
+    fn test_dmc_sample_fetches_stall_the_cpu() {
+        for cycle_accurate in [false, true] {
+            // LDA #$00; STA $4013; LDA #$10; STA $4015; NOP; NOP
+            let mut cpu = nes_with_program(&[0xA9, 0x00, 0x8D, 0x13, 0x40, 0xA9, 0x10, 0x8D, 0x15, 0x40, 0xEA, 0xEA]);
+            cpu.set_cycle_accurate(cycle_accurate);
+            run_instructions(&mut cpu, 4);
+            // Enabling the DMC with a one byte sample fetches it before the next instruction, and only once
+            assert_eq!(cpu.bus.dmc_dma_request(), Some(0xC000));
+            assert_eq!(cpu.step().unwrap().cycles, 2 + DMC_DMA_CYCLES);
+            assert_eq!(cpu.bus.dmc_dma_request(), None);
+            assert_eq!(cpu.step().unwrap().cycles, 2);
+        }
+    }
+
+    #[derive(PartialEq, Debug)]
+    enum BusAccess {
+        Read(u16),
+        Write(u16, u8)
+    }
+
+    /// Flat memory that records every access and tick, to check the cycle-by-cycle behaviour
+    struct RecordingBus {
+        memory: Vec<u8>,
+        accesses: Vec<BusAccess>,
+        ticks: usize
+    }
+
+    impl RecordingBus {
+        fn new() -> Self {
+            Self { memory: vec![0; MEMORY_SIZE], accesses: Vec::new(), ticks: 0 }
+        }
+    }
+
+    impl Bus for RecordingBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.accesses.push(BusAccess::Read(address));
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.accesses.push(BusAccess::Write(address, value));
+            self.memory[address as usize] = value;
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn tick(&mut self) {
+            self.ticks += 1;
+        }
+    }
+
+    fn cycle_accurate_cpu(program: &[u8]) -> CPU6502<RecordingBus> {
+        let mut cpu = cpu_with_program(RecordingBus::new(), program);
+        cpu.set_cycle_accurate(true);
+        cpu
+    }
+
+    /// Executes a single instruction and returns the bus accesses it made, checking there was one per cycle
+    fn execute_recorded(cpu: &mut CPU6502<RecordingBus>) -> Vec<BusAccess> {
+        cpu.bus.accesses.clear();
+        cpu.bus.ticks = 0;
+        let start_cycles = cpu.cycles;
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycles - start_cycles, cpu.bus.accesses.len());
+        assert_eq!(cpu.bus.ticks, cpu.bus.accesses.len());
+        std::mem::take(&mut cpu.bus.accesses)
+    }
+
+    #[test]
+    fn test_read_modify_write_makes_dummy_accesses() {
+        // INC $12F0,X with X = 0x20 crosses into page 0x13
+        let mut cpu = cycle_accurate_cpu(&[0xFE, 0xF0, 0x12]);
+        cpu.load_memory(0x1310, &[0x41]);
+        cpu.x = 0x20;
+        assert_eq!(execute_recorded(&mut cpu), vec![
+            BusAccess::Read(0x8000),
+            BusAccess::Read(0x8001),
+            BusAccess::Read(0x8002),
+            // Dummy read before the high byte is fixed up
+            BusAccess::Read(0x1210),
+            BusAccess::Read(0x1310),
+            // The unmodified value is written back before the result
+            BusAccess::Write(0x1310, 0x41),
+            BusAccess::Write(0x1310, 0x42)
+        ]);
+    }
+
+    #[test]
+    fn test_indexed_reads_only_make_dummy_reads_across_pages() {
+        // LDA $1200,X; LDA $12F0,X
+        let mut cpu = cycle_accurate_cpu(&[0xBD, 0x00, 0x12, 0xBD, 0xF0, 0x12]);
+        cpu.x = 0x20;
+        assert_eq!(execute_recorded(&mut cpu), vec![
+            BusAccess::Read(0x8000),
+            BusAccess::Read(0x8001),
+            BusAccess::Read(0x8002),
+            BusAccess::Read(0x1220)
+        ]);
+        assert_eq!(execute_recorded(&mut cpu), vec![
+            BusAccess::Read(0x8003),
+            BusAccess::Read(0x8004),
+            BusAccess::Read(0x8005),
+            BusAccess::Read(0x1210),
+            BusAccess::Read(0x1310)
+        ]);
+    }
+
+    #[test]
+    fn test_jsr_and_rts_accesses() {
+        // JSR $8010, then RTS at $8010
+        let mut program = [0xEA; 0x11];
+        program[..3].copy_from_slice(&[0x20, 0x10, 0x80]);
+        program[0x10] = 0x60;
+        let mut cpu = cycle_accurate_cpu(&program);
+        assert_eq!(execute_recorded(&mut cpu), vec![
+            BusAccess::Read(0x8000),
+            BusAccess::Read(0x8001),
+            BusAccess::Read(0x01FD),
+            BusAccess::Write(0x01FD, 0x80),
+            BusAccess::Write(0x01FC, 0x02),
+            BusAccess::Read(0x8002)
+        ]);
+        assert_eq!(cpu.pc, 0x8010);
+
+        assert_eq!(execute_recorded(&mut cpu), vec![
+            BusAccess::Read(0x8010),
+            BusAccess::Read(0x8011),
+            BusAccess::Read(0x01FB),
+            BusAccess::Read(0x01FC),
+            BusAccess::Read(0x01FD),
+            BusAccess::Read(0x8002)
+        ]);
+        assert_eq!(cpu.pc, 0x8003);
+    }
+
+    fn memory_at<B: Bus>(cpu: &CPU6502<B>, address: u16, length: u16) -> Vec<u8> {
+        (address..address + length).map(|address| cpu.bus.peek(address)).collect()
+    }
+}
@@ -0,0 +1,563 @@
+use std::{collections::{BTreeMap, BTreeSet}, io::{self, BufRead, Write}};
+
+use crate::{bus::Bus, disassembler, instruction::Opcode, CPU6502};
+
+/// How many bytes `mem` shows when it isn't given a length
+const DEFAULT_MEMORY_LENGTH: u16 = 0x40;
+const MEMORY_ROW_LENGTH: usize = 16;
+/// How many instructions `list` shows when it isn't given a count
+const DEFAULT_LIST_LENGTH: usize = 10;
+/// There's no way to interrupt a run from the prompt, so runs that never reach a breakpoint give up after
+/// this many instructions, which is a few seconds of emulated time
+const MAX_RUN_INSTRUCTIONS: usize = 5_000_000;
+
+const HELP: &str = "\
+Addresses and values are hex, with or without a $. Counts are decimal.
+  step [count]            execute instructions (s)
+  next                    step over a JSR, running the whole subroutine (n)
+  continue                run until a breakpoint or watchpoint (c)
+  until <address>         run until the PC reaches an address (u)
+  break [address]         set a breakpoint on the PC, or list them (b)
+  delete <address>        remove a breakpoint
+  watch [address] [r|w|rw] stop on reads and/or writes of an address, or list watchpoints (w)
+  unwatch <address>       remove a watchpoint
+  regs                    show the registers (r)
+  set <register> <value>  change A, X, Y, P, SP or PC
+  mem <address> [length]  show memory (m)
+  poke <address> <bytes>  write bytes to memory through the bus
+  list [count]            disassemble from the PC (l)
+  reset                   run the CPU's reset sequence
+  quit                    exit (q)
+An empty line repeats the last command.";
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum WatchKind {
+    Read,
+    Write,
+    ReadWrite
+}
+
+impl WatchKind {
+    fn watches(self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::ReadWrite => true
+        }
+    }
+}
+
+/// An access to a watched address
+#[derive(PartialEq, Clone, Copy, Debug)]
+struct WatchHit {
+    address: u16,
+    value: u8,
+    write: bool
+}
+
+/// Passes everything through to the bus underneath, noting reads and writes of watched addresses. Peeks don't
+/// count, so tracing and the debugger's own memory views never trigger a watchpoint
+struct WatchedBus<B: Bus> {
+    bus: B,
+    watchpoints: BTreeMap<u16, WatchKind>,
+    hit: Option<WatchHit>
+}
+
+impl<B: Bus> WatchedBus<B> {
+    fn new(bus: B) -> Self {
+        Self { bus, watchpoints: BTreeMap::new(), hit: None }
+    }
+
+    /// The first watched access since this was last called
+    fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    fn check(&mut self, address: u16, value: u8, write: bool) {
+        let watched = self.watchpoints.get(&address).is_some_and(|kind| kind.watches(write));
+        if watched && self.hit.is_none() {
+            self.hit = Some(WatchHit { address, value, write });
+        }
+    }
+}
+
+impl<B: Bus> Bus for WatchedBus<B> {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.bus.read(address);
+        self.check(address, value, false);
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.check(address, value, true);
+        self.bus.write(address, value);
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.bus.peek(address)
+    }
+
+    fn tick(&mut self) {
+        self.bus.tick()
+    }
+
+    fn take_oam_dma_request(&mut self) -> Option<u8> {
+        self.bus.take_oam_dma_request()
+    }
+
+    fn dmc_dma_request(&self) -> Option<u16> {
+        self.bus.dmc_dma_request()
+    }
+
+    fn load_dmc_sample(&mut self, byte: u8) {
+        self.bus.load_dmc_sample(byte)
+    }
+
+    fn ppu_position(&self) -> (u16, u16) {
+        self.bus.ppu_position()
+    }
+
+    fn nmi_asserted(&self) -> bool {
+        self.bus.nmi_asserted()
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.bus.irq_asserted()
+    }
+}
+
+/// An interactive debugger over a CPU and whatever bus it's on. Commands are run one line at a time, and
+/// each returns the text to show. Whenever execution stops, the instruction at the PC is shown as a nestest
+/// trace line, the same as the CPU logs
+pub struct Debugger<B: Bus> {
+    cpu: CPU6502<WatchedBus<B>>,
+    breakpoints: BTreeSet<u16>
+}
+
+impl<B: Bus> Debugger<B> {
+    /// Resets the CPU, so it's stopped at the first instruction
+    pub fn new(bus: B) -> Self {
+        let mut cpu = CPU6502::new(WatchedBus::new(bus));
+        cpu.reset();
+        Self { cpu, breakpoints: BTreeSet::new() }
+    }
+
+    /// The trace line for the instruction at the PC
+    pub fn current_line(&self) -> String {
+        self.cpu.to_string()
+    }
+
+    /// Reads commands from `input` until it runs out or says quit, writing what they show to `output`
+    pub fn run_repl(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        writeln!(output, "{}", self.current_line())?;
+        write!(output, "> ")?;
+        output.flush()?;
+        let mut last_command = String::new();
+        for line in input.lines() {
+            let line = line?;
+            let command = if line.trim().is_empty() { last_command.clone() } else { line.trim().to_string() };
+            if matches!(command.as_str(), "quit" | "q") {
+                return Ok(());
+            }
+            if !command.is_empty() {
+                writeln!(output, "{}", self.execute(&command))?;
+            }
+            last_command = command;
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Runs a single command, returning its output or what was wrong with it
+    pub fn execute(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let Some((&name, arguments)) = words.split_first() else {
+            return String::new();
+        };
+        let result = match name {
+            "step" | "s" => self.step(arguments),
+            "next" | "n" => Ok(self.step_over()),
+            "continue" | "c" => Ok(self.run(|_| false)),
+            "until" | "u" => self.until(arguments),
+            "break" | "b" => self.set_breakpoint(arguments),
+            "delete" => self.delete_breakpoint(arguments),
+            "watch" | "w" => self.set_watchpoint(arguments),
+            "unwatch" => self.delete_watchpoint(arguments),
+            "regs" | "r" => Ok(self.registers()),
+            "set" => self.set_register(arguments),
+            "mem" | "m" => self.memory(arguments),
+            "poke" => self.poke(arguments),
+            "list" | "l" => self.list(arguments),
+            "reset" => Ok(self.reset()),
+            "help" | "h" => Ok(HELP.to_string()),
+            _ => Err(format!("Unknown command {}, try help", name))
+        };
+        result.unwrap_or_else(|error| error)
+    }
+
+    /// Executes instructions until `done` says to stop, or a breakpoint or watchpoint is hit. The first
+    /// instruction always runs, so continuing from a breakpoint doesn't stop straight away
+    fn run(&mut self, mut done: impl FnMut(&CPU6502<WatchedBus<B>>) -> bool) -> String {
+        self.cpu.bus_mut().take_hit();
+        for _ in 0..MAX_RUN_INSTRUCTIONS {
+            if let Err(error) = self.cpu.step() {
+                return format!("Stopped: {}\n{}", error, self.current_line());
+            }
+            if self.cpu.is_halted() {
+                return format!("Halted until reset\n{}", self.current_line());
+            }
+            if let Some(hit) = self.cpu.bus_mut().take_hit() {
+                let (action, preposition) = if hit.write { ("wrote", "to") } else { ("read", "from") };
+                return format!("Watchpoint: {} ${:02X} {} ${:04X}\n{}", action, hit.value, preposition, hit.address, self.current_line());
+            }
+            if done(&self.cpu) {
+                return self.current_line();
+            }
+            if self.breakpoints.contains(&self.cpu.pc()) {
+                return format!("Breakpoint at ${:04X}\n{}", self.cpu.pc(), self.current_line());
+            }
+        }
+        format!("Still running after {} instructions\n{}", MAX_RUN_INSTRUCTIONS, self.current_line())
+    }
+
+    fn step(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let count = match arguments {
+            [] => 1,
+            // Running nothing would still run an instruction, as the check is made after each one
+            [count] => count.parse::<usize>().ok().filter(|&count| count > 0)
+                .ok_or_else(|| format!("Can't step {} instructions", count))?,
+            _ => return Err("Usage: step [count]".to_string())
+        };
+        let mut remaining = count;
+        Ok(self.run(|_| {
+            remaining = remaining.saturating_sub(1);
+            remaining == 0
+        }))
+    }
+
+    /// Steps over a subroutine call by running until it returns to the instruction after the JSR, with the
+    /// stack back where it was, so recursive calls to the same subroutine don't stop it early
+    fn step_over(&mut self) -> String {
+        let is_call = self.cpu.next_instruction().opcode == Opcode::JSR;
+        if !is_call {
+            return self.run(|_| true);
+        }
+        let return_address = self.cpu.pc().wrapping_add(3);
+        let sp = self.cpu.sp();
+        self.run(|cpu| cpu.pc() == return_address && cpu.sp() == sp)
+    }
+
+    fn until(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [address] = arguments else {
+            return Err("Usage: until <address>".to_string());
+        };
+        let address = parse_hex(address)?;
+        Ok(self.run(|cpu| cpu.pc() == address))
+    }
+
+    fn set_breakpoint(&mut self, arguments: &[&str]) -> Result<String, String> {
+        match arguments {
+            [] if self.breakpoints.is_empty() => Ok("No breakpoints".to_string()),
+            [] => Ok(self.breakpoints.iter().map(|address| format!("${:04X}", address)).collect::<Vec<_>>().join("\n")),
+            [address] => {
+                let address = parse_hex(address)?;
+                self.breakpoints.insert(address);
+                Ok(format!("Breakpoint at ${:04X}", address))
+            },
+            _ => Err("Usage: break [address]".to_string())
+        }
+    }
+
+    fn delete_breakpoint(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [address] = arguments else {
+            return Err("Usage: delete <address>".to_string());
+        };
+        let address = parse_hex(address)?;
+        if self.breakpoints.remove(&address) {
+            Ok(format!("Deleted breakpoint at ${:04X}", address))
+        } else {
+            Err(format!("No breakpoint at ${:04X}", address))
+        }
+    }
+
+    fn set_watchpoint(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let watchpoints = &mut self.cpu.bus_mut().watchpoints;
+        let (address, kind) = match arguments {
+            [] if watchpoints.is_empty() => return Ok("No watchpoints".to_string()),
+            [] => {
+                let lines: Vec<String> = watchpoints.iter().map(|(address, kind)| format!("${:04X} {}", address, describe(*kind))).collect();
+                return Ok(lines.join("\n"));
+            },
+            [address] => (address, WatchKind::ReadWrite),
+            [address, "r"] => (address, WatchKind::Read),
+            [address, "w"] => (address, WatchKind::Write),
+            [address, "rw"] => (address, WatchKind::ReadWrite),
+            _ => return Err("Usage: watch [address] [r|w|rw]".to_string())
+        };
+        let address = parse_hex(address)?;
+        watchpoints.insert(address, kind);
+        Ok(format!("Watching {} of ${:04X}", describe(kind), address))
+    }
+
+    fn delete_watchpoint(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [address] = arguments else {
+            return Err("Usage: unwatch <address>".to_string());
+        };
+        let address = parse_hex(address)?;
+        match self.cpu.bus_mut().watchpoints.remove(&address) {
+            Some(_) => Ok(format!("Stopped watching ${:04X}", address)),
+            None => Err(format!("No watchpoint at ${:04X}", address))
+        }
+    }
+
+    /// The registers, and the flags spelt out with set flags in capitals
+    fn registers(&self) -> String {
+        let flags = self.cpu.flags().as_byte();
+        let flag_names: String = "NV-BDIZC".chars().enumerate()
+            .map(|(bit, name)| if flags & (0x80 >> bit) != 0 { name } else { name.to_ascii_lowercase() })
+            .collect();
+        format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} ({}) SP:{:02X} PC:{:04X} CYC:{}",
+            self.cpu.a(), self.cpu.x(), self.cpu.y(), flags, flag_names, self.cpu.sp(), self.cpu.pc(), self.cpu.cycles())
+    }
+
+    fn set_register(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [register, value] = arguments else {
+            return Err("Usage: set <register> <value>".to_string());
+        };
+        let value = parse_hex(value)?;
+        let byte = || u8::try_from(value).map_err(|_| format!("{} doesn't fit in {}", value, register));
+        match register.to_ascii_lowercase().as_str() {
+            "a" => self.cpu.set_a(byte()?),
+            "x" => self.cpu.set_x(byte()?),
+            "y" => self.cpu.set_y(byte()?),
+            "p" => self.cpu.flags_mut().set_from_byte(byte()?),
+            "sp" => self.cpu.set_sp(byte()?),
+            "pc" => self.cpu.set_pc(value),
+            _ => return Err(format!("Unknown register {}", register))
+        }
+        Ok(self.registers())
+    }
+
+    /// A hex dump, read with peeks so looking doesn't disturb hardware registers
+    fn memory(&self, arguments: &[&str]) -> Result<String, String> {
+        let (start, length) = match arguments {
+            [start] => (parse_hex(start)?, DEFAULT_MEMORY_LENGTH),
+            [start, length] => (parse_hex(start)?, parse_hex(length)?),
+            _ => return Err("Usage: mem <address> [length]".to_string())
+        };
+        let bytes: Vec<u8> = (0..length).map(|offset| self.cpu.bus().peek(start.wrapping_add(offset))).collect();
+        let rows: Vec<String> = bytes.chunks(MEMORY_ROW_LENGTH).enumerate().map(|(row, bytes)| {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("{:04X}  {}", start.wrapping_add((row * MEMORY_ROW_LENGTH) as u16), hex.join(" "))
+        }).collect();
+        Ok(rows.join("\n"))
+    }
+
+    /// Writes go to the bus underneath, so they don't trigger watchpoints, but writes to hardware registers
+    /// or the cartridge have the same effect as a store
+    fn poke(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [address, values @ ..] = arguments else {
+            return Err("Usage: poke <address> <bytes>".to_string());
+        };
+        if values.is_empty() {
+            return Err("Usage: poke <address> <bytes>".to_string());
+        }
+        let address = parse_hex(address)?;
+        for (offset, value) in values.iter().enumerate() {
+            let value = u8::try_from(parse_hex(value)?).map_err(|_| format!("{} isn't a byte", value))?;
+            self.cpu.bus_mut().bus.write(address.wrapping_add(offset as u16), value);
+        }
+        Ok(format!("Wrote {} bytes at ${:04X}", values.len(), address))
+    }
+
+    /// Resets the CPU, which is the only way to get a halted one going again
+    fn reset(&mut self) -> String {
+        self.cpu.reset();
+        self.current_line()
+    }
+
+    /// The trace line for the instruction at the PC, then a disassembly of the ones after it
+    fn list(&self, arguments: &[&str]) -> Result<String, String> {
+        let count = match arguments {
+            [] => DEFAULT_LIST_LENGTH,
+            [count] => count.parse::<usize>().ok().filter(|&count| count > 0)
+                .ok_or_else(|| format!("Can't list {} instructions", count))?,
+            _ => return Err("Usage: list [count]".to_string())
+        };
+        let mut lines = vec![self.current_line()];
+        let width = self.cpu.next_instruction().width;
+        let start = self.cpu.pc().wrapping_add(width as u16);
+        let bus = self.cpu.bus();
+        // Instructions are at most three bytes, and there's no more to show than the whole address space
+        let length = count.saturating_mul(3).min(0x10000);
+        let bytes: Vec<u8> = (0..length).map(|offset| bus.peek(start.wrapping_add(offset as u16))).collect();
+        lines.extend(disassembler::disassemble_for(self.cpu.variant(), &bytes, start).iter().take(count.saturating_sub(1)).map(ToString::to_string));
+        Ok(lines.join("\n"))
+    }
+}
+
+fn describe(kind: WatchKind) -> &'static str {
+    match kind {
+        WatchKind::Read => "reads",
+        WatchKind::Write => "writes",
+        WatchKind::ReadWrite => "reads and writes"
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} isn't a hex address or value", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, MEMORY_SIZE};
+
+    /// A program at $8000, reset vector included, with a subroutine that sets $0300 to 42
+    const PROGRAM: &str = "
+                .org $8000
+        start:  LDX #2
+                JSR store
+        after:  LDA $0300
+                NOP
+        done:   JMP done
+        store:  LDA #42
+                STA $0300
+                RTS
+                .org $FFFC
+                .word start
+    ";
+
+    fn debugger(memory: &mut [u8]) -> Debugger<&mut [u8]> {
+        assembler::assemble(PROGRAM).unwrap().load_into(memory);
+        Debugger::new(memory)
+    }
+
+    #[test]
+    fn test_stepping_shows_the_trace_line() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut debugger = debugger(&mut memory);
+        assert!(debugger.current_line().starts_with("8000  A2 02     LDX #$02"));
+        let output = debugger.execute("step");
+        assert!(output.starts_with("8002  20 0C 80  JSR $800C"), "{}", output);
+        assert!(output.contains("A:00 X:02 Y:00 P:24 SP:FD"));
+        debugger.execute("s 2");
+        assert_eq!(debugger.execute("regs"), "A:2A X:02 Y:00 P:24 (nv-bdIzc) SP:FB PC:800E CYC:17");
+        assert_eq!(debugger.execute("step 0"), "Can't step 0 instructions");
+        assert_eq!(debugger.cpu.pc(), 0x800E);
+    }
+
+    #[test]
+    fn test_step_over_runs_the_whole_subroutine() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut debugger = debugger(&mut memory);
+        debugger.execute("step");
+        let output = debugger.execute("next");
+        assert!(output.starts_with("8005  AD 00 03  LDA $0300 = 2A"), "{}", output);
+        // Anything other than a JSR is just a step
+        assert!(debugger.execute("n").starts_with("8008  EA"));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut debugger = debugger(&mut memory);
+        assert_eq!(debugger.execute("break"), "No breakpoints");
+        assert_eq!(debugger.execute("break $800E"), "Breakpoint at $800E");
+        debugger.execute("b 8008");
+        assert_eq!(debugger.execute("b"), "$8008\n$800E");
+
+        assert!(debugger.execute("continue").starts_with("Breakpoint at $800E\n800E  8D 00 03  STA $0300 = 00"));
+        assert!(debugger.execute("c").starts_with("Breakpoint at $8008"));
+        assert_eq!(debugger.execute("delete 8008"), "Deleted breakpoint at $8008");
+        assert_eq!(debugger.execute("delete 8008"), "No breakpoint at $8008");
+
+        debugger.execute("set pc 8000");
+        assert!(debugger.execute("until 8005").starts_with("Breakpoint at $800E"));
+        assert!(debugger.execute("u 8005").starts_with("8005  AD 00 03"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut debugger = debugger(&mut memory);
+        assert_eq!(debugger.execute("watch 300 w"), "Watching writes of $0300");
+        // Execution stops after the instruction that made the access
+        assert!(debugger.execute("c").starts_with("Watchpoint: wrote $2A to $0300\n8011  60"));
+        assert_eq!(debugger.execute("w 0300 r"), "Watching reads of $0300");
+        assert_eq!(debugger.execute("watch"), "$0300 reads");
+        assert!(debugger.execute("c").starts_with("Watchpoint: read $2A from $0300\n8008  EA"));
+
+        // Looking at memory doesn't count as a read
+        debugger.execute("mem 300 1");
+        assert_eq!(debugger.execute("unwatch 300"), "Stopped watching $0300");
+        assert_eq!(debugger.execute("unwatch 300"), "No watchpoint at $0300");
+        debugger.execute("break 8009");
+        assert!(debugger.execute("c").starts_with("Breakpoint at $8009"));
+    }
+
+    #[test]
+    fn test_editing_registers_and_memory() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut debugger = debugger(&mut memory);
+        assert_eq!(debugger.execute("set a $80"), "A:80 X:00 Y:00 P:24 (nv-bdIzc) SP:FD PC:8000 CYC:7");
+        assert!(debugger.execute("set p C3").contains("P:E3 (NV-bdiZC)"));
+        assert_eq!(debugger.execute("set x 100"), "256 doesn't fit in x");
+        assert_eq!(debugger.execute("set q 1"), "Unknown register q");
+
+        assert_eq!(debugger.execute("poke 0x10 1 2 FF"), "Wrote 3 bytes at $0010");
+        assert_eq!(debugger.execute("mem 8 12"), "0008  00 00 00 00 00 00 00 00 01 02 FF 00 00 00 00 00\n0018  00 00");
+        assert_eq!(debugger.execute("poke 10 1FF"), "1FF isn't a byte");
+        assert_eq!(debugger.execute("mem zz"), "zz isn't a hex address or value");
+    }
+
+    #[test]
+    fn test_listing_starts_with_the_trace_line() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut debugger = debugger(&mut memory);
+        let output = debugger.execute("list 4");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], debugger.current_line());
+        assert_eq!(&lines[1..], ["8002  20 0C 80  JSR SUB_800C", "8005  AD 00 03  LDA $0300", "8008  EA        NOP"]);
+        assert_eq!(debugger.execute("list 0"), "Can't list 0 instructions");
+        // Long listings stop once they've covered the address space
+        let lines = debugger.execute(&format!("list {}", usize::MAX)).lines().count();
+        assert!(lines > 1 && lines <= 0x10001, "{}", lines);
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut debugger = debugger(&mut memory);
+        let mut output = Vec::new();
+        // An empty line repeats the last command
+        debugger.run_repl("step\n\nfoo\nquit\nstep\n".as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("8000"));
+        assert!(lines[1].starts_with("> 8002"));
+        assert!(lines[2].starts_with("> 800C"));
+        assert_eq!(lines[3], "> Unknown command foo, try help");
+        // Nothing after quit runs
+        assert_eq!(lines[4], "> ");
+        assert!(debugger.execute("help").contains("step [count]"));
+    }
+
+    #[test]
+    fn test_running_into_a_jam_stops_the_program() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut debugger = debugger(&mut memory);
+        debugger.execute("poke 8005 02");
+        let output = debugger.execute("continue");
+        assert!(output.starts_with("Stopped: CPU jammed by opcode $02 at $8005\n8005  02       *JAM"), "{}", output);
+        assert!(debugger.execute("regs").contains("PC:8005"));
+        // Running a halted CPU doesn't go on forever
+        assert!(debugger.execute("continue").starts_with("Halted until reset\n8005"));
+        assert!(debugger.execute("reset").starts_with("8000"));
+        assert!(debugger.execute("step").starts_with("8002"));
+    }
+}
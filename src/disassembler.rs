@@ -0,0 +1,186 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::instruction::{AddressingMode, CpuVariant, Instruction, Opcode};
+
+/// A decoded instruction, or a byte of one cut off by the end of the input
+#[derive(PartialEq, Clone, Debug)]
+pub struct DisassembledLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// Set when a branch, jump or subroutine call in the disassembly targets this line
+    pub label: Option<String>,
+    /// None for data bytes
+    pub opcode: Option<Opcode>,
+    /// The instruction in assembler syntax, or a .byte directive for data
+    pub text: String
+}
+
+impl DisassembledLine {
+    pub fn is_data(&self) -> bool {
+        self.opcode.is_none()
+    }
+}
+
+impl Display for DisassembledLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(label) = &self.label {
+            writeln!(f, "{}:", label)?;
+        }
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "{:04X}  {:<8}  {}", self.address, bytes.join(" "), self.text)
+    }
+}
+
+/// Disassembles code loaded at `origin`, without running it. It's a linear sweep, so data mixed in with
+/// code can decode as instructions. Every byte decodes to something, so only an instruction cut off by the
+/// end of the input comes out as data.
+pub fn disassemble(bytes: &[u8], origin: u16) -> Vec<DisassembledLine> {
+    disassemble_for(CpuVariant::default(), bytes, origin)
+}
+
+/// Disassembles code for a particular chip, such as a 65C02
+pub fn disassemble_for(variant: CpuVariant, bytes: &[u8], origin: u16) -> Vec<DisassembledLine> {
+    let mut decoded = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = origin.wrapping_add(offset as u16);
+        let data = (bytes.get(offset + 1).copied().unwrap_or(0), bytes.get(offset + 2).copied().unwrap_or(0));
+        let instruction = Some(Instruction::from_bytes_for(variant, bytes[offset], data))
+            .filter(|instruction| offset + instruction.width <= bytes.len());
+        let width = instruction.as_ref().map_or(1, |instruction| instruction.width);
+        decoded.push((address, &bytes[offset..offset + width], instruction));
+        offset += width;
+    }
+
+    // Only targets that land on the start of a line get a label. Subroutines are named differently, so
+    // they stand out
+    let starts: Vec<u16> = decoded.iter().map(|(address, _, _)| *address).collect();
+    let mut labels = HashMap::new();
+    for (address, _, instruction) in &decoded {
+        let Some(instruction) = instruction else { continue };
+        if let Some(target) = target_of(instruction, *address).filter(|target| starts.contains(target)) {
+            if instruction.opcode == Opcode::JSR {
+                labels.insert(target, format!("SUB_{:04X}", target));
+            } else {
+                labels.entry(target).or_insert_with(|| format!("L_{:04X}", target));
+            }
+        }
+    }
+
+    decoded.into_iter().map(|(address, bytes, instruction)| {
+        let text = match &instruction {
+            Some(instruction) => format_instruction(instruction, address, &labels),
+            None => format!(".byte ${:02X}", bytes[0])
+        };
+        DisassembledLine {
+            address,
+            bytes: bytes.to_vec(),
+            label: labels.get(&address).cloned(),
+            opcode: instruction.map(|instruction| instruction.opcode),
+            text
+        }
+    }).collect()
+}
+
+/// Where a branch, jump or subroutine call goes. Indirect jumps are left out, as the target is in memory
+fn target_of(instruction: &Instruction, address: u16) -> Option<u16> {
+    match (instruction.opcode, instruction.addressing_mode) {
+        (_, AddressingMode::Relative) => {
+            let next = address.wrapping_add(instruction.width as u16);
+            Some(next.wrapping_add_signed(instruction.data.0 as i8 as i16))
+        },
+        (_, AddressingMode::ZeroPageRelative) => {
+            let next = address.wrapping_add(instruction.width as u16);
+            Some(next.wrapping_add_signed(instruction.data.1 as i8 as i16))
+        },
+        (Opcode::JMP | Opcode::JSR, AddressingMode::Absolute) => Some(u16::from_le_bytes([instruction.data.0, instruction.data.1])),
+        _ => None
+    }
+}
+
+fn format_instruction(instruction: &Instruction, address: u16, labels: &HashMap<u16, String>) -> String {
+    let (lo, hi) = instruction.data;
+    let absolute = u16::from_le_bytes([lo, hi]);
+    let operand = match instruction.addressing_mode {
+        AddressingMode::Implied => return format!("{:?}", instruction.opcode),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${:02X}", lo),
+        AddressingMode::ZeroPage => format!("${:02X}", lo),
+        AddressingMode::ZeroPageIndexedX => format!("${:02X},X", lo),
+        AddressingMode::ZeroPageIndexedY => format!("${:02X},Y", lo),
+        AddressingMode::Absolute | AddressingMode::Relative => {
+            match target_of(instruction, address) {
+                Some(target) => labels.get(&target).cloned().unwrap_or_else(|| format!("${:04X}", target)),
+                None => format!("${:04X}", absolute)
+            }
+        },
+        AddressingMode::AbsoluteIndexedX => format!("${:04X},X", absolute),
+        AddressingMode::AbsoluteIndexedY => format!("${:04X},Y", absolute),
+        AddressingMode::Indirect => format!("(${:04X})", absolute),
+        AddressingMode::IndexedIndirect => format!("(${:02X},X)", lo),
+        AddressingMode::IndirectIndexed => format!("(${:02X}),Y", lo),
+        AddressingMode::ZeroPageIndirect => format!("(${:02X})", lo),
+        AddressingMode::AbsoluteIndexedIndirect => format!("(${:04X},X)", absolute),
+        AddressingMode::ZeroPageRelative => {
+            let target = target_of(instruction, address).unwrap();
+            format!("${:02X},{}", lo, labels.get(&target).cloned().unwrap_or_else(|| format!("${:04X}", target)))
+        }
+    };
+    format!("{:?} {}", instruction.opcode, operand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[DisassembledLine]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let program = [
+            0x0A, 0xA9, 0x10, 0xA5, 0x20, 0xB5, 0x20, 0xB6, 0x20, 0xAD, 0x00, 0x20, 0xBD, 0x00, 0x20,
+            0xB9, 0x00, 0x20, 0x6C, 0xFC, 0xFF, 0xA1, 0x40, 0xB1, 0x40, 0xEA
+        ];
+        let lines = disassemble(&program, 0x8000);
+        assert_eq!(texts(&lines), [
+            "ASL A", "LDA #$10", "LDA $20", "LDA $20,X", "LDX $20,Y", "LDA $2000", "LDA $2000,X", "LDA $2000,Y",
+            "JMP ($FFFC)", "LDA ($40,X)", "LDA ($40),Y", "NOP"
+        ]);
+        assert_eq!(lines[1].address, 0x8001);
+        assert_eq!(lines[1].bytes, [0xA9, 0x10]);
+    }
+
+    #[test]
+    fn test_labels_for_branch_and_call_targets() {
+        // loop: DEX; BNE loop; JSR sub; JMP $9000; sub: RTS
+        let program = [0xCA, 0xD0, 0xFD, 0x20, 0x09, 0xC0, 0x4C, 0x00, 0x90, 0x60];
+        let lines = disassemble(&program, 0xC000);
+        assert_eq!(texts(&lines), ["DEX", "BNE L_C000", "JSR SUB_C009", "JMP $9000", "RTS"]);
+        assert_eq!(lines[0].label.as_deref(), Some("L_C000"));
+        assert_eq!(lines[4].label.as_deref(), Some("SUB_C009"));
+        assert_eq!(lines[1].label, None);
+        assert_eq!(lines[0].to_string(), "L_C000:\nC000  CA        DEX");
+        assert_eq!(lines[2].to_string(), "C003  20 09 C0  JSR SUB_C009");
+    }
+
+    #[test]
+    fn test_cut_off_instructions_are_data() {
+        // A JAM, a NOP, then an LDA cut off by the end of the input
+        let lines = disassemble(&[0x02, 0xEA, 0xAD, 0x34], 0x8000);
+        assert_eq!(texts(&lines), ["JAM", "NOP", ".byte $AD", ".byte $34"]);
+        assert!(lines[2].is_data());
+        assert!(!lines[1].is_data());
+        assert_eq!(lines[0].opcode, Some(Opcode::JAM));
+    }
+
+    #[test]
+    fn test_65c02_instructions() {
+        // loop: BBR3 $10,loop; STZ $2000; LDA ($20); JMP ($1234,X); BRA loop; and a JAM on the NMOS, a NOP here
+        let program = [0x3F, 0x10, 0xFD, 0x9C, 0x00, 0x20, 0xB2, 0x20, 0x7C, 0x34, 0x12, 0x80, 0xF3, 0x02, 0x00];
+        let lines = disassemble_for(CpuVariant::Wdc65C02, &program, 0x8000);
+        assert_eq!(texts(&lines), ["BBR3 $10,L_8000", "STZ $2000", "LDA ($20)", "JMP ($1234,X)", "BRA L_8000", "NOP #$00"]);
+        assert!(disassemble(&program, 0x8000)[0].text.starts_with("RLA"));
+    }
+}
@@ -1,8 +1,22 @@
-use std::fmt::Display;
+use crate::bus::Bus;
+
+/// The chips this core can behave as. The 2A03 and NMOS 6502 share an instruction set, and the 65C02 adds to it
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum CpuVariant {
+    /// The NES's CPU, a second source 6502 with the decimal mode circuitry cut out. The D flag can still be
+    /// set and cleared, but ADC and SBC always work in binary
+    #[default]
+    Ricoh2A03,
+    /// A stock NMOS 6502, where ADC and SBC do binary-coded decimal arithmetic when the D flag is set
+    Nmos6502,
+    /// The CMOS 65C02, with the WDC and Rockwell bit instructions. It fixes the NMOS bugs, and every opcode
+    /// that isn't an instruction is a NOP. Cycle-accurate mode still makes the NMOS bus accesses
+    Wdc65C02
+}
 
 /// Represents the various addressing modes used by the 6502. A more comprehensive explanation is
 /// available at [Emulator 101](http://www.emulator101.com/6502-addressing-modes.html)
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum AddressingMode {
     /// The target is the A register
     Accumulator,
@@ -29,11 +43,20 @@ pub enum AddressingMode {
     /// The target address is stored at the address location represented by the next two bytes (little-endian) plus the contents of the X register
     IndexedIndirect,
     /// The target address is the value at the address location represented by the next two bytes (little-endian), with the contents of the Y register added to it
-    IndirectIndexed
+    IndirectIndexed,
+    /// 65C02 only. The target address is stored on the zero page at the address given by the next byte
+    ZeroPageIndirect,
+    /// 65C02 only. The target address is stored at the address given by the next two bytes (little-endian) plus the contents of the X register
+    AbsoluteIndexedIndirect,
+    /// 65C02 only. The next byte is a zero page address, and the one after is a signed offset to branch by, as for Relative
+    ZeroPageRelative
 }
 
 
-#[derive(Debug, Clone, Copy)]
+/// The instruction mnemonics, in alphabetical order. Unofficial instructions follow the official
+/// set and use the names given by [masswerk](https://www.masswerk.at/6502/6502_instruction_set.html#illegals)
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub enum Opcode {
     /// [Add with carry](https://www.masswerk.at/6502/6502_instruction_set.html#ADC)
     ADC,
@@ -146,10 +169,143 @@ pub enum Opcode {
     /// [Transfer X to stack pointer](https://www.masswerk.at/6502/6502_instruction_set.html#TXS)
     TXS,
     /// [Transfer Y to accumulator](https://www.masswerk.at/6502/6502_instruction_set.html#TYA)
-    TYA    
+    TYA,
+
+    /// [AND immediate then shift one bit right](https://www.masswerk.at/6502/6502_instruction_set.html#ALR)
+    ALR,
+    /// [AND immediate then copy bit 7 into carry](https://www.masswerk.at/6502/6502_instruction_set.html#ANC)
+    ANC,
+    /// [Unstable AND X with accumulator and immediate](https://www.masswerk.at/6502/6502_instruction_set.html#ANE)
+    ANE,
+    /// [AND immediate then rotate one bit right](https://www.masswerk.at/6502/6502_instruction_set.html#ARR)
+    ARR,
+    /// [Decrement memory by 1 then compare with accumulator](https://www.masswerk.at/6502/6502_instruction_set.html#DCP)
+    DCP,
+    /// [Increment memory by 1 then subtract from accumulator with borrow](https://www.masswerk.at/6502/6502_instruction_set.html#ISC)
+    ISB,
+    /// [Lock up the CPU until reset](https://www.masswerk.at/6502/6502_instruction_set.html#JAM)
+    JAM,
+    /// [AND memory with stack pointer into accumulator, X and stack pointer](https://www.masswerk.at/6502/6502_instruction_set.html#LAS)
+    LAS,
+    /// [Load accumulator and X with memory](https://www.masswerk.at/6502/6502_instruction_set.html#LAX)
+    LAX,
+    /// [Unstable load accumulator and X with immediate](https://www.masswerk.at/6502/6502_instruction_set.html#LXA)
+    LXA,
+    /// [Rotate memory one bit left then AND with accumulator](https://www.masswerk.at/6502/6502_instruction_set.html#RLA)
+    RLA,
+    /// [Rotate memory one bit right then add to accumulator with carry](https://www.masswerk.at/6502/6502_instruction_set.html#RRA)
+    RRA,
+    /// [Store accumulator AND X in memory](https://www.masswerk.at/6502/6502_instruction_set.html#SAX)
+    SAX,
+    /// [Subtract immediate from accumulator AND X into X](https://www.masswerk.at/6502/6502_instruction_set.html#SBX)
+    SBX,
+    /// [Store accumulator AND X AND (high byte + 1) in memory](https://www.masswerk.at/6502/6502_instruction_set.html#SHA)
+    SHA,
+    /// [Store X AND (high byte + 1) in memory](https://www.masswerk.at/6502/6502_instruction_set.html#SHX)
+    SHX,
+    /// [Store Y AND (high byte + 1) in memory](https://www.masswerk.at/6502/6502_instruction_set.html#SHY)
+    SHY,
+    /// [Shift memory one bit left then OR with accumulator](https://www.masswerk.at/6502/6502_instruction_set.html#SLO)
+    SLO,
+    /// [Shift memory one bit right then XOR with accumulator](https://www.masswerk.at/6502/6502_instruction_set.html#SRE)
+    SRE,
+    /// [Transfer accumulator AND X to stack pointer, then store as SHA](https://www.masswerk.at/6502/6502_instruction_set.html#TAS)
+    TAS,
+
+    /// [Branch always](http://www.6502.org/tutorials/65c02opcodes.html#2)
+    BRA,
+    /// [Push X onto stack](http://www.6502.org/tutorials/65c02opcodes.html#3)
+    PHX,
+    /// [Push Y onto stack](http://www.6502.org/tutorials/65c02opcodes.html#3)
+    PHY,
+    /// [Pull X from stack](http://www.6502.org/tutorials/65c02opcodes.html#3)
+    PLX,
+    /// [Pull Y from stack](http://www.6502.org/tutorials/65c02opcodes.html#3)
+    PLY,
+    /// [Store zero in memory](http://www.6502.org/tutorials/65c02opcodes.html#4)
+    STZ,
+    /// [Test and reset memory bits with accumulator](http://www.6502.org/tutorials/65c02opcodes.html#5)
+    TRB,
+    /// [Test and set memory bits with accumulator](http://www.6502.org/tutorials/65c02opcodes.html#5)
+    TSB,
+    /// [Wait for interrupt](http://www.6502.org/tutorials/65c02opcodes.html#6)
+    WAI,
+    /// [Stop the clock until reset](http://www.6502.org/tutorials/65c02opcodes.html#6)
+    STP,
+    /// [Reset bit 0 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    RMB0,
+    /// [Reset bit 1 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    RMB1,
+    /// [Reset bit 2 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    RMB2,
+    /// [Reset bit 3 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    RMB3,
+    /// [Reset bit 4 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    RMB4,
+    /// [Reset bit 5 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    RMB5,
+    /// [Reset bit 6 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    RMB6,
+    /// [Reset bit 7 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    RMB7,
+    /// [Set bit 0 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    SMB0,
+    /// [Set bit 1 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    SMB1,
+    /// [Set bit 2 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    SMB2,
+    /// [Set bit 3 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    SMB3,
+    /// [Set bit 4 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    SMB4,
+    /// [Set bit 5 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    SMB5,
+    /// [Set bit 6 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    SMB6,
+    /// [Set bit 7 of zero page memory](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    SMB7,
+    /// [Branch if bit 0 of zero page memory is reset](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBR0,
+    /// [Branch if bit 1 of zero page memory is reset](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBR1,
+    /// [Branch if bit 2 of zero page memory is reset](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBR2,
+    /// [Branch if bit 3 of zero page memory is reset](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBR3,
+    /// [Branch if bit 4 of zero page memory is reset](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBR4,
+    /// [Branch if bit 5 of zero page memory is reset](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBR5,
+    /// [Branch if bit 6 of zero page memory is reset](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBR6,
+    /// [Branch if bit 7 of zero page memory is reset](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBR7,
+    /// [Branch if bit 0 of zero page memory is set](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBS0,
+    /// [Branch if bit 1 of zero page memory is set](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBS1,
+    /// [Branch if bit 2 of zero page memory is set](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBS2,
+    /// [Branch if bit 3 of zero page memory is set](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBS3,
+    /// [Branch if bit 4 of zero page memory is set](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBS4,
+    /// [Branch if bit 5 of zero page memory is set](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBS5,
+    /// [Branch if bit 6 of zero page memory is set](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBS6,
+    /// [Branch if bit 7 of zero page memory is set](http://www.6502.org/tutorials/65c02opcodes.html#7)
+    BBS7
 }
 
 
+/// The bit instructions, indexed by the bit they work on
+const RMB: [Opcode; 8] = [Opcode::RMB0, Opcode::RMB1, Opcode::RMB2, Opcode::RMB3, Opcode::RMB4, Opcode::RMB5, Opcode::RMB6, Opcode::RMB7];
+const SMB: [Opcode; 8] = [Opcode::SMB0, Opcode::SMB1, Opcode::SMB2, Opcode::SMB3, Opcode::SMB4, Opcode::SMB5, Opcode::SMB6, Opcode::SMB7];
+const BBR: [Opcode; 8] = [Opcode::BBR0, Opcode::BBR1, Opcode::BBR2, Opcode::BBR3, Opcode::BBR4, Opcode::BBR5, Opcode::BBR6, Opcode::BBR7];
+const BBS: [Opcode; 8] = [Opcode::BBS0, Opcode::BBS1, Opcode::BBS2, Opcode::BBS3, Opcode::BBS4, Opcode::BBS5, Opcode::BBS6, Opcode::BBS7];
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub struct Instruction {
     pub opcode: Opcode,
     pub addressing_mode: AddressingMode,
@@ -160,12 +316,101 @@ pub struct Instruction {
 }
 
 impl Instruction {
+    /// Whether the instruction is one of the undocumented opcodes. Some mnemonics are shared with
+    /// official instructions (NOP and SBC), so we need to check the opcode byte for those
+    pub fn is_unofficial(&self) -> bool {
+        match self.opcode {
+            Opcode::NOP => self.opcode_byte != 0xEA,
+            Opcode::SBC => self.opcode_byte == 0xEB,
+            Opcode::ALR | Opcode::ANC | Opcode::ANE | Opcode::ARR | Opcode::DCP | Opcode::ISB | Opcode::JAM
+            | Opcode::LAS | Opcode::LAX | Opcode::LXA | Opcode::RLA | Opcode::RRA | Opcode::SAX | Opcode::SBX | Opcode::SHA
+            | Opcode::SHX | Opcode::SHY | Opcode::SLO | Opcode::SRE | Opcode::TAS => true,
+            _ => false
+        }
+    }
+
+    /// Decodes the instruction at the given position on the bus for the given chip. Decoding only looks at
+    /// memory, so it doesn't trigger any read side effects
     #[inline]
-    pub fn decode(memory: &[u8], memory_position: u16) -> Instruction {
-        let index = memory_position as usize;
-        let opcode_byte = memory[index];
-        // We always pass the next two bytes as data as it simplifies construction logic
-        let data = (memory[index+1], memory[index+2]);
+    pub fn decode<B: Bus + ?Sized>(bus: &B, memory_position: u16, variant: CpuVariant) -> Instruction {
+        let opcode_byte = bus.peek(memory_position);
+        // We always pass the next two bytes as data as it simplifies construction logic. They wrap around
+        // to $0000 past the end of memory, like the PC does
+        let data = (bus.peek(memory_position.wrapping_add(1)), bus.peek(memory_position.wrapping_add(2)));
+        Self::from_bytes_for(variant, opcode_byte, data)
+    }
+
+    /// Decodes an instruction in the given chip's instruction set. Every byte is an instruction on all of them
+    pub fn from_bytes_for(variant: CpuVariant, opcode_byte: u8, data: (u8, u8)) -> Instruction {
+        match variant {
+            CpuVariant::Ricoh2A03 | CpuVariant::Nmos6502 => Self::from_bytes(opcode_byte, data),
+            CpuVariant::Wdc65C02 => Self::from_65c02_bytes(opcode_byte, data)
+        }
+    }
+
+    /// Decodes a 65C02 instruction, where the official NMOS ones only differ in timing: the fixed JMP indirect
+    /// takes a cycle more and the abs,X shifts a cycle less. Every opcode byte decodes to something, if only a
+    /// NOP. Ref: http://www.6502.org/tutorials/65c02opcodes.html
+    fn from_65c02_bytes(opcode_byte: u8, data: (u8, u8)) -> Instruction {
+        let instruction = |opcode, addressing_mode, width, cycles| Self { opcode, addressing_mode, width, cycles, data, opcode_byte };
+        let bit = (opcode_byte >> 4) as usize & 0x07;
+        match opcode_byte {
+            0x80 => instruction(Opcode::BRA, AddressingMode::Relative, 2, 2),
+            0xDA => instruction(Opcode::PHX, AddressingMode::Implied, 1, 3),
+            0x5A => instruction(Opcode::PHY, AddressingMode::Implied, 1, 3),
+            0xFA => instruction(Opcode::PLX, AddressingMode::Implied, 1, 4),
+            0x7A => instruction(Opcode::PLY, AddressingMode::Implied, 1, 4),
+            0x64 => instruction(Opcode::STZ, AddressingMode::ZeroPage, 2, 3),
+            0x74 => instruction(Opcode::STZ, AddressingMode::ZeroPageIndexedX, 2, 4),
+            0x9C => instruction(Opcode::STZ, AddressingMode::Absolute, 3, 4),
+            0x9E => instruction(Opcode::STZ, AddressingMode::AbsoluteIndexedX, 3, 5),
+            0x14 => instruction(Opcode::TRB, AddressingMode::ZeroPage, 2, 5),
+            0x1C => instruction(Opcode::TRB, AddressingMode::Absolute, 3, 6),
+            0x04 => instruction(Opcode::TSB, AddressingMode::ZeroPage, 2, 5),
+            0x0C => instruction(Opcode::TSB, AddressingMode::Absolute, 3, 6),
+            0x1A => instruction(Opcode::INC, AddressingMode::Accumulator, 1, 2),
+            0x3A => instruction(Opcode::DEC, AddressingMode::Accumulator, 1, 2),
+            0x89 => instruction(Opcode::BIT, AddressingMode::Immediate, 2, 2),
+            0x34 => instruction(Opcode::BIT, AddressingMode::ZeroPageIndexedX, 2, 4),
+            0x3C => instruction(Opcode::BIT, AddressingMode::AbsoluteIndexedX, 3, 4),
+            // Shifts and rotates only take the extra cycle for abs,X when indexing crosses a page
+            0x1E => instruction(Opcode::ASL, AddressingMode::AbsoluteIndexedX, 3, 6),
+            0x5E => instruction(Opcode::LSR, AddressingMode::AbsoluteIndexedX, 3, 6),
+            0x3E => instruction(Opcode::ROL, AddressingMode::AbsoluteIndexedX, 3, 6),
+            0x7E => instruction(Opcode::ROR, AddressingMode::AbsoluteIndexedX, 3, 6),
+            0x6C => instruction(Opcode::JMP, AddressingMode::Indirect, 3, 6),
+            0x7C => instruction(Opcode::JMP, AddressingMode::AbsoluteIndexedIndirect, 3, 6),
+            0xCB => instruction(Opcode::WAI, AddressingMode::Implied, 1, 3),
+            0xDB => instruction(Opcode::STP, AddressingMode::Implied, 1, 3),
+
+            // The (zp) column has the same instructions as (zp),Y
+            0x12 | 0x32 | 0x52 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
+                let opcode = [Opcode::ORA, Opcode::AND, Opcode::EOR, Opcode::ADC, Opcode::STA, Opcode::LDA, Opcode::CMP, Opcode::SBC][opcode_byte as usize >> 5];
+                instruction(opcode, AddressingMode::ZeroPageIndirect, 2, 5)
+            },
+
+            // The bit instructions, with the bit number in the high nibble
+            0x07 | 0x17 | 0x27 | 0x37 | 0x47 | 0x57 | 0x67 | 0x77 => instruction(RMB[bit], AddressingMode::ZeroPage, 2, 5),
+            0x87 | 0x97 | 0xA7 | 0xB7 | 0xC7 | 0xD7 | 0xE7 | 0xF7 => instruction(SMB[bit], AddressingMode::ZeroPage, 2, 5),
+            0x0F | 0x1F | 0x2F | 0x3F | 0x4F | 0x5F | 0x6F | 0x7F => instruction(BBR[bit], AddressingMode::ZeroPageRelative, 3, 5),
+            0x8F | 0x9F | 0xAF | 0xBF | 0xCF | 0xDF | 0xEF | 0xFF => instruction(BBS[bit], AddressingMode::ZeroPageRelative, 3, 5),
+
+            // Everything else that isn't an official NMOS instruction is a NOP, of a few different sizes
+            0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xC2 | 0xE2 => instruction(Opcode::NOP, AddressingMode::Immediate, 2, 2),
+            0x44 => instruction(Opcode::NOP, AddressingMode::ZeroPage, 2, 3),
+            0x54 | 0xD4 | 0xF4 => instruction(Opcode::NOP, AddressingMode::ZeroPageIndexedX, 2, 4),
+            0x5C => instruction(Opcode::NOP, AddressingMode::Absolute, 3, 8),
+            0xDC | 0xFC => instruction(Opcode::NOP, AddressingMode::Absolute, 3, 4),
+            _ => match Self::from_bytes(opcode_byte, data) {
+                official if !official.is_unofficial() => official,
+                _ => instruction(Opcode::NOP, AddressingMode::Implied, 1, 1)
+            }
+        }
+    }
+
+    /// Decodes an opcode byte and the two bytes after it, whether or not the instruction uses them. Every
+    /// byte decodes to something on the NMOS 6502, if only a JAM
+    pub fn from_bytes(opcode_byte: u8, data: (u8, u8)) -> Instruction {
         // Cases are in alphabetical order of opcode for readability
         match opcode_byte {
 
@@ -1489,7 +1734,804 @@ impl Instruction {
 				opcode_byte
             },
 
-            _ => panic!("Unsupported instruction decoded {}!", opcode_byte)
+            // Unofficial opcodes. These are undocumented, but stable enough that a number of commercial
+            // games rely on them. Ref: https://www.nesdev.org/wiki/CPU_unofficial_opcodes
+
+            // ALR
+            0x4B => Self {
+                opcode: Opcode::ALR,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+
+            // ANC
+            0x0B => Self {
+                opcode: Opcode::ANC,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0x2B => Self {
+                opcode: Opcode::ANC,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+
+            // ANE
+            0x8B => Self {
+                opcode: Opcode::ANE,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+
+            // ARR
+            0x6B => Self {
+                opcode: Opcode::ARR,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+
+            // DCP
+            0xC7 => Self {
+                opcode: Opcode::DCP,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+            0xD7 => Self {
+                opcode: Opcode::DCP,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0xCF => Self {
+                opcode: Opcode::DCP,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0xDF => Self {
+                opcode: Opcode::DCP,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0xDB => Self {
+                opcode: Opcode::DCP,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0xC3 => Self {
+                opcode: Opcode::DCP,
+                addressing_mode: AddressingMode::IndexedIndirect,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+            0xD3 => Self {
+                opcode: Opcode::DCP,
+                addressing_mode: AddressingMode::IndirectIndexed,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+
+            // ISB
+            0xE7 => Self {
+                opcode: Opcode::ISB,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+            0xF7 => Self {
+                opcode: Opcode::ISB,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0xEF => Self {
+                opcode: Opcode::ISB,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0xFF => Self {
+                opcode: Opcode::ISB,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0xFB => Self {
+                opcode: Opcode::ISB,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0xE3 => Self {
+                opcode: Opcode::ISB,
+                addressing_mode: AddressingMode::IndexedIndirect,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+            0xF3 => Self {
+                opcode: Opcode::ISB,
+                addressing_mode: AddressingMode::IndirectIndexed,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+
+            // JAM. The CPU fetches the opcode and the byte after it, then stops
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => Self {
+                opcode: Opcode::JAM,
+                addressing_mode: AddressingMode::Implied,
+                width: 1,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+
+            // LAS
+            0xBB => Self {
+                opcode: Opcode::LAS,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+
+            // LAX
+            0xA7 => Self {
+                opcode: Opcode::LAX,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 3,
+                data,
+				opcode_byte
+            },
+            0xB7 => Self {
+                opcode: Opcode::LAX,
+                addressing_mode: AddressingMode::ZeroPageIndexedY,
+                width: 2,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0xAF => Self {
+                opcode: Opcode::LAX,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0xBF => Self {
+                opcode: Opcode::LAX,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0xA3 => Self {
+                opcode: Opcode::LAX,
+                addressing_mode: AddressingMode::IndexedIndirect,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0xB3 => Self {
+                opcode: Opcode::LAX,
+                addressing_mode: AddressingMode::IndirectIndexed,
+                width: 2,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+
+            // LXA
+            0xAB => Self {
+                opcode: Opcode::LXA,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+
+            // NOP
+            0x1A => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Implied,
+                width: 1,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0x3A => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Implied,
+                width: 1,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0x5A => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Implied,
+                width: 1,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0x7A => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Implied,
+                width: 1,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0xDA => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Implied,
+                width: 1,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0xFA => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Implied,
+                width: 1,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0x80 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0x82 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0x89 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0xC2 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0xE2 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+            0x04 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 3,
+                data,
+				opcode_byte
+            },
+            0x44 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 3,
+                data,
+				opcode_byte
+            },
+            0x64 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 3,
+                data,
+				opcode_byte
+            },
+            0x14 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x34 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x54 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x74 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0xD4 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0xF4 => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x0C => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x1C => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x3C => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x5C => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x7C => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0xDC => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0xFC => Self {
+                opcode: Opcode::NOP,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+
+            // RLA
+            0x27 => Self {
+                opcode: Opcode::RLA,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+            0x37 => Self {
+                opcode: Opcode::RLA,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0x2F => Self {
+                opcode: Opcode::RLA,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0x3F => Self {
+                opcode: Opcode::RLA,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0x3B => Self {
+                opcode: Opcode::RLA,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0x23 => Self {
+                opcode: Opcode::RLA,
+                addressing_mode: AddressingMode::IndexedIndirect,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+            0x33 => Self {
+                opcode: Opcode::RLA,
+                addressing_mode: AddressingMode::IndirectIndexed,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+
+            // RRA
+            0x67 => Self {
+                opcode: Opcode::RRA,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+            0x77 => Self {
+                opcode: Opcode::RRA,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0x6F => Self {
+                opcode: Opcode::RRA,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0x7F => Self {
+                opcode: Opcode::RRA,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0x7B => Self {
+                opcode: Opcode::RRA,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0x63 => Self {
+                opcode: Opcode::RRA,
+                addressing_mode: AddressingMode::IndexedIndirect,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+            0x73 => Self {
+                opcode: Opcode::RRA,
+                addressing_mode: AddressingMode::IndirectIndexed,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+
+            // SAX
+            0x87 => Self {
+                opcode: Opcode::SAX,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 3,
+                data,
+				opcode_byte
+            },
+            0x97 => Self {
+                opcode: Opcode::SAX,
+                addressing_mode: AddressingMode::ZeroPageIndexedY,
+                width: 2,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x8F => Self {
+                opcode: Opcode::SAX,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 4,
+                data,
+				opcode_byte
+            },
+            0x83 => Self {
+                opcode: Opcode::SAX,
+                addressing_mode: AddressingMode::IndexedIndirect,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+
+            // SBC
+            0xEB => Self {
+                opcode: Opcode::SBC,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+
+            // SBX
+            0xCB => Self {
+                opcode: Opcode::SBX,
+                addressing_mode: AddressingMode::Immediate,
+                width: 2,
+                cycles: 2,
+                data,
+				opcode_byte
+            },
+
+            // SHA
+            0x9F => Self {
+                opcode: Opcode::SHA,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+            0x93 => Self {
+                opcode: Opcode::SHA,
+                addressing_mode: AddressingMode::IndirectIndexed,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+
+            // SHX
+            0x9E => Self {
+                opcode: Opcode::SHX,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+
+            // SHY
+            0x9C => Self {
+                opcode: Opcode::SHY,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+
+            // SLO
+            0x07 => Self {
+                opcode: Opcode::SLO,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+            0x17 => Self {
+                opcode: Opcode::SLO,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0x0F => Self {
+                opcode: Opcode::SLO,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0x1F => Self {
+                opcode: Opcode::SLO,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0x1B => Self {
+                opcode: Opcode::SLO,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0x03 => Self {
+                opcode: Opcode::SLO,
+                addressing_mode: AddressingMode::IndexedIndirect,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+            0x13 => Self {
+                opcode: Opcode::SLO,
+                addressing_mode: AddressingMode::IndirectIndexed,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+
+            // SRE
+            0x47 => Self {
+                opcode: Opcode::SRE,
+                addressing_mode: AddressingMode::ZeroPage,
+                width: 2,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
+            0x57 => Self {
+                opcode: Opcode::SRE,
+                addressing_mode: AddressingMode::ZeroPageIndexedX,
+                width: 2,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0x4F => Self {
+                opcode: Opcode::SRE,
+                addressing_mode: AddressingMode::Absolute,
+                width: 3,
+                cycles: 6,
+                data,
+				opcode_byte
+            },
+            0x5F => Self {
+                opcode: Opcode::SRE,
+                addressing_mode: AddressingMode::AbsoluteIndexedX,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0x5B => Self {
+                opcode: Opcode::SRE,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 7,
+                data,
+				opcode_byte
+            },
+            0x43 => Self {
+                opcode: Opcode::SRE,
+                addressing_mode: AddressingMode::IndexedIndirect,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+            0x53 => Self {
+                opcode: Opcode::SRE,
+                addressing_mode: AddressingMode::IndirectIndexed,
+                width: 2,
+                cycles: 8,
+                data,
+				opcode_byte
+            },
+
+            // TAS
+            0x9B => Self {
+                opcode: Opcode::TAS,
+                addressing_mode: AddressingMode::AbsoluteIndexedY,
+                width: 3,
+                cycles: 5,
+                data,
+				opcode_byte
+            },
         }
     }
 }
\ No newline at end of file
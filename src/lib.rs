@@ -0,0 +1,72 @@
+//! A NES emulator core. The 6502 in `CPU6502` runs against anything that implements `Bus`: a flat 64KiB
+//! slice for test binaries, or `NesBus` for the console's memory map with the PPU, APU, controllers and
+//! cartridge mapper behind it.
+//!
+//! ```
+//! use rust_nes::{Opcode, CPU6502, MEMORY_SIZE};
+//!
+//! let mut memory = vec![0; MEMORY_SIZE];
+//! let mut cpu = CPU6502::new(memory.as_mut_slice());
+//! // LDA #$42 at $8000, and the reset vector pointing to it
+//! cpu.load_memory(0x8000, &[0xA9, 0x42]);
+//! cpu.load_memory(0xFFFC, &[0x00, 0x80]);
+//! cpu.reset();
+//!
+//! assert_eq!(cpu.next_instruction().opcode, Opcode::LDA);
+//! let step = cpu.step().unwrap();
+//! assert_eq!((cpu.a(), cpu.pc(), step.cycles), (0x42, 0x8002, 2));
+//! assert!(!cpu.flags().zero);
+//! ```
+//!
+//! Running a ROM on the console looks like this:
+//!
+//! ```no_run
+//! use rust_nes::{mapper, Cartridge, NesBus, CPU6502};
+//!
+//! let cartridge = Cartridge::load("game.nes").unwrap();
+//! let mut cpu = CPU6502::new(NesBus::new(mapper::from_cartridge(&cartridge).unwrap()));
+//! cpu.reset();
+//! while cpu.bus().ppu().frame() < 60 {
+//!     cpu.step().unwrap();
+//! }
+//! ```
+
+mod apu;
+mod assembler;
+mod battery;
+mod bus;
+mod cartridge;
+mod controller;
+mod cpu;
+mod debugger;
+mod disassembler;
+mod instruction;
+pub mod mapper;
+mod nes_bus;
+mod ppu;
+mod rewind;
+mod savestate;
+mod utils;
+mod wav;
+
+// The CPU, the console's chips and the cartridge they run
+pub use apu::{Apu, DEFAULT_SAMPLE_RATE};
+pub use bus::Bus;
+pub use cartridge::{Cartridge, CartridgeError, Mirroring, RomFormat, TvSystem};
+pub use controller::{Button, Controller};
+pub use cpu::{CPUFlags, CpuError, StepInfo, CPU6502, MEMORY_SIZE};
+pub use instruction::{AddressingMode, CpuVariant, Instruction, Opcode};
+pub use nes_bus::NesBus;
+pub use ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// `CPU6502::save_state` and `load_state` work on any bus that implements `Snapshot`, which is also a
+// supertrait of `Mapper`, so a bus or board from outside the crate saves its state through these
+pub use savestate::{Snapshot, StateError, StateReader, StateWriter};
+
+// Tools for 6502 code and for front ends
+pub use assembler::{assemble, assemble_for, Assembly, AssemblyError, AssemblyErrorKind, Segment};
+pub use battery::{BatterySave, FRAMES_PER_SECOND};
+pub use debugger::Debugger;
+pub use disassembler::{disassemble, disassemble_for, DisassembledLine};
+pub use rewind::{RewindBuffer, DEFAULT_KEYFRAME_INTERVAL};
+pub use wav::{encode_wav, write_wav};
@@ -0,0 +1,229 @@
+mod axrom;
+mod cnrom;
+mod gxrom;
+mod mmc1;
+mod mmc3;
+mod nrom;
+mod uxrom;
+
+use crate::{cartridge::{Cartridge, CartridgeError, Mirroring}, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+pub(crate) use axrom::Axrom;
+pub(crate) use cnrom::Cnrom;
+pub(crate) use gxrom::Gxrom;
+pub(crate) use mmc1::Mmc1;
+pub(crate) use mmc3::Mmc3;
+pub(crate) use nrom::Nrom;
+pub(crate) use uxrom::Uxrom;
+
+/// Work RAM on the cartridge, for the boards that have it
+const PRG_RAM_START: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM_START: u16 = 0x8000;
+/// Boards without CHR-ROM have at least 8KiB of CHR-RAM to fill the pattern tables
+const MINIMUM_CHR_RAM_SIZE: usize = 0x2000;
+
+/// The circuitry on a cartridge board. The CPU sees PRG memory through it in $4020-$FFFF, the PPU sees
+/// CHR memory through it in the pattern tables at $0000-$1FFF, and it decides how the nametables are
+/// mirrored. Some boards can also interrupt the CPU. Its banking registers and RAM are saved in save states.
+/// Ref: https://www.nesdev.org/wiki/Mapper
+pub trait Mapper: Snapshot {
+    /// Reads from cartridge space. Addresses nothing on the board responds to return None, leaving the
+    /// CPU's open bus
+    fn read_prg(&mut self, address: u16) -> Option<u8> {
+        self.peek_prg(address)
+    }
+
+    /// Reads from cartridge space without side effects
+    fn peek_prg(&self, address: u16) -> Option<u8>;
+
+    /// Writes to cartridge space. Writes to ROM are how most boards are configured
+    fn write_prg(&mut self, address: u16, value: u8);
+
+    /// Reads from the pattern tables on the PPU bus
+    fn read_chr(&mut self, address: u16) -> u8 {
+        self.peek_chr(address)
+    }
+
+    fn peek_chr(&self, address: u16) -> u8;
+
+    /// Writes to the pattern tables, which only has an effect on boards with CHR-RAM
+    fn write_chr(&mut self, address: u16, value: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// The work RAM at $6000-$7FFF, which is what a battery keeps between sessions. Empty if the board has none
+    fn prg_ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    /// Whether the board is pulling the CPU's IRQ line
+    fn irq_asserted(&self) -> bool {
+        false
+    }
+
+    /// Called once every CPU cycle, for boards that watch the CPU clock
+    fn tick(&mut self) {}
+
+    /// Called with every address the PPU puts on its bus, for boards that watch it
+    fn ppu_bus_access(&mut self, _address: u16) {}
+}
+
+/// Builds the mapper for the board the cartridge's header describes
+pub fn from_cartridge(cartridge: &Cartridge) -> Result<Box<dyn Mapper>, CartridgeError> {
+    match cartridge.mapper {
+        0 => Ok(Box::new(Nrom::new(cartridge))),
+        1 => Ok(Box::new(Mmc1::new(cartridge))),
+        2 => Ok(Box::new(Uxrom::new(cartridge))),
+        3 => Ok(Box::new(Cnrom::new(cartridge))),
+        4 => Ok(Box::new(Mmc3::new(cartridge))),
+        7 => Ok(Box::new(Axrom::new(cartridge))),
+        66 => Ok(Box::new(Gxrom::new(cartridge))),
+        mapper => Err(CartridgeError::UnsupportedMapper(mapper))
+    }
+}
+
+/// The board's pattern table memory: CHR-ROM, or CHR-RAM on boards without any ROM
+struct ChrMemory {
+    data: Vec<u8>,
+    is_ram: bool
+}
+
+impl ChrMemory {
+    fn new(cartridge: &Cartridge) -> Self {
+        if cartridge.chr_rom.is_empty() {
+            Self { data: vec![0; cartridge.chr_ram_size.max(MINIMUM_CHR_RAM_SIZE)], is_ram: true }
+        } else {
+            Self { data: cartridge.chr_rom.clone(), is_ram: false }
+        }
+    }
+
+    /// Reads at an offset into the whole of CHR memory. Offsets past the end wrap around, as boards leave
+    /// the upper address lines unconnected
+    fn read(&self, offset: usize) -> u8 {
+        self.data[offset % self.data.len()]
+    }
+
+    fn write(&mut self, offset: usize, value: u8) {
+        if self.is_ram {
+            let index = offset % self.data.len();
+            self.data[index] = value;
+        }
+    }
+}
+
+/// CHR-ROM can't change, so only CHR-RAM is saved
+impl Snapshot for ChrMemory {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(if self.is_ram { &self.data } else { &[] });
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if self.is_ram {
+            state.read_bytes_into(&mut self.data)
+        } else {
+            state.read_bytes_into(&mut [])
+        }
+    }
+}
+
+/// The work RAM at $6000-$7FFF, battery-backed or not. Boards with more than 8KiB bank it through the window
+struct WorkRam {
+    data: Vec<u8>
+}
+
+impl WorkRam {
+    /// Empty if the cartridge has none, in which case nothing responds at $6000-$7FFF
+    fn new(cartridge: &Cartridge) -> Self {
+        Self::with_minimum_size(cartridge, 0)
+    }
+
+    /// For boards that always have work RAM, whatever the header says
+    fn with_minimum_size(cartridge: &Cartridge, minimum_size: usize) -> Self {
+        Self { data: vec![0; (cartridge.prg_ram_size + cartridge.prg_nvram_size).max(minimum_size)] }
+    }
+
+    fn read(&self, address: u16) -> Option<u8> {
+        self.read_bank(0, address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.write_bank(0, address, value);
+    }
+
+    /// Reads through the window with the given 8KiB bank mapped. Offsets past the end wrap around, as with CHR
+    fn read_bank(&self, bank: usize, address: u16) -> Option<u8> {
+        self.index(bank, address).map(|index| self.data[index])
+    }
+
+    fn write_bank(&mut self, bank: usize, address: u16, value: u8) {
+        if let Some(index) = self.index(bank, address) {
+            self.data[index] = value;
+        }
+    }
+
+    fn index(&self, bank: usize, address: u16) -> Option<usize> {
+        let window_size = (PRG_RAM_END - PRG_RAM_START) as usize + 1;
+        let offset = bank * window_size + (address - PRG_RAM_START) as usize;
+        (!self.data.is_empty()).then(|| offset % self.data.len())
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Snapshot for WorkRam {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.data)
+    }
+}
+
+/// Whether writes to ROM on a discrete-logic board have bus conflicts. The ROM drives the data bus while the
+/// CPU writes to it, so the board latches the AND of the two. NES 2.0 submapper 1 means there are no
+/// conflicts and 2 means there are, otherwise it's down to what the board usually does.
+/// Ref: https://www.nesdev.org/wiki/Bus_conflict
+fn has_bus_conflicts(cartridge: &Cartridge, board_default: bool) -> bool {
+    match cartridge.submapper {
+        1 => false,
+        2 => true,
+        _ => board_default
+    }
+}
+
+/// Builds a cartridge for mapper tests, with the given ROM, 8KiB of work RAM, and CHR-RAM if there's no CHR-ROM
+#[cfg(test)]
+pub fn test_cartridge(mapper: u16, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Cartridge {
+    use crate::cartridge::{RomFormat, TvSystem};
+    Cartridge {
+        format: RomFormat::INes,
+        chr_ram_size: if chr_rom.is_empty() { MINIMUM_CHR_RAM_SIZE } else { 0 },
+        prg_rom,
+        chr_rom,
+        prg_ram_size: 0x2000,
+        prg_nvram_size: 0,
+        trainer: None,
+        mirroring: Mirroring::Horizontal,
+        has_battery: false,
+        mapper,
+        submapper: 0,
+        tv_system: TvSystem::Ntsc
+    }
+}
+
+/// ROM made of banks where every byte is the bank's number, so tests can tell which bank is mapped
+#[cfg(test)]
+pub fn numbered_banks(count: usize, bank_size: usize) -> Vec<u8> {
+    (0..count).flat_map(|bank| vec![bank as u8; bank_size]).collect()
+}
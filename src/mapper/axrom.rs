@@ -0,0 +1,116 @@
+use crate::{cartridge::{Cartridge, Mirroring}, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+use super::{has_bus_conflicts, ChrMemory, Mapper, WorkRam, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const PRG_BANK: u8 = 0b00000111;
+const UPPER_NAMETABLE: u8 = 0b00010000;
+
+/// AxROM, mapper 7. Writes to ROM select a 32KiB PRG bank and which nametable fills the screen. CHR is
+/// 8KiB of RAM. Ref: https://www.nesdev.org/wiki/AxROM
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    prg_ram: WorkRam,
+    chr: ChrMemory,
+    bus_conflicts: bool,
+    bank_select: u8
+}
+
+impl Axrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: WorkRam::new(cartridge),
+            chr: ChrMemory::new(cartridge),
+            // ANROM and AMROM have conflicts, but AOROM only sometimes does, so games avoid relying on them
+            bus_conflicts: has_bus_conflicts(cartridge, false),
+            bank_select: 0
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn peek_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.read(address),
+            PRG_ROM_START..=0xFFFF => {
+                let offset = (self.bank_select & PRG_BANK) as usize * PRG_BANK_SIZE + (address - PRG_ROM_START) as usize;
+                Some(self.prg_rom[offset % self.prg_rom.len()])
+            },
+            _ => None
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.write(address, value),
+            PRG_ROM_START..=0xFFFF => {
+                let rom_value = self.peek_prg(address).unwrap_or(0xFF);
+                self.bank_select = if self.bus_conflicts { value & rom_value } else { value };
+            },
+            _ => ()
+        }
+    }
+
+    fn peek_chr(&self, address: u16) -> u8 {
+        self.chr.read(address as usize)
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.chr.write(address as usize, value);
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.as_slice()
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.prg_ram.as_mut_slice()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.bank_select & UPPER_NAMETABLE != 0 { Mirroring::SingleScreenUpper } else { Mirroring::SingleScreenLower }
+    }
+}
+
+impl Snapshot for Axrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        self.chr.save_state(state);
+        state.write_u8(self.bank_select);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(state)?;
+        self.chr.load_state(state)?;
+        self.bank_select = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge};
+
+    #[test]
+    fn test_prg_banks_and_single_screen_mirroring() {
+        let mut axrom = Axrom::new(&test_cartridge(7, numbered_banks(8, PRG_BANK_SIZE), vec![]));
+        assert_eq!(axrom.read_prg(0xFFFC), Some(0));
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+
+        axrom.write_prg(0x8000, UPPER_NAMETABLE | 5);
+        assert_eq!(axrom.read_prg(0x8000), Some(5));
+        assert_eq!(axrom.read_prg(0xFFFF), Some(5));
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_bus_conflicts_on_submapper_2() {
+        let mut cartridge = test_cartridge(7, vec![0x0F; 2 * PRG_BANK_SIZE], vec![]);
+        cartridge.submapper = 2;
+        let mut axrom = Axrom::new(&cartridge);
+        axrom.write_prg(0x8000, UPPER_NAMETABLE | 1);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::{cartridge::{Cartridge, Mirroring}, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+use super::{has_bus_conflicts, ChrMemory, Mapper, WorkRam, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// CNROM, mapper 3. PRG-ROM is laid out as on NROM, and any write to ROM selects the 8KiB CHR bank.
+/// Ref: https://www.nesdev.org/wiki/CNROM
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    prg_ram: WorkRam,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    chr_bank: u8
+}
+
+impl Cnrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: WorkRam::new(cartridge),
+            chr: ChrMemory::new(cartridge),
+            mirroring: cartridge.mirroring,
+            bus_conflicts: has_bus_conflicts(cartridge, true),
+            chr_bank: 0
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn peek_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.read(address),
+            PRG_ROM_START..=0xFFFF => Some(self.prg_rom[(address - PRG_ROM_START) as usize % self.prg_rom.len()]),
+            _ => None
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.write(address, value),
+            PRG_ROM_START..=0xFFFF => {
+                let rom_value = self.peek_prg(address).unwrap_or(0xFF);
+                self.chr_bank = if self.bus_conflicts { value & rom_value } else { value };
+            },
+            _ => ()
+        }
+    }
+
+    fn peek_chr(&self, address: u16) -> u8 {
+        self.chr.read(self.chr_bank as usize * CHR_BANK_SIZE + address as usize)
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.chr.write(self.chr_bank as usize * CHR_BANK_SIZE + address as usize, value);
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.as_slice()
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.prg_ram.as_mut_slice()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+impl Snapshot for Cnrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        self.chr.save_state(state);
+        state.write_u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(state)?;
+        self.chr.load_state(state)?;
+        self.chr_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge};
+
+    #[test]
+    fn test_chr_bank_switching_with_bus_conflicts() {
+        let mut prg_rom = vec![0xFF; 0x8000];
+        prg_rom[0] = 0b01;
+        let mut cnrom = Cnrom::new(&test_cartridge(3, prg_rom, numbered_banks(4, CHR_BANK_SIZE)));
+        cnrom.write_prg(0x8001, 2);
+        assert_eq!(cnrom.read_chr(0x0000), 2);
+        assert_eq!(cnrom.read_chr(0x1FFF), 2);
+        // The ROM at $8000 pulls bit 1 low
+        cnrom.write_prg(0x8000, 3);
+        assert_eq!(cnrom.read_chr(0x0000), 1);
+    }
+}
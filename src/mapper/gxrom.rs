@@ -0,0 +1,119 @@
+use crate::{cartridge::{Cartridge, Mirroring}, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+use super::{has_bus_conflicts, ChrMemory, Mapper, WorkRam, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+const PRG_BANK: u8 = 0b00110000;
+const CHR_BANK: u8 = 0b00000011;
+
+/// GxROM, mapper 66. Writes to ROM select a 32KiB PRG bank and an 8KiB CHR bank.
+/// Ref: https://www.nesdev.org/wiki/GxROM
+pub struct Gxrom {
+    prg_rom: Vec<u8>,
+    prg_ram: WorkRam,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    bank_select: u8
+}
+
+impl Gxrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: WorkRam::new(cartridge),
+            chr: ChrMemory::new(cartridge),
+            mirroring: cartridge.mirroring,
+            bus_conflicts: has_bus_conflicts(cartridge, true),
+            bank_select: 0
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        (self.bank_select & CHR_BANK) as usize * CHR_BANK_SIZE + address as usize
+    }
+}
+
+impl Mapper for Gxrom {
+    fn peek_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.read(address),
+            PRG_ROM_START..=0xFFFF => {
+                let bank = ((self.bank_select & PRG_BANK) >> 4) as usize;
+                let offset = bank * PRG_BANK_SIZE + (address - PRG_ROM_START) as usize;
+                Some(self.prg_rom[offset % self.prg_rom.len()])
+            },
+            _ => None
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.write(address, value),
+            PRG_ROM_START..=0xFFFF => {
+                let rom_value = self.peek_prg(address).unwrap_or(0xFF);
+                self.bank_select = if self.bus_conflicts { value & rom_value } else { value };
+            },
+            _ => ()
+        }
+    }
+
+    fn peek_chr(&self, address: u16) -> u8 {
+        self.chr.read(self.chr_offset(address))
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.chr.write(self.chr_offset(address), value);
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.as_slice()
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.prg_ram.as_mut_slice()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+impl Snapshot for Gxrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        self.chr.save_state(state);
+        state.write_u8(self.bank_select);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(state)?;
+        self.chr.load_state(state)?;
+        self.bank_select = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge};
+
+    #[test]
+    fn test_prg_and_chr_banks_with_bus_conflicts() {
+        let mut prg_rom = numbered_banks(4, PRG_BANK_SIZE);
+        prg_rom[0] = 0xFF;
+        prg_rom[1] = 0b00010011;
+        let mut gxrom = Gxrom::new(&test_cartridge(66, prg_rom, numbered_banks(4, CHR_BANK_SIZE)));
+        gxrom.write_prg(0x8000, 0b00100001);
+        assert_eq!(gxrom.read_prg(0x8000), Some(2));
+        assert_eq!(gxrom.read_chr(0x0000), 1);
+
+        // Back in bank 0, the ROM at $8001 masks both banks
+        gxrom.write_prg(0x8000, 0b00000000);
+        gxrom.write_prg(0x8001, 0b00110010);
+        assert_eq!(gxrom.read_prg(0x8002), Some(1));
+        assert_eq!(gxrom.read_chr(0x0000), 2);
+    }
+}
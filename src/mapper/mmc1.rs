@@ -0,0 +1,412 @@
+use crate::{cartridge::{Cartridge, Mirroring}, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+use super::{ChrMemory, Mapper, WorkRam, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+const PRG_RAM_BANK_SIZE: usize = 0x2000;
+/// 512KiB boards split their PRG-ROM into two 256KiB halves, selected through the CHR bank registers
+const OUTER_PRG_BANK_SIZE: usize = 0x40000;
+const LARGE_PRG_ROM_SIZE: usize = 2 * OUTER_PRG_BANK_SIZE;
+
+/// Writes with bit 7 set reset the shift register. Otherwise bit 0 is shifted in, and the fifth write
+/// copies the five bits into the register selected by the address
+const SHIFT_REGISTER_RESET: u8 = 0b10000000;
+const SHIFT_REGISTER_WRITES: u8 = 5;
+
+const CONTROL_MIRRORING: u8 = 0b00011;
+const CONTROL_PRG_MODE: u8 = 0b01100;
+const CONTROL_CHR_4K_MODE: u8 = 0b10000;
+const PRG_BANK_RAM_DISABLE: u8 = 0b10000;
+
+/// The MMC1 boards that use the CHR bank registers for more than CHR. Ref:
+/// https://www.nesdev.org/wiki/MMC1#SxROM_connection_variants
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Board {
+    /// Any board where the CHR bank registers only select CHR
+    Standard,
+    /// 8KiB CHR-RAM, where bit 4 of the CHR bank also disables the PRG-RAM
+    Snrom,
+    /// 16KiB PRG-RAM, with bit 3 of the CHR bank selecting an 8KiB bank of it
+    Sorom,
+    /// 512KiB PRG-ROM, with bit 4 of the CHR bank selecting a 256KiB half of it
+    Surom,
+    /// 512KiB PRG-ROM and 32KiB PRG-RAM, with bit 4 of the CHR bank selecting the PRG-ROM half and bits
+    /// 2-3 the PRG-RAM bank
+    Sxrom
+}
+
+impl Board {
+    /// NES 2.0 headers give the board as a submapper. Otherwise it has to be worked out from the ROM and RAM sizes
+    fn detect(cartridge: &Cartridge) -> Self {
+        match cartridge.submapper {
+            1 => return Board::Surom,
+            2 => return Board::Sorom,
+            3 => return Board::Sxrom,
+            _ => ()
+        }
+        let prg_ram_size = cartridge.prg_ram_size + cartridge.prg_nvram_size;
+        if cartridge.prg_rom.len() >= LARGE_PRG_ROM_SIZE {
+            if prg_ram_size >= 4 * PRG_RAM_BANK_SIZE { Board::Sxrom } else { Board::Surom }
+        } else if prg_ram_size >= 2 * PRG_RAM_BANK_SIZE {
+            Board::Sorom
+        } else if cartridge.chr_rom.is_empty() && prg_ram_size > 0 {
+            Board::Snrom
+        } else {
+            Board::Standard
+        }
+    }
+
+    fn prg_ram_size(self) -> usize {
+        match self {
+            Board::Sorom => 2 * PRG_RAM_BANK_SIZE,
+            Board::Sxrom => 4 * PRG_RAM_BANK_SIZE,
+            _ => PRG_RAM_BANK_SIZE
+        }
+    }
+}
+
+/// MMC1, mapper 1. Its registers are loaded serially, a bit per write, and switch 16KiB or 32KiB PRG banks,
+/// 4KiB or 8KiB CHR banks, and the nametable mirroring. Ref: https://www.nesdev.org/wiki/MMC1
+pub struct Mmc1 {
+    board: Board,
+    prg_rom: Vec<u8>,
+    prg_ram: WorkRam,
+    chr: ChrMemory,
+    shift_register: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    /// The MMC1 ignores a write on the cycle after another, so only the first write of a read-modify-write
+    /// instruction counts
+    cycle: u64,
+    last_write_cycle: Option<u64>
+}
+
+impl Mmc1 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let board = Board::detect(cartridge);
+        Self {
+            board,
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: WorkRam::with_minimum_size(cartridge, board.prg_ram_size()),
+            chr: ChrMemory::new(cartridge),
+            shift_register: 0,
+            shift_count: 0,
+            // Boards power up with the last PRG bank fixed at $C000, so the reset vector can be found
+            control: CONTROL_PRG_MODE,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycle: 0,
+            last_write_cycle: None
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank_0 = value,
+            0xC000..=0xDFFF => self.chr_bank_1 = value,
+            _ => self.prg_bank = value
+        }
+    }
+
+    /// The 256KiB half of PRG-ROM selected on SUROM and SXROM, as a count of 16KiB banks
+    fn outer_prg_bank(&self) -> usize {
+        match self.board {
+            Board::Surom | Board::Sxrom => ((self.chr_bank_0 as usize >> 4) & 1) * (OUTER_PRG_BANK_SIZE / PRG_BANK_SIZE),
+            _ => 0
+        }
+    }
+
+    /// The 16KiB bank of PRG-ROM mapped at the address
+    fn prg_bank_at(&self, address: u16) -> usize {
+        let bank = (self.prg_bank & 0b1111) as usize;
+        let upper_half = address >= 0xC000;
+        let inner_bank = match (self.control & CONTROL_PRG_MODE) >> 2 {
+            // 32KiB mode ignores the low bit of the bank number
+            0 | 1 => (bank & !1) | upper_half as usize,
+            2 => if upper_half { bank } else { 0 },
+            _ => if upper_half { 0b1111 } else { bank }
+        };
+        self.outer_prg_bank() + inner_bank
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        let snrom_disabled = self.board == Board::Snrom && self.chr_bank_0 & 0b10000 != 0;
+        self.prg_bank & PRG_BANK_RAM_DISABLE == 0 && !snrom_disabled
+    }
+
+    /// The 8KiB bank of work RAM selected on SOROM and SXROM
+    fn prg_ram_bank(&self) -> usize {
+        match self.board {
+            Board::Sorom => (self.chr_bank_0 as usize >> 3) & 0b01,
+            Board::Sxrom => (self.chr_bank_0 as usize >> 2) & 0b11,
+            _ => 0
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = if self.control & CONTROL_CHR_4K_MODE != 0 {
+            if address < 0x1000 { self.chr_bank_0 } else { self.chr_bank_1 }
+        } else {
+            // 8KiB mode ignores the low bit of the bank number
+            (self.chr_bank_0 & !1) | (address >= 0x1000) as u8
+        };
+        bank as usize * CHR_BANK_SIZE + (address as usize % CHR_BANK_SIZE)
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn peek_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END if self.prg_ram_enabled() => self.prg_ram.read_bank(self.prg_ram_bank(), address),
+            PRG_ROM_START..=0xFFFF => {
+                let offset = self.prg_bank_at(address) * PRG_BANK_SIZE + (address as usize % PRG_BANK_SIZE);
+                Some(self.prg_rom[offset % self.prg_rom.len()])
+            },
+            _ => None
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END if self.prg_ram_enabled() => {
+                let bank = self.prg_ram_bank();
+                self.prg_ram.write_bank(bank, address, value);
+            },
+            PRG_ROM_START..=0xFFFF => {
+                let consecutive = self.last_write_cycle.is_some_and(|last| self.cycle <= last + 1);
+                self.last_write_cycle = Some(self.cycle);
+                if consecutive {
+                    return;
+                }
+
+                if value & SHIFT_REGISTER_RESET != 0 {
+                    self.shift_register = 0;
+                    self.shift_count = 0;
+                    self.control |= CONTROL_PRG_MODE;
+                    return;
+                }
+                self.shift_register |= (value & 1) << self.shift_count;
+                self.shift_count += 1;
+                if self.shift_count == SHIFT_REGISTER_WRITES {
+                    self.write_register(address, self.shift_register);
+                    self.shift_register = 0;
+                    self.shift_count = 0;
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn peek_chr(&self, address: u16) -> u8 {
+        self.chr.read(self.chr_offset(address))
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.chr.write(self.chr_offset(address), value);
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.as_slice()
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.prg_ram.as_mut_slice()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & CONTROL_MIRRORING {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal
+        }
+    }
+
+    fn tick(&mut self) {
+        self.cycle += 1;
+    }
+}
+
+impl Snapshot for Mmc1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        self.chr.save_state(state);
+        state.write_u8(self.shift_register);
+        state.write_u8(self.shift_count);
+        state.write_u8(self.control);
+        state.write_u8(self.chr_bank_0);
+        state.write_u8(self.chr_bank_1);
+        state.write_u8(self.prg_bank);
+        state.write_u64(self.cycle);
+        state.write_bool(self.last_write_cycle.is_some());
+        state.write_u64(self.last_write_cycle.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(state)?;
+        self.chr.load_state(state)?;
+        self.shift_register = state.read_u8()?;
+        self.shift_count = state.read_u8()? % SHIFT_REGISTER_WRITES;
+        self.control = state.read_u8()?;
+        self.chr_bank_0 = state.read_u8()?;
+        self.chr_bank_1 = state.read_u8()?;
+        self.prg_bank = state.read_u8()?;
+        self.cycle = state.read_u64()?;
+        let has_written = state.read_bool()?;
+        let last_write_cycle = state.read_u64()?;
+        self.last_write_cycle = has_written.then_some(last_write_cycle);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge};
+
+    /// Loads a register a bit at a time, with a cycle between each write as there would be on the CPU
+    fn write_serially(mmc1: &mut Mmc1, address: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.tick();
+            mmc1.tick();
+            mmc1.write_prg(address, value >> bit);
+        }
+    }
+
+    fn mmc1_with_banks(prg_banks: usize, chr_banks: usize) -> Mmc1 {
+        Mmc1::new(&test_cartridge(1, numbered_banks(prg_banks, PRG_BANK_SIZE), numbered_banks(chr_banks, CHR_BANK_SIZE)))
+    }
+
+    #[test]
+    fn test_prg_banking_modes() {
+        let mut mmc1 = mmc1_with_banks(8, 2);
+        // Powers up in mode 3, with the last bank fixed at $C000
+        assert_eq!(mmc1.read_prg(0x8000), Some(0));
+        assert_eq!(mmc1.read_prg(0xC000), Some(7));
+        write_serially(&mut mmc1, 0xE000, 5);
+        assert_eq!(mmc1.read_prg(0x8000), Some(5));
+        assert_eq!(mmc1.read_prg(0xFFFF), Some(7));
+
+        // Mode 2 fixes the first bank at $8000
+        write_serially(&mut mmc1, 0x8000, 0b01000);
+        assert_eq!(mmc1.read_prg(0x8000), Some(0));
+        assert_eq!(mmc1.read_prg(0xC000), Some(5));
+
+        // Modes 0 and 1 switch 32KiB at a time
+        write_serially(&mut mmc1, 0x8000, 0b00100);
+        assert_eq!(mmc1.read_prg(0x8000), Some(4));
+        assert_eq!(mmc1.read_prg(0xC000), Some(5));
+    }
+
+    #[test]
+    fn test_chr_banking_modes() {
+        let mut mmc1 = mmc1_with_banks(2, 8);
+        write_serially(&mut mmc1, 0xA000, 3);
+        write_serially(&mut mmc1, 0xC000, 6);
+        // 8KiB mode ignores the second register and the low bit
+        assert_eq!(mmc1.read_chr(0x0000), 2);
+        assert_eq!(mmc1.read_chr(0x1000), 3);
+
+        write_serially(&mut mmc1, 0x8000, CONTROL_PRG_MODE | CONTROL_CHR_4K_MODE);
+        assert_eq!(mmc1.read_chr(0x0000), 3);
+        assert_eq!(mmc1.read_chr(0x1000), 6);
+    }
+
+    #[test]
+    fn test_mirroring_control() {
+        let mut mmc1 = mmc1_with_banks(2, 2);
+        let expected = [Mirroring::SingleScreenLower, Mirroring::SingleScreenUpper, Mirroring::Vertical, Mirroring::Horizontal];
+        for (mode, mirroring) in expected.into_iter().enumerate() {
+            write_serially(&mut mmc1, 0x8000, CONTROL_PRG_MODE | mode as u8);
+            assert_eq!(mmc1.mirroring(), mirroring);
+        }
+    }
+
+    #[test]
+    fn test_prg_ram_can_be_disabled() {
+        let mut mmc1 = mmc1_with_banks(2, 2);
+        mmc1.write_prg(0x6000, 0x42);
+        assert_eq!(mmc1.read_prg(0x6000), Some(0x42));
+        write_serially(&mut mmc1, 0xE000, PRG_BANK_RAM_DISABLE);
+        assert_eq!(mmc1.read_prg(0x6000), None);
+    }
+
+    #[test]
+    fn test_reset_and_consecutive_writes() {
+        let mut mmc1 = mmc1_with_banks(8, 2);
+        // A reset part way through loading a register starts again, and sets PRG mode 3
+        write_serially(&mut mmc1, 0x8000, 0b00000);
+        mmc1.tick();
+        mmc1.tick();
+        mmc1.write_prg(0xE000, 1);
+        mmc1.tick();
+        mmc1.tick();
+        mmc1.write_prg(0xE000, SHIFT_REGISTER_RESET);
+        write_serially(&mut mmc1, 0xE000, 2);
+        assert_eq!(mmc1.read_prg(0x8000), Some(2));
+        assert_eq!(mmc1.read_prg(0xC000), Some(7));
+
+        // The second write of a read-modify-write, on the very next cycle, is ignored. Here that's the write
+        // that would have reset the shift register
+        mmc1.tick();
+        mmc1.tick();
+        mmc1.write_prg(0xE000, 1);
+        mmc1.tick();
+        mmc1.write_prg(0xE000, SHIFT_REGISTER_RESET);
+        for _ in 0..4 {
+            mmc1.tick();
+            mmc1.tick();
+            mmc1.write_prg(0xE000, 0);
+        }
+        assert_eq!(mmc1.read_prg(0x8000), Some(1));
+    }
+
+    #[test]
+    fn test_board_detection() {
+        let mut cartridge = test_cartridge(1, numbered_banks(32, PRG_BANK_SIZE), vec![]);
+        assert_eq!(Board::detect(&cartridge), Board::Surom);
+        cartridge.prg_ram_size = 0x8000;
+        assert_eq!(Board::detect(&cartridge), Board::Sxrom);
+
+        let mut cartridge = test_cartridge(1, numbered_banks(8, PRG_BANK_SIZE), vec![]);
+        assert_eq!(Board::detect(&cartridge), Board::Snrom);
+        cartridge.submapper = 2;
+        assert_eq!(Board::detect(&cartridge), Board::Sorom);
+        let cartridge = test_cartridge(1, numbered_banks(8, PRG_BANK_SIZE), numbered_banks(2, CHR_BANK_SIZE));
+        assert_eq!(Board::detect(&cartridge), Board::Standard);
+    }
+
+    #[test]
+    fn test_surom_outer_prg_bank() {
+        let mut mmc1 = Mmc1::new(&test_cartridge(1, numbered_banks(32, PRG_BANK_SIZE), vec![]));
+        assert_eq!(mmc1.board, Board::Surom);
+        assert_eq!(mmc1.read_prg(0xC000), Some(15));
+        write_serially(&mut mmc1, 0xA000, 0b10000);
+        write_serially(&mut mmc1, 0xE000, 3);
+        assert_eq!(mmc1.read_prg(0x8000), Some(19));
+        assert_eq!(mmc1.read_prg(0xC000), Some(31));
+    }
+
+    #[test]
+    fn test_snrom_and_sorom_prg_ram() {
+        let mut snrom = Mmc1::new(&test_cartridge(1, numbered_banks(8, PRG_BANK_SIZE), vec![]));
+        write_serially(&mut snrom, 0xA000, 0b10000);
+        assert_eq!(snrom.read_prg(0x6000), None);
+
+        let mut cartridge = test_cartridge(1, numbered_banks(8, PRG_BANK_SIZE), vec![]);
+        cartridge.submapper = 2;
+        let mut sorom = Mmc1::new(&cartridge);
+        sorom.write_prg(0x6000, 0x11);
+        write_serially(&mut sorom, 0xA000, 0b01000);
+        assert_eq!(sorom.read_prg(0x6000), Some(0x00));
+        sorom.write_prg(0x6000, 0x22);
+        write_serially(&mut sorom, 0xA000, 0b00000);
+        assert_eq!(sorom.read_prg(0x6000), Some(0x11));
+    }
+}
@@ -0,0 +1,353 @@
+use crate::{cartridge::{Cartridge, Mirroring}, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+use super::{ChrMemory, Mapper, WorkRam, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const BANK_SELECT_REGISTER: u8 = 0b00000111;
+const BANK_SELECT_PRG_MODE: u8 = 0b01000000;
+const BANK_SELECT_CHR_INVERSION: u8 = 0b10000000;
+const PRG_RAM_ENABLE: u8 = 0b10000000;
+const PRG_RAM_WRITE_PROTECT: u8 = 0b01000000;
+
+/// The scanline counter is clocked when A12 on the PPU bus rises. It has to have been low for a few CPU
+/// cycles first, which filters out the quick toggles between background fetches
+const PPU_A12: u16 = 0x1000;
+const A12_LOW_CYCLES: u64 = 4;
+
+/// MMC3, mapper 4. Eight bank registers switch 8KiB PRG banks and 1KiB or 2KiB CHR banks, and a counter
+/// watching the PPU's address bus interrupts the CPU after a number of scanlines.
+/// Ref: https://www.nesdev.org/wiki/MMC3
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    prg_ram: WorkRam,
+    chr: ChrMemory,
+    four_screen: bool,
+    bank_select: u8,
+    /// R0-R1 are 2KiB CHR banks, R2-R5 1KiB CHR banks, and R6-R7 8KiB PRG banks
+    banks: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_protect: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    cycle: u64,
+    a12_high: bool,
+    a12_low_since: u64
+}
+
+impl Mmc3 {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: WorkRam::new(cartridge),
+            chr: ChrMemory::new(cartridge),
+            four_screen: cartridge.mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: cartridge.mirroring,
+            prg_ram_protect: PRG_RAM_ENABLE,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            cycle: 0,
+            a12_high: false,
+            a12_low_since: 0
+        }
+    }
+
+    /// The 8KiB bank of PRG-ROM mapped at the address
+    fn prg_bank_at(&self, address: u16) -> usize {
+        // ROMs smaller than a bank are mirrored through it
+        let last_bank = (self.prg_rom.len() / PRG_BANK_SIZE).saturating_sub(1);
+        let second_last_bank = last_bank.saturating_sub(1);
+        let slot = (address - PRG_ROM_START) as usize / PRG_BANK_SIZE;
+        // PRG mode 1 swaps the two slots R6 and the second to last bank appear in
+        let swapped = self.bank_select & BANK_SELECT_PRG_MODE != 0;
+        match (slot, swapped) {
+            (0, false) | (2, true) => (self.banks[6] & 0b00111111) as usize,
+            (0, true) | (2, false) => second_last_bank,
+            (1, _) => (self.banks[7] & 0b00111111) as usize,
+            _ => last_bank
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        // Inversion swaps the 2KiB banks and the 1KiB banks between the pattern tables
+        let address = if self.bank_select & BANK_SELECT_CHR_INVERSION != 0 { address ^ 0x1000 } else { address } as usize;
+        let bank = match address / CHR_BANK_SIZE {
+            slot @ 0..=3 => (self.banks[slot / 2] & !1) as usize + slot % 2,
+            slot => self.banks[slot - 2] as usize
+        };
+        bank * CHR_BANK_SIZE + address % CHR_BANK_SIZE
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_protect & PRG_RAM_ENABLE != 0
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn peek_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END if self.prg_ram_enabled() => self.prg_ram.read(address),
+            PRG_ROM_START..=0xFFFF => {
+                let offset = self.prg_bank_at(address) * PRG_BANK_SIZE + (address as usize % PRG_BANK_SIZE);
+                Some(self.prg_rom[offset % self.prg_rom.len()])
+            },
+            _ => None
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END if self.prg_ram_enabled() && self.prg_ram_protect & PRG_RAM_WRITE_PROTECT == 0 => {
+                self.prg_ram.write(address, value);
+            },
+            // Each pair of registers is mirrored through its 8KiB, with A0 choosing between them
+            PRG_ROM_START..=0xFFFF => match address & 0xE001 {
+                0x8000 => self.bank_select = value,
+                0x8001 => self.banks[(self.bank_select & BANK_SELECT_REGISTER) as usize] = value,
+                0xA000 if !self.four_screen => {
+                    self.mirroring = if value & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+                },
+                0xA001 => self.prg_ram_protect = value,
+                0xC000 => self.irq_latch = value,
+                0xC001 => {
+                    self.irq_counter = 0;
+                    self.irq_reload = true;
+                },
+                0xE000 => {
+                    self.irq_enabled = false;
+                    self.irq_pending = false;
+                },
+                0xE001 => self.irq_enabled = true,
+                _ => ()
+            },
+            _ => ()
+        }
+    }
+
+    fn peek_chr(&self, address: u16) -> u8 {
+        self.chr.read(self.chr_offset(address))
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.chr.write(self.chr_offset(address), value);
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.as_slice()
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.prg_ram.as_mut_slice()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn tick(&mut self) {
+        self.cycle += 1;
+    }
+
+    fn ppu_bus_access(&mut self, address: u16) {
+        let a12_high = address & PPU_A12 != 0;
+        if a12_high && !self.a12_high && self.cycle - self.a12_low_since >= A12_LOW_CYCLES {
+            self.clock_irq_counter();
+        } else if !a12_high && self.a12_high {
+            self.a12_low_since = self.cycle;
+        }
+        self.a12_high = a12_high;
+    }
+}
+
+impl Snapshot for Mmc3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        self.chr.save_state(state);
+        state.write_u8(self.bank_select);
+        state.write_bytes(&self.banks);
+        state.write_bool(self.mirroring == Mirroring::Horizontal);
+        state.write_u8(self.prg_ram_protect);
+        state.write_u8(self.irq_latch);
+        state.write_u8(self.irq_counter);
+        state.write_bool(self.irq_reload);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_pending);
+        state.write_u64(self.cycle);
+        state.write_bool(self.a12_high);
+        state.write_u64(self.a12_low_since);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(state)?;
+        self.chr.load_state(state)?;
+        self.bank_select = state.read_u8()?;
+        state.read_bytes_into(&mut self.banks)?;
+        let horizontal = state.read_bool()?;
+        // Four-screen boards ignore the mirroring register
+        if !self.four_screen {
+            self.mirroring = if horizontal { Mirroring::Horizontal } else { Mirroring::Vertical };
+        }
+        self.prg_ram_protect = state.read_u8()?;
+        self.irq_latch = state.read_u8()?;
+        self.irq_counter = state.read_u8()?;
+        self.irq_reload = state.read_bool()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        self.cycle = state.read_u64()?;
+        self.a12_high = state.read_bool()?;
+        self.a12_low_since = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge};
+
+    fn mmc3_with_banks(prg_banks: usize, chr_banks: usize) -> Mmc3 {
+        Mmc3::new(&test_cartridge(4, numbered_banks(prg_banks, PRG_BANK_SIZE), numbered_banks(chr_banks, CHR_BANK_SIZE)))
+    }
+
+    fn select_bank(mmc3: &mut Mmc3, register: u8, bank: u8) {
+        mmc3.write_prg(0x8000, register);
+        mmc3.write_prg(0x8001, bank);
+    }
+
+    /// What the counter sees of a scanline with the background at $0000 and sprites at $1000
+    fn run_scanline(mmc3: &mut Mmc3) {
+        mmc3.ppu_bus_access(0x0000);
+        for _ in 0..30 {
+            mmc3.tick();
+        }
+        mmc3.ppu_bus_access(0x1FF0);
+        for _ in 0..84 {
+            mmc3.tick();
+        }
+    }
+
+    #[test]
+    fn test_prg_banking_modes() {
+        let mut mmc3 = mmc3_with_banks(16, 8);
+        select_bank(&mut mmc3, 6, 3);
+        select_bank(&mut mmc3, 7, 9);
+        assert_eq!(mmc3.read_prg(0x8000), Some(3));
+        assert_eq!(mmc3.read_prg(0xA000), Some(9));
+        assert_eq!(mmc3.read_prg(0xC000), Some(14));
+        assert_eq!(mmc3.read_prg(0xE000), Some(15));
+
+        mmc3.write_prg(0x8000, BANK_SELECT_PRG_MODE);
+        assert_eq!(mmc3.read_prg(0x8000), Some(14));
+        assert_eq!(mmc3.read_prg(0xA000), Some(9));
+        assert_eq!(mmc3.read_prg(0xC000), Some(3));
+        assert_eq!(mmc3.read_prg(0xE000), Some(15));
+    }
+
+    #[test]
+    fn test_prg_rom_smaller_than_a_bank() {
+        let prg_rom: Vec<u8> = (0..0x1000).map(|i| (i >> 4) as u8).collect();
+        let mut mmc3 = Mmc3::new(&test_cartridge(4, prg_rom, vec![]));
+        assert_eq!(mmc3.read_prg(0xE000), Some(0x00));
+        assert_eq!(mmc3.read_prg(0xFFFF), Some(0xFF));
+        assert_eq!(mmc3.read_prg(0xC010), Some(0x01));
+    }
+
+    #[test]
+    fn test_chr_banking_and_inversion() {
+        let mut mmc3 = mmc3_with_banks(4, 32);
+        for (register, bank) in [(0, 5), (1, 10), (2, 20), (3, 21), (4, 22), (5, 23)] {
+            select_bank(&mut mmc3, register, bank);
+        }
+        // 2KiB banks ignore the low bit
+        let expected = [4, 5, 10, 11, 20, 21, 22, 23];
+        for (slot, bank) in expected.into_iter().enumerate() {
+            assert_eq!(mmc3.read_chr(slot as u16 * 0x400), bank);
+        }
+
+        mmc3.write_prg(0x8000, BANK_SELECT_CHR_INVERSION);
+        for (slot, bank) in expected.into_iter().enumerate() {
+            assert_eq!(mmc3.read_chr((slot as u16 * 0x400) ^ 0x1000), bank);
+        }
+    }
+
+    #[test]
+    fn test_mirroring_and_prg_ram_protection() {
+        let mut mmc3 = mmc3_with_banks(4, 8);
+        mmc3.write_prg(0xA000, 0);
+        assert_eq!(mmc3.mirroring(), Mirroring::Vertical);
+        mmc3.write_prg(0xBFFE, 1);
+        assert_eq!(mmc3.mirroring(), Mirroring::Horizontal);
+
+        mmc3.write_prg(0x6000, 0x42);
+        mmc3.write_prg(0xA001, PRG_RAM_ENABLE | PRG_RAM_WRITE_PROTECT);
+        mmc3.write_prg(0x6000, 0x43);
+        assert_eq!(mmc3.read_prg(0x6000), Some(0x42));
+        mmc3.write_prg(0xA001, 0);
+        assert_eq!(mmc3.read_prg(0x6000), None);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc3 = mmc3_with_banks(4, 8);
+        mmc3.write_prg(0xC000, 2);
+        mmc3.write_prg(0xC001, 0);
+        mmc3.write_prg(0xE001, 0);
+        // Reloads to 2, then counts down to 0 two scanlines later
+        for _ in 0..2 {
+            run_scanline(&mut mmc3);
+            assert!(!mmc3.irq_asserted());
+        }
+        run_scanline(&mut mmc3);
+        assert!(mmc3.irq_asserted());
+
+        // Disabling acknowledges the interrupt
+        mmc3.write_prg(0xE000, 0);
+        assert!(!mmc3.irq_asserted());
+        run_scanline(&mut mmc3);
+        run_scanline(&mut mmc3);
+        assert!(!mmc3.irq_asserted());
+    }
+
+    #[test]
+    fn test_quick_a12_toggles_are_filtered() {
+        let mut mmc3 = mmc3_with_banks(4, 8);
+        mmc3.write_prg(0xC000, 1);
+        mmc3.write_prg(0xE001, 0);
+        run_scanline(&mut mmc3);
+        // Background fetches from $1000 with nametable fetches in between
+        for _ in 0..8 {
+            mmc3.ppu_bus_access(0x2000);
+            mmc3.tick();
+            mmc3.ppu_bus_access(0x1000);
+            mmc3.tick();
+        }
+        assert!(!mmc3.irq_asserted());
+        run_scanline(&mut mmc3);
+        assert!(mmc3.irq_asserted());
+    }
+}
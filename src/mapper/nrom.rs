@@ -0,0 +1,111 @@
+use crate::{cartridge::{Cartridge, Mirroring}, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+use super::{ChrMemory, Mapper, WorkRam, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+/// NROM, mapper 0. There's no banking: 16KiB or 32KiB of PRG-ROM, where 16KiB boards see it mirrored into
+/// both halves of $8000-$FFFF, and 8KiB of CHR. Ref: https://www.nesdev.org/wiki/NROM
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    /// Only the Family BASIC boards had work RAM, but emulators conventionally provide it
+    prg_ram: WorkRam,
+    chr: ChrMemory,
+    mirroring: Mirroring
+}
+
+impl Nrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: WorkRam::new(cartridge),
+            chr: ChrMemory::new(cartridge),
+            mirroring: cartridge.mirroring
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn peek_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.read(address),
+            PRG_ROM_START..=0xFFFF => Some(self.prg_rom[(address - PRG_ROM_START) as usize % self.prg_rom.len()]),
+            _ => None
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        if let PRG_RAM_START..=PRG_RAM_END = address {
+            self.prg_ram.write(address, value);
+        }
+    }
+
+    fn peek_chr(&self, address: u16) -> u8 {
+        self.chr.read(address as usize)
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.chr.write(address as usize, value);
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.as_slice()
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.prg_ram.as_mut_slice()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+impl Snapshot for Nrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        self.chr.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(state)?;
+        self.chr.load_state(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge};
+
+    #[test]
+    fn test_16k_prg_rom_is_mirrored() {
+        let mut nrom = Nrom::new(&test_cartridge(0, numbered_banks(1, 0x4000), vec![]));
+        assert_eq!(nrom.read_prg(0x8000), Some(0));
+        assert_eq!(nrom.read_prg(0xC000), Some(0));
+        // Nothing responds below the work RAM
+        assert_eq!(nrom.read_prg(0x5000), None);
+    }
+
+    #[test]
+    fn test_32k_prg_rom_and_work_ram() {
+        let mut nrom = Nrom::new(&test_cartridge(0, numbered_banks(2, 0x4000), vec![]));
+        assert_eq!(nrom.read_prg(0xBFFF), Some(0));
+        assert_eq!(nrom.read_prg(0xC000), Some(1));
+        // ROM can't be written, but work RAM can
+        nrom.write_prg(0x8000, 0x55);
+        nrom.write_prg(0x6000, 0xAA);
+        assert_eq!(nrom.read_prg(0x8000), Some(0));
+        assert_eq!(nrom.read_prg(0x6000), Some(0xAA));
+    }
+
+    #[test]
+    fn test_chr_rom_is_read_only_and_chr_ram_is_not() {
+        let mut nrom = Nrom::new(&test_cartridge(0, numbered_banks(1, 0x4000), vec![0x11; 0x2000]));
+        nrom.write_chr(0x0000, 0x22);
+        assert_eq!(nrom.read_chr(0x0000), 0x11);
+
+        let mut nrom = Nrom::new(&test_cartridge(0, numbered_banks(1, 0x4000), vec![]));
+        nrom.write_chr(0x1FFF, 0x22);
+        assert_eq!(nrom.read_chr(0x1FFF), 0x22);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{cartridge::{Cartridge, Mirroring}, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+use super::{has_bus_conflicts, ChrMemory, Mapper, WorkRam, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+/// UxROM, mapper 2. Any write to ROM selects the 16KiB PRG bank at $8000, with the last bank fixed at $C000.
+/// CHR is 8KiB, usually RAM. Ref: https://www.nesdev.org/wiki/UxROM
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    prg_ram: WorkRam,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    prg_bank: u8
+}
+
+impl Uxrom {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        Self {
+            prg_rom: cartridge.prg_rom.clone(),
+            prg_ram: WorkRam::new(cartridge),
+            chr: ChrMemory::new(cartridge),
+            mirroring: cartridge.mirroring,
+            bus_conflicts: has_bus_conflicts(cartridge, true),
+            prg_bank: 0
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn peek_prg(&self, address: u16) -> Option<u8> {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.read(address),
+            PRG_ROM_START..=0xFFFF => {
+                // ROMs smaller than a bank are mirrored through it
+                let last_bank = (self.prg_rom.len() / PRG_BANK_SIZE).saturating_sub(1);
+                let bank = if address < 0xC000 { self.prg_bank as usize } else { last_bank };
+                let offset = bank * PRG_BANK_SIZE + (address as usize % PRG_BANK_SIZE);
+                Some(self.prg_rom[offset % self.prg_rom.len()])
+            },
+            _ => None
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            PRG_RAM_START..=PRG_RAM_END => self.prg_ram.write(address, value),
+            PRG_ROM_START..=0xFFFF => {
+                let rom_value = self.peek_prg(address).unwrap_or(0xFF);
+                self.prg_bank = if self.bus_conflicts { value & rom_value } else { value };
+            },
+            _ => ()
+        }
+    }
+
+    fn peek_chr(&self, address: u16) -> u8 {
+        self.chr.read(address as usize)
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.chr.write(address as usize, value);
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.as_slice()
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.prg_ram.as_mut_slice()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+impl Snapshot for Uxrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        self.chr.save_state(state);
+        state.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.load_state(state)?;
+        self.chr.load_state(state)?;
+        self.prg_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge};
+
+    #[test]
+    fn test_switchable_and_fixed_banks() {
+        let mut uxrom = Uxrom::new(&test_cartridge(2, numbered_banks(8, PRG_BANK_SIZE), vec![]));
+        assert_eq!(uxrom.read_prg(0xC000), Some(7));
+        // The fixed bank is all 7s, so writing the bank number there doesn't conflict
+        uxrom.write_prg(0xC003, 3);
+        assert_eq!(uxrom.read_prg(0x8000), Some(3));
+        assert_eq!(uxrom.read_prg(0xFFFF), Some(7));
+    }
+
+    #[test]
+    fn test_bus_conflicts() {
+        let mut prg_rom = numbered_banks(8, PRG_BANK_SIZE);
+        *prg_rom.last_mut().unwrap() = 0b101;
+        let mut cartridge = test_cartridge(2, prg_rom, vec![]);
+        let mut uxrom = Uxrom::new(&cartridge);
+        uxrom.write_prg(0xFFFF, 0b110);
+        assert_eq!(uxrom.read_prg(0x8000), Some(0b100));
+
+        // Submapper 1 boards don't have them
+        cartridge.submapper = 1;
+        let mut uxrom = Uxrom::new(&cartridge);
+        uxrom.write_prg(0xFFFF, 0b110);
+        assert_eq!(uxrom.read_prg(0x8000), Some(0b110));
+    }
+
+    #[test]
+    fn test_prg_rom_smaller_than_a_bank() {
+        // NES 2.0 exponent-multiplier sizes go below 16KiB: this is 2^12 * 1 = 4KiB
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 12 << 2, 0x00, 0x20, 0x08, 0x00, 0x0F, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00];
+        rom.extend((0..0x1000).map(|i| (i >> 4) as u8));
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        assert_eq!(cartridge.prg_rom.len(), 0x1000);
+        let mut uxrom = Uxrom::new(&cartridge);
+        assert_eq!(uxrom.read_prg(0xC000), Some(0x00));
+        assert_eq!(uxrom.read_prg(0xFFFF), Some(0xFF));
+        assert_eq!(uxrom.read_prg(0x8010), Some(0x01));
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{apu::Apu, bus::Bus, controller::Controller, mapper::Mapper, ppu::Ppu, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+/// The NES has 2KiB of internal RAM, mirrored four times across $0000-$1FFF
+const RAM_SIZE: usize = 0x0800;
+const RAM_END: u16 = 0x1FFF;
+/// The eight PPU registers are mirrored every eight bytes across $2000-$3FFF
+const PPU_REGISTER_COUNT: usize = 8;
+/// The NTSC PPU runs three dots for every CPU cycle
+const PPU_DOTS_PER_CPU_CYCLE: usize = 3;
+const PPU_REGISTERS_START: u16 = 0x2000;
+const PPU_REGISTERS_END: u16 = 0x3FFF;
+/// The APU and IO registers live at $4000-$4017. $4018-$401F is normally disabled test functionality
+const APU_IO_REGISTERS_START: u16 = 0x4000;
+const APU_IO_REGISTERS_END: u16 = 0x4017;
+/// The only APU register that can be read
+const APU_STATUS: u16 = 0x4015;
+const OAM_DMA: u16 = 0x4014;
+/// Writing $4016 strobes both controllers, and each is read from its own port. Writes to $4017 go to the APU
+const CONTROLLER_1: u16 = 0x4016;
+const CONTROLLER_2: u16 = 0x4017;
+/// Controller reads only drive the low bits of the data bus
+const CONTROLLER_OPEN_BUS_MASK: u8 = 0b11100000;
+/// Everything from $4020 upwards is handed to the cartridge
+const CARTRIDGE_START: u16 = 0x4020;
+
+/// The CPU's view of the NES. Addresses are decoded into internal RAM, the PPU and APU/IO registers
+/// and cartridge space following the [CPU memory map](https://www.nesdev.org/wiki/CPU_memory_map)
+pub struct NesBus {
+    ram: [u8; RAM_SIZE],
+    ppu: Ppu,
+    apu: Apu,
+    controllers: [Controller; 2],
+    /// The page written to $4014, waiting for the CPU to copy it
+    oam_dma_page: Option<u8>,
+    mapper: Box<dyn Mapper>,
+    /// The last value driven onto the data bus. Reads from addresses nothing responds to return this
+    open_bus: u8
+}
+
+impl NesBus {
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
+        Self {
+            ram: [0; RAM_SIZE],
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            controllers: [Controller::new(), Controller::new()],
+            oam_dma_page: None,
+            mapper,
+            open_bus: 0
+        }
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    /// Reads from the PPU's address space without side effects, with the cartridge's pattern tables and
+    /// mirroring in place
+    pub fn peek_ppu_memory(&self, address: u16) -> u8 {
+        self.ppu.peek_memory(address, self.mapper.as_ref())
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn mapper(&self) -> &dyn Mapper {
+        self.mapper.as_ref()
+    }
+
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        self.mapper.as_mut()
+    }
+
+    /// The controller plugged into port 0 or 1, for the host to set the buttons on
+    pub fn controller_mut(&mut self, port: usize) -> &mut Controller {
+        &mut self.controllers[port]
+    }
+
+    fn ram_index(address: u16) -> usize {
+        address as usize % RAM_SIZE
+    }
+
+    fn ppu_register_index(address: u16) -> usize {
+        (address - PPU_REGISTERS_START) as usize % PPU_REGISTER_COUNT
+    }
+
+    fn peek_mapped(&self, address: u16) -> Option<u8> {
+        match address {
+            0..=RAM_END => Some(self.ram[Self::ram_index(address)]),
+            PPU_REGISTERS_START..=PPU_REGISTERS_END => Some(self.ppu.peek_register(Self::ppu_register_index(address))),
+            // Reading these registers has side effects, or just returns open bus, so there's nothing meaningful
+            // to peek at. They show as $FF, which is also how the nestest log shows them
+            APU_IO_REGISTERS_START..=APU_IO_REGISTERS_END => Some(0xFF),
+            CARTRIDGE_START..=0xFFFF => self.mapper.peek_prg(address),
+            _ => None
+        }
+    }
+}
+
+impl Bus for NesBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = match address {
+            PPU_REGISTERS_START..=PPU_REGISTERS_END => self.ppu.read_register(Self::ppu_register_index(address), self.mapper.as_mut()),
+            // Bit 5 of the status isn't driven
+            APU_STATUS => self.apu.read_status() | (self.open_bus & 0b00100000),
+            CONTROLLER_1 => self.controllers[0].read() | (self.open_bus & CONTROLLER_OPEN_BUS_MASK),
+            CONTROLLER_2 => self.controllers[1].read() | (self.open_bus & CONTROLLER_OPEN_BUS_MASK),
+            APU_IO_REGISTERS_START..=APU_IO_REGISTERS_END => self.open_bus,
+            CARTRIDGE_START..=0xFFFF => self.mapper.read_prg(address).unwrap_or(self.open_bus),
+            _ => self.peek(address)
+        };
+        self.open_bus = value;
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.open_bus = value;
+        match address {
+            0..=RAM_END => self.ram[Self::ram_index(address)] = value,
+            PPU_REGISTERS_START..=PPU_REGISTERS_END => self.ppu.write_register(Self::ppu_register_index(address), value, self.mapper.as_mut()),
+            OAM_DMA => self.oam_dma_page = Some(value),
+            CONTROLLER_1 => {
+                for controller in &mut self.controllers {
+                    controller.write_strobe(value);
+                }
+            },
+            APU_IO_REGISTERS_START..=APU_IO_REGISTERS_END => self.apu.write_register(address, value),
+            CARTRIDGE_START..=0xFFFF => self.mapper.write_prg(address, value),
+            // Writes to the disabled test registers go nowhere
+            _ => ()
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.peek_mapped(address).unwrap_or(self.open_bus)
+    }
+
+    fn tick(&mut self) {
+        for _ in 0..PPU_DOTS_PER_CPU_CYCLE {
+            self.ppu.tick(self.mapper.as_mut());
+        }
+        self.apu.tick();
+        self.mapper.tick();
+    }
+
+    /// The DMC asks for its next sample as soon as its buffer empties, and stops asking once it's loaded
+    fn dmc_dma_request(&self) -> Option<u16> {
+        self.apu.dmc_sample_address()
+    }
+
+    fn load_dmc_sample(&mut self, byte: u8) {
+        self.apu.load_dmc_sample(byte);
+    }
+
+    fn nmi_asserted(&self) -> bool {
+        self.ppu.nmi_asserted()
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.apu.irq_asserted() || self.mapper.irq_asserted()
+    }
+
+    fn take_oam_dma_request(&mut self) -> Option<u8> {
+        self.oam_dma_page.take()
+    }
+
+    fn ppu_position(&self) -> (u16, u16) {
+        self.ppu.position()
+    }
+}
+
+/// The console's own state, then each chip's in its own section
+impl Snapshot for NesBus {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"BUS ", |state| {
+            state.write_bytes(&self.ram);
+            state.write_bool(self.oam_dma_page.is_some());
+            state.write_u8(self.oam_dma_page.unwrap_or(0));
+            state.write_u8(self.open_bus);
+            for controller in &self.controllers {
+                controller.save_state(state);
+            }
+        });
+        state.section(b"PPU ", |state| self.ppu.save_state(state));
+        state.section(b"APU ", |state| self.apu.save_state(state));
+        state.section(b"CART", |state| self.mapper.save_state(state));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.section(b"BUS ", |state| {
+            state.read_bytes_into(&mut self.ram)?;
+            let has_dma_page = state.read_bool()?;
+            let dma_page = state.read_u8()?;
+            self.oam_dma_page = has_dma_page.then_some(dma_page);
+            self.open_bus = state.read_u8()?;
+            for controller in &mut self.controllers {
+                controller.load_state(state)?;
+            }
+            Ok(())
+        })?;
+        state.section(b"PPU ", |state| self.ppu.load_state(state))?;
+        state.section(b"APU ", |state| self.apu.load_state(state))?;
+        state.section(b"CART", |state| self.mapper.load_state(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge, Nrom};
+
+    fn test_bus() -> NesBus {
+        NesBus::new(Box::new(Nrom::new(&test_cartridge(0, numbered_banks(2, 0x4000), vec![]))))
+    }
+
+    #[test]
+    fn test_ram_is_mirrored() {
+        let mut bus = test_bus();
+        bus.write(0x0012, 0xAB);
+        for mirror in [0x0012, 0x0812, 0x1012, 0x1812] {
+            assert_eq!(bus.read(mirror), 0xAB);
+        }
+        bus.write(0x1FFF, 0xCD);
+        assert_eq!(bus.read(0x07FF), 0xCD);
+    }
+
+    #[test]
+    fn test_ppu_registers_are_mirrored() {
+        let mut bus = test_bus();
+        // PPUADDR through a mirror, then PPUDATA through another
+        bus.write(0x3FFE, 0x21);
+        bus.write(0x200E, 0x08);
+        bus.write(0x2FFF, 0x1E);
+        assert_eq!(bus.peek_ppu_memory(0x2108), 0x1E);
+    }
+
+    #[test]
+    fn test_ticks_clock_the_ppu() {
+        let mut bus = test_bus();
+        bus.tick();
+        assert_eq!(bus.ppu_position(), (0, 3));
+    }
+
+    #[test]
+    fn test_unmapped_reads_return_open_bus() {
+        let mut bus = test_bus();
+        bus.write(0x4017, 0x40);
+        bus.write(0x4020, 0x5A);
+        assert_eq!(bus.read(0x4020), 0x5A);
+        assert_eq!(bus.read(0x4018), 0x5A);
+    }
+
+    #[test]
+    fn test_apu_status_and_frame_interrupt() {
+        let mut bus = test_bus();
+        bus.write(0x4015, 0b00000001);
+        bus.write(0x4003, 0b00001000);
+        assert_eq!(bus.read(0x4015), 0b00000001);
+        // Write-only registers read back as open bus
+        assert_eq!(bus.read(0x4003), 0b00000001);
+
+        for _ in 0..29830 {
+            bus.tick();
+        }
+        assert!(bus.irq_asserted());
+        assert_eq!(bus.read(0x4015) & 0b01000000, 0b01000000);
+        assert!(!bus.irq_asserted());
+    }
+
+    #[test]
+    fn test_controller_ports() {
+        let mut bus = test_bus();
+        bus.controller_mut(0).set_buttons(0b00000010);
+        bus.controller_mut(1).set_buttons(0b00000001);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        assert_eq!(bus.read(0x4016) & 1, 0);
+        assert_eq!(bus.read(0x4016) & 1, 1);
+        assert_eq!(bus.read(0x4017) & 1, 1);
+        assert_eq!(bus.read(0x4017) & 1, 0);
+    }
+
+    #[test]
+    fn test_cartridge_space_is_handed_to_the_mapper() {
+        let mut bus = NesBus::new(Box::new(Nrom::new(&test_cartridge(0, include_bytes!("../nestest.bin").to_vec(), vec![]))));
+        assert_eq!(bus.read(0x8000), 0x4C);
+        assert_eq!(bus.read(0xC000), 0x4C);
+        assert_eq!(bus.read(0xFFFC), 0x04);
+    }
+
+    #[test]
+    fn test_work_ram_is_exposed_for_battery_saves() {
+        let mut bus = test_bus();
+        bus.write(0x6000, 0x12);
+        assert_eq!(bus.mapper().prg_ram()[0], 0x12);
+        bus.mapper_mut().prg_ram_mut()[1] = 0x34;
+        assert_eq!(bus.read(0x6001), 0x34);
+    }
+}
@@ -0,0 +1,948 @@
+use crate::{cartridge::Mirroring, mapper::Mapper, savestate::{Snapshot, StateError, StateReader, StateWriter}};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+/// NTSC timing. Each scanline is 341 dots long, and a frame is 240 visible scanlines, a post-render
+/// scanline, 20 scanlines of vertical blank and a pre-render scanline
+const DOTS_PER_SCANLINE: u16 = 341;
+const SCANLINES_PER_FRAME: u16 = 262;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+const OAM_SIZE: usize = 256;
+const MAX_SPRITES_PER_SCANLINE: usize = 8;
+/// The console has 2KiB of nametable RAM. Four-screen boards supply the other 2KiB
+const VRAM_SIZE: usize = 0x1000;
+const PALETTE_SIZE: usize = 32;
+
+const PATTERN_TABLES_END: u16 = 0x1FFF;
+const NAMETABLES_START: u16 = 0x2000;
+const NAMETABLE_SIZE: u16 = 0x0400;
+const PALETTE_START: u16 = 0x3F00;
+
+/// The CPU-facing registers, by their offset from $2000
+const PPUCTRL: usize = 0;
+const PPUMASK: usize = 1;
+const PPUSTATUS: usize = 2;
+const OAMADDR: usize = 3;
+const OAMDATA: usize = 4;
+const PPUSCROLL: usize = 5;
+const PPUADDR: usize = 6;
+const PPUDATA: usize = 7;
+
+const CTRL_INCREMENT_32: u8 = 0b00000100;
+const CTRL_SPRITE_PATTERN_TABLE: u8 = 0b00001000;
+const CTRL_BACKGROUND_PATTERN_TABLE: u8 = 0b00010000;
+const CTRL_TALL_SPRITES: u8 = 0b00100000;
+const CTRL_NMI_ENABLE: u8 = 0b10000000;
+
+const MASK_GREYSCALE: u8 = 0b00000001;
+const MASK_SHOW_BACKGROUND_LEFT: u8 = 0b00000010;
+const MASK_SHOW_SPRITES_LEFT: u8 = 0b00000100;
+const MASK_SHOW_BACKGROUND: u8 = 0b00001000;
+const MASK_SHOW_SPRITES: u8 = 0b00010000;
+
+const STATUS_SPRITE_OVERFLOW: u8 = 0b00100000;
+const STATUS_SPRITE_ZERO_HIT: u8 = 0b01000000;
+const STATUS_VBLANK: u8 = 0b10000000;
+
+const SPRITE_PALETTE: u8 = 0b00000011;
+const SPRITE_BEHIND_BACKGROUND: u8 = 0b00100000;
+const SPRITE_FLIP_HORIZONTAL: u8 = 0b01000000;
+const SPRITE_FLIP_VERTICAL: u8 = 0b10000000;
+
+/// The parts of the loopy v and t registers. Ref: https://www.nesdev.org/wiki/PPU_scrolling
+const COARSE_X: u16 = 0b000000000011111;
+const COARSE_Y: u16 = 0b000001111100000;
+const NAMETABLE_X: u16 = 0b000010000000000;
+const NAMETABLE_Y: u16 = 0b000100000000000;
+const FINE_Y: u16 = 0b111000000000000;
+
+/// A sprite selected for the scanline being drawn, with its pattern row already fetched. Flipping is applied
+/// when the pattern is fetched, so bit 7 is always the leftmost pixel
+#[derive(Clone, Copy, Default)]
+struct Sprite {
+    x: u8,
+    attributes: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+    is_sprite_zero: bool
+}
+
+/// The 2C02 picture processing unit. It's clocked one dot at a time, three dots per CPU cycle, and draws
+/// into a framebuffer of palette indices. The pattern tables and nametable mirroring belong to the
+/// cartridge, so anything that touches the PPU's address space takes the mapper.
+/// Ref: https://www.nesdev.org/wiki/PPU
+pub struct Ppu {
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_address: u8,
+    oam: [u8; OAM_SIZE],
+    /// The loopy registers: the current VRAM address, the temporary VRAM address, fine X scroll and the
+    /// write toggle shared by PPUSCROLL and PPUADDR
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+    /// PPUDATA reads from below the palettes return the previous read's value
+    read_buffer: u8,
+    /// The PPU's data bus decays slowly, so reading write-only registers returns the last value written
+    io_latch: u8,
+    /// Reading PPUSTATUS just as vblank starts stops the flag being set, and the NMI with it, for that frame
+    suppress_vblank: bool,
+    vram: [u8; VRAM_SIZE],
+    palette: [u8; PALETTE_SIZE],
+    scanline: u16,
+    dot: u16,
+    frame: u64,
+    odd_frame: bool,
+    /// The background pipeline. The next tile is fetched over eight dots, then loaded into the low byte of
+    /// the shift registers, which are shifted once per dot
+    next_tile_id: u8,
+    next_tile_attribute: u8,
+    next_tile_lo: u8,
+    next_tile_hi: u8,
+    pattern_lo_shifter: u16,
+    pattern_hi_shifter: u16,
+    attribute_lo_shifter: u16,
+    attribute_hi_shifter: u16,
+    /// Sprite evaluation on one scanline fills secondary OAM with the sprites for the next, whose patterns
+    /// are then fetched at the end of the scanline
+    secondary_oam: [u8; MAX_SPRITES_PER_SCANLINE * 4],
+    secondary_sprite_count: usize,
+    secondary_has_sprite_zero: bool,
+    next_sprites: [Sprite; MAX_SPRITES_PER_SCANLINE],
+    sprites: [Sprite; MAX_SPRITES_PER_SCANLINE],
+    sprite_count: usize,
+    framebuffer: Vec<u8>
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Self {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_address: 0,
+            oam: [0; OAM_SIZE],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            suppress_vblank: false,
+            vram: [0; VRAM_SIZE],
+            palette: [0; PALETTE_SIZE],
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            odd_frame: false,
+            next_tile_id: 0,
+            next_tile_attribute: 0,
+            next_tile_lo: 0,
+            next_tile_hi: 0,
+            pattern_lo_shifter: 0,
+            pattern_hi_shifter: 0,
+            attribute_lo_shifter: 0,
+            attribute_hi_shifter: 0,
+            secondary_oam: [0xFF; MAX_SPRITES_PER_SCANLINE * 4],
+            secondary_sprite_count: 0,
+            secondary_has_sprite_zero: false,
+            next_sprites: [Sprite::default(); MAX_SPRITES_PER_SCANLINE],
+            sprites: [Sprite::default(); MAX_SPRITES_PER_SCANLINE],
+            sprite_count: 0,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT]
+        }
+    }
+
+    /// The scanline and dot that will be drawn on the next tick
+    pub fn position(&self) -> (u16, u16) {
+        (self.scanline, self.dot)
+    }
+
+    /// How many frames have been completed. This goes up as vertical blank starts
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The picture, as 256x240 indices into the 64 colour palette, row by row
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// The PPU pulls the CPU's NMI line low for as long as vblank is flagged and NMIs are enabled
+    pub(crate) fn nmi_asserted(&self) -> bool {
+        self.status & STATUS_VBLANK != 0 && self.ctrl & CTRL_NMI_ENABLE != 0
+    }
+
+    fn is_rendering_enabled(&self) -> bool {
+        self.mask & (MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES) != 0
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_TALL_SPRITES != 0 { 16 } else { 8 }
+    }
+
+    fn vram_increment(&self) -> u16 {
+        if self.ctrl & CTRL_INCREMENT_32 != 0 { 32 } else { 1 }
+    }
+
+    /// Reads a register from the CPU side, with all its side effects
+    pub(crate) fn read_register(&mut self, register: usize, mapper: &mut dyn Mapper) -> u8 {
+        match register {
+            PPUSTATUS => {
+                let value = self.peek_register(register);
+                // Reading on the dot vblank would be flagged stops it happening
+                if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+                    self.suppress_vblank = true;
+                }
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+                self.io_latch = value;
+            },
+            OAMDATA => self.io_latch = self.oam[self.oam_address as usize],
+            PPUDATA => {
+                let address = self.v & 0x3FFF;
+                if address >= PALETTE_START {
+                    // Palette reads aren't buffered, but the nametable byte underneath is still put in the buffer.
+                    // The top two bits come from the decayed bus
+                    self.read_buffer = self.read_memory(address - 0x1000, mapper);
+                    self.io_latch = (self.io_latch & 0b11000000) | self.read_palette(address);
+                } else {
+                    self.io_latch = self.read_buffer;
+                    self.read_buffer = self.read_memory(address, mapper);
+                }
+                self.increment_vram_address();
+            },
+            // The rest are write-only
+            _ => ()
+        }
+        self.io_latch
+    }
+
+    /// What a read of the register would return, without any side effects
+    pub(crate) fn peek_register(&self, register: usize) -> u8 {
+        match register {
+            PPUSTATUS => self.status | (self.io_latch & 0b00011111),
+            OAMDATA => self.oam[self.oam_address as usize],
+            PPUDATA => {
+                let address = self.v & 0x3FFF;
+                if address >= PALETTE_START { self.read_palette(address) } else { self.read_buffer }
+            },
+            _ => self.io_latch
+        }
+    }
+
+    pub(crate) fn write_register(&mut self, register: usize, value: u8, mapper: &mut dyn Mapper) {
+        self.io_latch = value;
+        match register {
+            PPUCTRL => {
+                self.ctrl = value;
+                self.t = (self.t & !(NAMETABLE_X | NAMETABLE_Y)) | ((value as u16 & 0b11) << 10);
+            },
+            PPUMASK => self.mask = value,
+            OAMADDR => self.oam_address = value,
+            OAMDATA => self.write_oam(value),
+            PPUSCROLL => {
+                if !self.w {
+                    self.t = (self.t & !COARSE_X) | (value as u16 >> 3);
+                    self.x = value & 0b111;
+                } else {
+                    self.t = (self.t & !(COARSE_Y | FINE_Y)) | ((value as u16 >> 3) << 5) | ((value as u16 & 0b111) << 12);
+                }
+                self.w = !self.w;
+            },
+            PPUADDR => {
+                if !self.w {
+                    // Only 14 bits of address. Bit 14 of t is cleared too
+                    self.t = (self.t & 0x00FF) | ((value as u16 & 0b00111111) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v = self.t;
+                    // Outside rendering the bus holds v, which some games use to clock the mapper
+                    mapper.ppu_bus_access(self.v & 0x3FFF);
+                }
+                self.w = !self.w;
+            },
+            PPUDATA => {
+                self.write_memory(self.v & 0x3FFF, value, mapper);
+                self.increment_vram_address();
+            },
+            // PPUSTATUS is read-only
+            _ => ()
+        }
+    }
+
+    pub fn peek_oam(&self, address: u8) -> u8 {
+        self.oam[address as usize]
+    }
+
+    /// Writes a byte to OAM at OAMADDR and moves on to the next. This is what OAM DMA uses too
+    pub(crate) fn write_oam(&mut self, value: u8) {
+        self.oam[self.oam_address as usize] = value;
+        self.oam_address = self.oam_address.wrapping_add(1);
+    }
+
+    fn increment_vram_address(&mut self) {
+        // Accessing PPUDATA while rendering glitches the scroll increments instead
+        if self.is_rendering_enabled() && (self.scanline < SCREEN_HEIGHT as u16 || self.scanline == PRE_RENDER_SCANLINE) {
+            self.increment_coarse_x();
+            self.increment_y();
+        } else {
+            self.v = self.v.wrapping_add(self.vram_increment()) & 0x7FFF;
+        }
+    }
+
+    /// Where a nametable address ends up in VRAM, following the cartridge's mirroring
+    fn nametable_index(address: u16, mirroring: Mirroring) -> usize {
+        let address = (address - NAMETABLES_START) & 0x0FFF;
+        let table = address / NAMETABLE_SIZE;
+        let offset = address % NAMETABLE_SIZE;
+        let physical_table = match mirroring {
+            Mirroring::Vertical => table & 0b01,
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::FourScreen => table,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1
+        };
+        (physical_table * NAMETABLE_SIZE + offset) as usize
+    }
+
+    /// The backdrop entries of the sprite palettes are mirrors of the background palettes'
+    fn palette_index(address: u16) -> usize {
+        let index = address as usize % PALETTE_SIZE;
+        if index & 0b10011 == 0b10000 { index & !0b10000 } else { index }
+    }
+
+    fn read_palette(&self, address: u16) -> u8 {
+        let colour = self.palette[Self::palette_index(address)] & 0b00111111;
+        if self.mask & MASK_GREYSCALE != 0 { colour & 0b00110000 } else { colour }
+    }
+
+    /// Reads from the PPU's own 14-bit address space
+    pub(crate) fn peek_memory(&self, address: u16, mapper: &dyn Mapper) -> u8 {
+        let address = address & 0x3FFF;
+        match address {
+            0..=PATTERN_TABLES_END => mapper.peek_chr(address),
+            NAMETABLES_START..=0x3EFF => self.vram[Self::nametable_index(address, mapper.mirroring())],
+            _ => self.palette[Self::palette_index(address)]
+        }
+    }
+
+    fn read_memory(&mut self, address: u16, mapper: &mut dyn Mapper) -> u8 {
+        let address = address & 0x3FFF;
+        mapper.ppu_bus_access(address);
+        match address {
+            0..=PATTERN_TABLES_END => mapper.read_chr(address),
+            _ => self.peek_memory(address, mapper)
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, mapper: &mut dyn Mapper) {
+        let address = address & 0x3FFF;
+        mapper.ppu_bus_access(address);
+        match address {
+            0..=PATTERN_TABLES_END => mapper.write_chr(address, value),
+            NAMETABLES_START..=0x3EFF => self.vram[Self::nametable_index(address, mapper.mirroring())] = value,
+            _ => self.palette[Self::palette_index(address)] = value
+        }
+    }
+
+    /// Advances the PPU by one dot
+    pub(crate) fn tick(&mut self, mapper: &mut dyn Mapper) {
+        let visible_scanline = self.scanline < SCREEN_HEIGHT as u16;
+        let pre_render_scanline = self.scanline == PRE_RENDER_SCANLINE;
+
+        if (visible_scanline || pre_render_scanline) && self.is_rendering_enabled() {
+            self.run_background_pipeline(pre_render_scanline, mapper);
+            self.run_sprite_pipeline(pre_render_scanline, mapper);
+        }
+
+        if visible_scanline && (1..=SCREEN_WIDTH as u16).contains(&self.dot) {
+            self.render_pixel();
+        }
+
+        if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+            if !self.suppress_vblank {
+                self.status |= STATUS_VBLANK;
+            }
+            self.suppress_vblank = false;
+            self.frame += 1;
+        }
+        if pre_render_scanline && self.dot == 1 {
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW);
+        }
+
+        self.advance_dot();
+    }
+
+    fn advance_dot(&mut self) {
+        self.dot += 1;
+        // Odd frames skip the last dot of the pre-render scanline when rendering
+        if self.scanline == PRE_RENDER_SCANLINE && self.dot == DOTS_PER_SCANLINE - 1 && self.odd_frame && self.is_rendering_enabled() {
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    /// Fetches tiles and updates the scroll position. Ref: https://www.nesdev.org/wiki/PPU_rendering
+    fn run_background_pipeline(&mut self, pre_render_scanline: bool, mapper: &mut dyn Mapper) {
+        let dot = self.dot;
+        let fetching = (1..=256).contains(&dot) || (321..=336).contains(&dot);
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.shift_background();
+        }
+        if fetching {
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.next_tile_id = self.read_memory(NAMETABLES_START | (self.v & 0x0FFF), mapper);
+                },
+                2 => {
+                    let address = 0x23C0 | (self.v & (NAMETABLE_X | NAMETABLE_Y)) | ((self.v >> 4) & 0b111000) | ((self.v >> 2) & 0b000111);
+                    let shift = ((self.v >> 4) & 0b100) | (self.v & 0b10);
+                    self.next_tile_attribute = (self.read_memory(address, mapper) >> shift) & 0b11;
+                },
+                4 => self.next_tile_lo = self.read_memory(self.background_pattern_address(), mapper),
+                6 => self.next_tile_hi = self.read_memory(self.background_pattern_address() + 8, mapper),
+                7 => self.increment_coarse_x(),
+                _ => ()
+            }
+        }
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.load_background_shifters();
+                self.v = (self.v & !(COARSE_X | NAMETABLE_X)) | (self.t & (COARSE_X | NAMETABLE_X));
+            },
+            280..=304 if pre_render_scanline => {
+                self.v = (self.v & !(COARSE_Y | NAMETABLE_Y | FINE_Y)) | (self.t & (COARSE_Y | NAMETABLE_Y | FINE_Y));
+            },
+            // Unused nametable fetches at the end of the scanline
+            337 | 339 => { self.read_memory(NAMETABLES_START | (self.v & 0x0FFF), mapper); },
+            _ => ()
+        }
+    }
+
+    fn background_pattern_address(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BACKGROUND_PATTERN_TABLE != 0 { 0x1000 } else { 0 };
+        table + self.next_tile_id as u16 * 16 + ((self.v & FINE_Y) >> 12)
+    }
+
+    fn shift_background(&mut self) {
+        self.pattern_lo_shifter <<= 1;
+        self.pattern_hi_shifter <<= 1;
+        self.attribute_lo_shifter <<= 1;
+        self.attribute_hi_shifter <<= 1;
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.pattern_lo_shifter = (self.pattern_lo_shifter & 0xFF00) | self.next_tile_lo as u16;
+        self.pattern_hi_shifter = (self.pattern_hi_shifter & 0xFF00) | self.next_tile_hi as u16;
+        // The attribute applies to the whole tile, so it's spread across all eight bits
+        let attribute_lo = if self.next_tile_attribute & 0b01 != 0 { 0xFF } else { 0x00 };
+        let attribute_hi = if self.next_tile_attribute & 0b10 != 0 { 0xFF } else { 0x00 };
+        self.attribute_lo_shifter = (self.attribute_lo_shifter & 0xFF00) | attribute_lo;
+        self.attribute_hi_shifter = (self.attribute_hi_shifter & 0xFF00) | attribute_hi;
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.v & COARSE_X == COARSE_X {
+            self.v &= !COARSE_X;
+            self.v ^= NAMETABLE_X;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & FINE_Y != FINE_Y {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !FINE_Y;
+        let mut coarse_y = (self.v & COARSE_Y) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= NAMETABLE_Y;
+        } else if coarse_y == 31 {
+            // Coarse Y can be set out of bounds, in which case it wraps without switching nametables
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !COARSE_Y) | (coarse_y << 5);
+    }
+
+    /// Evaluates the sprites for the next scanline and fetches their patterns. The real PPU spreads evaluation
+    /// across dots 65-256, but its result is only used from dot 257, so we do it all at once
+    fn run_sprite_pipeline(&mut self, pre_render_scanline: bool, mapper: &mut dyn Mapper) {
+        match self.dot {
+            257 => {
+                if pre_render_scanline {
+                    // Sprites are never drawn on the first scanline
+                    self.secondary_sprite_count = 0;
+                    self.secondary_has_sprite_zero = false;
+                } else {
+                    self.evaluate_sprites();
+                }
+                self.oam_address = 0;
+            },
+            258..=320 => {
+                self.oam_address = 0;
+                let slot = (self.dot - 257) as usize / 8;
+                match (self.dot - 257) % 8 {
+                    4 => self.next_sprites[slot].pattern_lo = self.fetch_sprite_pattern(slot, 0, mapper),
+                    6 => self.next_sprites[slot].pattern_hi = self.fetch_sprite_pattern(slot, 8, mapper),
+                    _ => ()
+                }
+                if self.dot == 320 {
+                    self.sprites = self.next_sprites;
+                    self.sprite_count = self.secondary_sprite_count;
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn evaluate_sprites(&mut self) {
+        let height = self.sprite_height() as i32;
+        let scanline = self.scanline as i32;
+        let in_range = |y: u8| (0..height).contains(&(scanline - y as i32));
+
+        self.secondary_oam = [0xFF; MAX_SPRITES_PER_SCANLINE * 4];
+        self.secondary_sprite_count = 0;
+        self.secondary_has_sprite_zero = false;
+        let mut sprite = 0;
+        while sprite < OAM_SIZE / 4 && self.secondary_sprite_count < MAX_SPRITES_PER_SCANLINE {
+            let entry = sprite * 4;
+            if in_range(self.oam[entry]) {
+                let slot = self.secondary_sprite_count * 4;
+                self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[entry..entry + 4]);
+                self.secondary_has_sprite_zero |= sprite == 0;
+                self.secondary_sprite_count += 1;
+            }
+            sprite += 1;
+        }
+
+        // Once eight sprites are found the PPU keeps looking for a ninth, but a hardware bug means it steps
+        // through the bytes of each entry diagonally rather than always checking the Y coordinate
+        let mut byte = 0;
+        while sprite < OAM_SIZE / 4 {
+            if in_range(self.oam[sprite * 4 + byte]) {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            sprite += 1;
+            byte = (byte + 1) % 4;
+        }
+    }
+
+    /// Fetches one plane of the pattern row for a sprite slot. Empty slots still fetch tile $FF, which matters
+    /// to mappers watching the PPU address bus
+    fn fetch_sprite_pattern(&mut self, slot: usize, plane_offset: u16, mapper: &mut dyn Mapper) -> u8 {
+        let entry = &self.secondary_oam[slot * 4..slot * 4 + 4];
+        let (y, tile, attributes, x) = (entry[0], entry[1], entry[2], entry[3]);
+        let is_empty = slot >= self.secondary_sprite_count;
+        let height = self.sprite_height();
+        let mut row = if is_empty { 0 } else { self.scanline.wrapping_sub(y as u16) % height };
+        if attributes & SPRITE_FLIP_VERTICAL != 0 && !is_empty {
+            row = height - 1 - row;
+        }
+
+        let tile_address = if height == 16 {
+            // Tall sprites take their pattern table from bit 0 of the tile, and use a pair of tiles
+            let table = (tile as u16 & 1) * 0x1000;
+            let top_tile = tile as u16 & 0xFE;
+            table + (top_tile + row / 8) * 16 + row % 8
+        } else {
+            let table = if self.ctrl & CTRL_SPRITE_PATTERN_TABLE != 0 { 0x1000 } else { 0 };
+            table + tile as u16 * 16 + row
+        };
+        let mut pattern = self.read_memory(tile_address + plane_offset, mapper);
+        if is_empty {
+            return 0;
+        }
+        if attributes & SPRITE_FLIP_HORIZONTAL != 0 {
+            pattern = pattern.reverse_bits();
+        }
+
+        self.next_sprites[slot].x = x;
+        self.next_sprites[slot].attributes = attributes;
+        self.next_sprites[slot].is_sprite_zero = slot == 0 && self.secondary_has_sprite_zero;
+        pattern
+    }
+
+    fn render_pixel(&mut self) {
+        let x = (self.dot - 1) as usize;
+
+        let (mut background_pixel, mut background_palette) = (0, 0);
+        if self.mask & MASK_SHOW_BACKGROUND != 0 && (x >= 8 || self.mask & MASK_SHOW_BACKGROUND_LEFT != 0) {
+            let bit = 0x8000 >> self.x;
+            background_pixel = ((self.pattern_hi_shifter & bit != 0) as u8) << 1 | (self.pattern_lo_shifter & bit != 0) as u8;
+            background_palette = ((self.attribute_hi_shifter & bit != 0) as u8) << 1 | (self.attribute_lo_shifter & bit != 0) as u8;
+        }
+
+        let (mut sprite_pixel, mut sprite_palette, mut sprite_behind_background) = (0, 0, false);
+        if self.mask & MASK_SHOW_SPRITES != 0 && (x >= 8 || self.mask & MASK_SHOW_SPRITES_LEFT != 0) {
+            // Earlier sprites in OAM take priority
+            for sprite in &self.sprites[..self.sprite_count] {
+                let offset = x.wrapping_sub(sprite.x as usize);
+                if offset >= 8 {
+                    continue;
+                }
+                let bit = 7 - offset;
+                let pixel = ((sprite.pattern_hi >> bit) & 1) << 1 | ((sprite.pattern_lo >> bit) & 1);
+                if pixel == 0 {
+                    continue;
+                }
+                if sprite.is_sprite_zero && background_pixel != 0 && x != SCREEN_WIDTH - 1 {
+                    self.status |= STATUS_SPRITE_ZERO_HIT;
+                }
+                sprite_pixel = pixel;
+                sprite_palette = (sprite.attributes & SPRITE_PALETTE) + 4;
+                sprite_behind_background = sprite.attributes & SPRITE_BEHIND_BACKGROUND != 0;
+                break;
+            }
+        }
+
+        let palette_address = match (background_pixel, sprite_pixel) {
+            (0, 0) => {
+                // With rendering off, the backdrop comes from the palette entry v points at, if it points at one
+                if !self.is_rendering_enabled() && self.v & 0x3FFF >= PALETTE_START {
+                    self.v
+                } else {
+                    PALETTE_START
+                }
+            },
+            (0, _) => PALETTE_START + sprite_palette as u16 * 4 + sprite_pixel as u16,
+            (_, 0) => PALETTE_START + background_palette as u16 * 4 + background_pixel as u16,
+            _ if sprite_behind_background => PALETTE_START + background_palette as u16 * 4 + background_pixel as u16,
+            _ => PALETTE_START + sprite_palette as u16 * 4 + sprite_pixel as u16
+        };
+        self.framebuffer[self.scanline as usize * SCREEN_WIDTH + x] = self.read_palette(palette_address);
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot for Sprite {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.x);
+        state.write_u8(self.attributes);
+        state.write_u8(self.pattern_lo);
+        state.write_u8(self.pattern_hi);
+        state.write_bool(self.is_sprite_zero);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.x = state.read_u8()?;
+        self.attributes = state.read_u8()?;
+        self.pattern_lo = state.read_u8()?;
+        self.pattern_hi = state.read_u8()?;
+        self.is_sprite_zero = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.ctrl);
+        state.write_u8(self.mask);
+        state.write_u8(self.status);
+        state.write_u8(self.oam_address);
+        state.write_bytes(&self.oam);
+        state.write_u16(self.v);
+        state.write_u16(self.t);
+        state.write_u8(self.x);
+        state.write_bool(self.w);
+        state.write_u8(self.read_buffer);
+        state.write_u8(self.io_latch);
+        state.write_bool(self.suppress_vblank);
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.palette);
+        state.write_u16(self.scanline);
+        state.write_u16(self.dot);
+        state.write_u64(self.frame);
+        state.write_bool(self.odd_frame);
+        state.write_u8(self.next_tile_id);
+        state.write_u8(self.next_tile_attribute);
+        state.write_u8(self.next_tile_lo);
+        state.write_u8(self.next_tile_hi);
+        state.write_u16(self.pattern_lo_shifter);
+        state.write_u16(self.pattern_hi_shifter);
+        state.write_u16(self.attribute_lo_shifter);
+        state.write_u16(self.attribute_hi_shifter);
+        state.write_bytes(&self.secondary_oam);
+        state.write_u8(self.secondary_sprite_count as u8);
+        state.write_bool(self.secondary_has_sprite_zero);
+        for sprite in self.next_sprites.iter().chain(&self.sprites) {
+            sprite.save_state(state);
+        }
+        state.write_u8(self.sprite_count as u8);
+        // The frame on screen is part of the state too, so a loaded state shows the right picture straight away
+        state.write_bytes(&self.framebuffer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ctrl = state.read_u8()?;
+        self.mask = state.read_u8()?;
+        self.status = state.read_u8()?;
+        self.oam_address = state.read_u8()?;
+        state.read_bytes_into(&mut self.oam)?;
+        self.v = state.read_u16()?;
+        self.t = state.read_u16()?;
+        self.x = state.read_u8()?;
+        self.w = state.read_bool()?;
+        self.read_buffer = state.read_u8()?;
+        self.io_latch = state.read_u8()?;
+        self.suppress_vblank = state.read_bool()?;
+        state.read_bytes_into(&mut self.vram)?;
+        state.read_bytes_into(&mut self.palette)?;
+        self.scanline = state.read_u16()? % SCANLINES_PER_FRAME;
+        self.dot = state.read_u16()? % DOTS_PER_SCANLINE;
+        self.frame = state.read_u64()?;
+        self.odd_frame = state.read_bool()?;
+        self.next_tile_id = state.read_u8()?;
+        self.next_tile_attribute = state.read_u8()?;
+        self.next_tile_lo = state.read_u8()?;
+        self.next_tile_hi = state.read_u8()?;
+        self.pattern_lo_shifter = state.read_u16()?;
+        self.pattern_hi_shifter = state.read_u16()?;
+        self.attribute_lo_shifter = state.read_u16()?;
+        self.attribute_hi_shifter = state.read_u16()?;
+        state.read_bytes_into(&mut self.secondary_oam)?;
+        self.secondary_sprite_count = (state.read_u8()? as usize).min(MAX_SPRITES_PER_SCANLINE);
+        self.secondary_has_sprite_zero = state.read_bool()?;
+        for sprite in self.next_sprites.iter_mut().chain(&mut self.sprites) {
+            sprite.load_state(state)?;
+        }
+        self.sprite_count = (state.read_u8()? as usize).min(MAX_SPRITES_PER_SCANLINE);
+        state.read_bytes_into(&mut self.framebuffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{numbered_banks, test_cartridge, Nrom};
+
+    /// A PPU plugged into an NROM board with CHR-RAM
+    struct TestPpu {
+        ppu: Ppu,
+        mapper: Nrom
+    }
+
+    impl TestPpu {
+        fn new() -> Self {
+            Self::with_mirroring(Mirroring::Horizontal)
+        }
+
+        fn with_mirroring(mirroring: Mirroring) -> Self {
+            let mut cartridge = test_cartridge(0, numbered_banks(1, 0x4000), vec![]);
+            cartridge.mirroring = mirroring;
+            Self { ppu: Ppu::new(), mapper: Nrom::new(&cartridge) }
+        }
+
+        fn read_register(&mut self, register: usize) -> u8 {
+            self.ppu.read_register(register, &mut self.mapper)
+        }
+
+        fn peek_register(&self, register: usize) -> u8 {
+            self.ppu.peek_register(register)
+        }
+
+        fn write_register(&mut self, register: usize, value: u8) {
+            self.ppu.write_register(register, value, &mut self.mapper);
+        }
+
+        fn peek_memory(&self, address: u16) -> u8 {
+            self.ppu.peek_memory(address, &self.mapper)
+        }
+
+        fn tick(&mut self) {
+            self.ppu.tick(&mut self.mapper);
+        }
+    }
+
+    impl std::ops::Deref for TestPpu {
+        type Target = Ppu;
+
+        fn deref(&self) -> &Ppu {
+            &self.ppu
+        }
+    }
+
+    fn set_vram_address(ppu: &mut TestPpu, address: u16) {
+        ppu.write_register(PPUADDR, (address >> 8) as u8);
+        ppu.write_register(PPUADDR, address as u8);
+    }
+
+    fn run_to(ppu: &mut TestPpu, scanline: u16, dot: u16) {
+        while ppu.position() != (scanline, dot) {
+            ppu.tick();
+        }
+    }
+
+    #[test]
+    fn test_ppudata_reads_are_buffered() {
+        let mut ppu = TestPpu::new();
+        set_vram_address(&mut ppu, 0x2000);
+        ppu.write_register(PPUDATA, 0x11);
+        ppu.write_register(PPUDATA, 0x22);
+        set_vram_address(&mut ppu, 0x2000);
+        // The first read returns the stale buffer
+        ppu.read_register(PPUDATA);
+        assert_eq!(ppu.read_register(PPUDATA), 0x11);
+        assert_eq!(ppu.read_register(PPUDATA), 0x22);
+
+        // Palette reads aren't buffered
+        set_vram_address(&mut ppu, 0x3F01);
+        ppu.write_register(PPUDATA, 0x2A);
+        set_vram_address(&mut ppu, 0x3F01);
+        assert_eq!(ppu.read_register(PPUDATA) & 0b00111111, 0x2A);
+    }
+
+    #[test]
+    fn test_vram_increment_of_32() {
+        let mut ppu = TestPpu::new();
+        ppu.write_register(PPUCTRL, CTRL_INCREMENT_32);
+        set_vram_address(&mut ppu, 0x2000);
+        ppu.write_register(PPUDATA, 0x01);
+        ppu.write_register(PPUDATA, 0x02);
+        assert_eq!(ppu.peek_memory(0x2000), 0x01);
+        assert_eq!(ppu.peek_memory(0x2020), 0x02);
+    }
+
+    #[test]
+    fn test_nametable_and_palette_mirroring() {
+        let mut ppu = TestPpu::with_mirroring(Mirroring::Vertical);
+        set_vram_address(&mut ppu, 0x2405);
+        ppu.write_register(PPUDATA, 0x42);
+        assert_eq!(ppu.peek_memory(0x2C05), 0x42);
+        assert_eq!(ppu.peek_memory(0x2005), 0x00);
+
+        let mut ppu = TestPpu::with_mirroring(Mirroring::Horizontal);
+        set_vram_address(&mut ppu, 0x2405);
+        ppu.write_register(PPUDATA, 0x42);
+        assert_eq!(ppu.peek_memory(0x2005), 0x42);
+        assert_eq!(ppu.peek_memory(0x2C05), 0x00);
+
+        set_vram_address(&mut ppu, 0x3F10);
+        ppu.write_register(PPUDATA, 0x0F);
+        assert_eq!(ppu.peek_memory(0x3F00), 0x0F);
+    }
+
+    #[test]
+    fn test_scroll_writes_update_loopy_registers() {
+        let mut ppu = TestPpu::new();
+        ppu.write_register(PPUCTRL, 0b10);
+        ppu.write_register(PPUSCROLL, 0b01111101);
+        ppu.write_register(PPUSCROLL, 0b01011110);
+        assert_eq!(ppu.t, 0b110100101101111);
+        assert_eq!(ppu.x, 0b101);
+        // Reading PPUSTATUS resets the write toggle
+        ppu.write_register(PPUADDR, 0x3D);
+        ppu.read_register(PPUSTATUS);
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn test_vblank_and_nmi() {
+        let mut ppu = TestPpu::new();
+        ppu.write_register(PPUCTRL, CTRL_NMI_ENABLE);
+        run_to(&mut ppu, VBLANK_SCANLINE, 2);
+        assert!(ppu.nmi_asserted());
+        assert_eq!(ppu.frame(), 1);
+        assert_eq!(ppu.read_register(PPUSTATUS) & STATUS_VBLANK, STATUS_VBLANK);
+        assert_eq!(ppu.read_register(PPUSTATUS) & STATUS_VBLANK, 0);
+        assert!(!ppu.nmi_asserted());
+
+        // Reading PPUSTATUS just before vblank suppresses it for the frame
+        run_to(&mut ppu, VBLANK_SCANLINE, 1);
+        ppu.read_register(PPUSTATUS);
+        ppu.tick();
+        assert!(!ppu.nmi_asserted());
+    }
+
+    /// A PPU with CHR-RAM where tile 1 is solid colour 1, and a second, solid colour 3, tile 2
+    fn ppu_with_tiles() -> TestPpu {
+        let mut ppu = TestPpu::new();
+        set_vram_address(&mut ppu, 0x0010);
+        for _ in 0..8 { ppu.write_register(PPUDATA, 0xFF) }
+        for _ in 0..8 { ppu.write_register(PPUDATA, 0x00) }
+        for _ in 0..16 { ppu.write_register(PPUDATA, 0xFF) }
+        set_vram_address(&mut ppu, 0x3F00);
+        for colour in [0x0F, 0x01, 0x02, 0x03] { ppu.write_register(PPUDATA, colour) }
+        set_vram_address(&mut ppu, 0x3F10);
+        for colour in [0x0F, 0x11, 0x12, 0x13] { ppu.write_register(PPUDATA, colour) }
+        ppu
+    }
+
+    #[test]
+    fn test_background_rendering() {
+        let mut ppu = ppu_with_tiles();
+        // Put tile 1 at the second tile of the top row, and scroll right by 4 pixels
+        set_vram_address(&mut ppu, 0x2001);
+        ppu.write_register(PPUDATA, 0x01);
+        ppu.read_register(PPUSTATUS);
+        ppu.write_register(PPUSCROLL, 4);
+        ppu.write_register(PPUSCROLL, 0);
+        ppu.write_register(PPUCTRL, 0);
+        ppu.write_register(PPUMASK, MASK_SHOW_BACKGROUND | MASK_SHOW_BACKGROUND_LEFT);
+
+        // Let the pre-render scanline prime the pipeline, then draw the first scanline
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 0);
+        run_to(&mut ppu, 1, 0);
+        let row = &ppu.framebuffer()[..16];
+        assert_eq!(row, [0x0F, 0x0F, 0x0F, 0x0F, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x0F, 0x0F, 0x0F, 0x0F]);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_and_priority() {
+        let mut ppu = ppu_with_tiles();
+        // Background tile 1 at the top left, sprite 0 over it and sprite 1 (solid colour 3) overlapping sprite 0
+        set_vram_address(&mut ppu, 0x2000);
+        ppu.write_register(PPUDATA, 0x01);
+        ppu.write_register(OAMADDR, 0);
+        for byte in [0x00, 0x01, 0x00, 0x04, 0x00, 0x02, 0x00, 0x08] { ppu.write_register(OAMDATA, byte) }
+        set_vram_address(&mut ppu, 0x0000);
+        ppu.write_register(PPUMASK, MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES | MASK_SHOW_BACKGROUND_LEFT | MASK_SHOW_SPRITES_LEFT);
+
+        run_to(&mut ppu, 2, 0);
+        assert_eq!(ppu.peek_register(PPUSTATUS) & STATUS_SPRITE_ZERO_HIT, STATUS_SPRITE_ZERO_HIT);
+        // Sprites with a Y of 0 start on the second scanline
+        let row = &ppu.framebuffer()[SCREEN_WIDTH..SCREEN_WIDTH + 16];
+        assert_eq!(row, [0x01, 0x01, 0x01, 0x01, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x13, 0x13, 0x13, 0x13]);
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = TestPpu::new();
+        ppu.write_register(OAMADDR, 0);
+        for sprite in 0..64 {
+            // Nine sprites on scanline 10, the rest off screen
+            let y = if sprite < 9 { 10 } else { 0xF0 };
+            for byte in [y, 0, 0, 0] { ppu.write_register(OAMDATA, byte) }
+        }
+        ppu.write_register(PPUMASK, MASK_SHOW_SPRITES);
+        run_to(&mut ppu, 10, 0);
+        assert_eq!(ppu.peek_register(PPUSTATUS) & STATUS_SPRITE_OVERFLOW, 0);
+        run_to(&mut ppu, 11, 0);
+        assert_eq!(ppu.peek_register(PPUSTATUS) & STATUS_SPRITE_OVERFLOW, STATUS_SPRITE_OVERFLOW);
+    }
+}
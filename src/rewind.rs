@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+
+/// One keyframe a second at 60 frames a second
+pub const DEFAULT_KEYFRAME_INTERVAL: usize = 60;
+
+/// A run of frames stored as a full save state, followed by each later frame's changes from it
+struct Group {
+    keyframe: Vec<u8>,
+    deltas: Vec<Vec<u8>>
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        self.keyframe.len() + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+/// A ring buffer of save states, one per frame, for stepping backwards. Most frames only change a little of
+/// the machine, so every state but the keyframes is stored as its differences from the last keyframe. Once
+/// the buffer goes over its memory budget the oldest keyframe is dropped, along with the frames that depend
+/// on it
+pub struct RewindBuffer {
+    memory_budget: usize,
+    keyframe_interval: usize,
+    groups: VecDeque<Group>,
+    memory_used: usize
+}
+
+impl RewindBuffer {
+    pub fn new(memory_budget: usize) -> Self {
+        Self::with_keyframe_interval(memory_budget, DEFAULT_KEYFRAME_INTERVAL)
+    }
+
+    /// Keyframes further apart save memory while frames are similar, but make each delta larger as the
+    /// machine drifts from its keyframe
+    pub fn with_keyframe_interval(memory_budget: usize, keyframe_interval: usize) -> Self {
+        Self {
+            memory_budget,
+            keyframe_interval: keyframe_interval.max(1),
+            groups: VecDeque::new(),
+            memory_used: 0
+        }
+    }
+
+    /// How many frames can be stepped back through
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|group| 1 + group.deltas.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// The bytes taken by the stored states. This may go over the budget when a single keyframe's group
+    /// doesn't fit in it, as the newest frames are always kept
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    /// Adds the state of the frame just run
+    pub fn push(&mut self, state: &[u8]) {
+        let delta = self.groups.back()
+            .filter(|group| group.deltas.len() + 1 < self.keyframe_interval && group.keyframe.len() == state.len())
+            .map(|group| encode_delta(&group.keyframe, state));
+        match delta {
+            Some(delta) => {
+                self.memory_used += delta.len();
+                self.groups.back_mut().unwrap().deltas.push(delta);
+            },
+            None => {
+                self.memory_used += state.len();
+                self.groups.push_back(Group { keyframe: state.to_vec(), deltas: Vec::new() });
+            }
+        }
+
+        while self.memory_used > self.memory_budget && self.groups.len() > 1 {
+            let oldest = self.groups.pop_front().unwrap();
+            self.memory_used -= oldest.size();
+        }
+    }
+
+    /// Removes and returns the most recent state
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let group = self.groups.back_mut()?;
+        match group.deltas.pop() {
+            Some(delta) => {
+                self.memory_used -= delta.len();
+                Some(decode_delta(&group.keyframe, &delta))
+            },
+            None => {
+                let group = self.groups.pop_back().unwrap();
+                self.memory_used -= group.keyframe.len();
+                Some(group.keyframe)
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.memory_used = 0;
+    }
+}
+
+/// Deltas are a series of runs: the number of bytes unchanged from the keyframe, then the number of changed
+/// bytes and the bytes themselves. The counts are LEB128 varints, so short runs take a byte each
+fn encode_delta(keyframe: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut position = 0;
+    while position < state.len() {
+        let unchanged = keyframe[position..].iter().zip(&state[position..]).take_while(|(a, b)| a == b).count();
+        position += unchanged;
+        let changed = keyframe[position..].iter().zip(&state[position..]).take_while(|(a, b)| a != b).count();
+        write_varint(&mut delta, unchanged);
+        write_varint(&mut delta, changed);
+        delta.extend_from_slice(&state[position..position + changed]);
+        position += changed;
+    }
+    delta
+}
+
+fn decode_delta(keyframe: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = Vec::with_capacity(keyframe.len());
+    let mut position = 0;
+    while position < delta.len() {
+        let unchanged = read_varint(delta, &mut position);
+        let changed = read_varint(delta, &mut position);
+        let start = state.len();
+        state.extend_from_slice(&keyframe[start..start + unchanged]);
+        state.extend_from_slice(&delta[position..position + changed]);
+        position += changed;
+    }
+    state
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A large state where only a couple of bytes change each frame, like RAM with a frame counter in it
+    fn frame_state(frame: usize) -> Vec<u8> {
+        let mut state = vec![0xAA; 4096];
+        state[0x10] = frame as u8;
+        state[0x800..0x808].copy_from_slice(&(frame as u64 * 1000).to_le_bytes());
+        state
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let keyframe = frame_state(0);
+        for frame in [0, 1, 300, 1_000_000] {
+            let state = frame_state(frame);
+            let delta = encode_delta(&keyframe, &state);
+            assert!(delta.len() < 32);
+            assert_eq!(decode_delta(&keyframe, &delta), state);
+        }
+    }
+
+    #[test]
+    fn test_steps_back_frame_by_frame() {
+        let mut rewind = RewindBuffer::with_keyframe_interval(1 << 20, 4);
+        for frame in 0..10 {
+            rewind.push(&frame_state(frame));
+        }
+        assert_eq!(rewind.len(), 10);
+        // Three keyframes, and small deltas for everything else
+        assert!(rewind.memory_used() < 4 * 4096);
+        for frame in (0..10).rev() {
+            assert_eq!(rewind.pop(), Some(frame_state(frame)));
+        }
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_used(), 0);
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn test_oldest_frames_are_dropped_to_fit_the_budget() {
+        let mut rewind = RewindBuffer::with_keyframe_interval(3 * 4096, 4);
+        for frame in 0..20 {
+            rewind.push(&frame_state(frame));
+            assert!(rewind.memory_used() <= 3 * 4096);
+        }
+        // Whole groups go at once, so what's left starts with a keyframe
+        assert_eq!(rewind.len(), 8);
+        let mut oldest = None;
+        while let Some(state) = rewind.pop() {
+            oldest = Some(state);
+        }
+        assert_eq!(oldest, Some(frame_state(12)));
+
+        rewind.push(&frame_state(0));
+        rewind.clear();
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn test_size_changes_start_a_keyframe() {
+        let mut rewind = RewindBuffer::new(1 << 20);
+        let mut states = Vec::new();
+        for frame in 0..3 {
+            let state = frame_state(frame);
+            rewind.push(&state);
+            states.push(state);
+        }
+        // A state of a different size, like one from another cartridge, starts a new keyframe
+        rewind.push(&[1, 2, 3]);
+        assert_eq!(rewind.pop(), Some(vec![1, 2, 3]));
+        assert_eq!(rewind.pop(), states.pop());
+    }
+}
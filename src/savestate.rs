@@ -0,0 +1,276 @@
+use std::fmt::Display;
+
+/// Every save state starts with this, followed by the format version
+pub const MAGIC: [u8; 8] = *b"RUSTNES\x1A";
+/// Bumped whenever the layout of any section changes. States from before OLDEST_SUPPORTED_VERSION are
+/// rejected, and ones in between are migrated when they're opened
+pub const VERSION: u16 = 2;
+pub const OLDEST_SUPPORTED_VERSION: u16 = 1;
+
+/// Sections are a four byte tag and a 32-bit length before their contents, so a state can be walked without
+/// knowing what's inside each part
+const SECTION_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    /// The data doesn't start with the save state magic
+    InvalidMagic,
+    /// The state was written by a version of the format that can't be loaded
+    UnsupportedVersion(u16),
+    /// The data ends part way through a value
+    Truncated,
+    /// The sections aren't in the order the machine expects
+    UnexpectedSection { expected: String, found: String },
+    /// The state is well formed but doesn't fit this machine, e.g. it's for a cartridge with a different
+    /// amount of RAM
+    Incompatible(String)
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::InvalidMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} isn't supported, only {} to {}", version, OLDEST_SUPPORTED_VERSION, VERSION)
+            },
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::UnexpectedSection { expected, found } => write!(f, "expected a {:?} section but found {:?}", expected, found),
+            StateError::Incompatible(reason) => write!(f, "save state doesn't fit this machine: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Something whose internal state can be written to and restored from a save state. A bus implements it to
+/// be saved along with the CPU, and a mapper to be saved along with the rest of `NesBus`
+pub trait Snapshot {
+    fn save_state(&self, state: &mut StateWriter);
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// Builds a save state. Values are little-endian, and variable length data is prefixed with its length
+pub struct StateWriter {
+    bytes: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        Self { bytes }
+    }
+
+    /// Writes a tagged section with whatever the closure writes as its contents
+    pub fn section(&mut self, tag: &[u8; 4], write: impl FnOnce(&mut Self)) {
+        self.bytes.extend_from_slice(tag);
+        let length_position = self.bytes.len();
+        self.write_u32(0);
+        write(self);
+        let length = (self.bytes.len() - length_position - 4) as u32;
+        self.bytes[length_position..length_position + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads a save state back in the order it was written
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    version: u16
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header, and returns a reader positioned at the first section
+    pub fn open(bytes: &'a [u8]) -> Result<Self, StateError> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+        let mut reader = Self { bytes, position: MAGIC.len(), version: VERSION };
+        let version = reader.read_u16()?;
+        if !(OLDEST_SUPPORTED_VERSION..=VERSION).contains(&version) {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        reader.version = version;
+        Ok(reader)
+    }
+
+    /// The version the state was written with. Sections whose layout has changed check this to read older
+    /// states
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Reads the next section, which must have the given tag, and checks the closure reads all of it
+    pub fn section<T>(&mut self, tag: &[u8; 4], read: impl FnOnce(&mut StateReader<'a>) -> Result<T, StateError>) -> Result<T, StateError> {
+        let header = self.take(SECTION_HEADER_SIZE)?;
+        if header[..4] != tag[..] {
+            return Err(StateError::UnexpectedSection {
+                expected: String::from_utf8_lossy(tag).into_owned(),
+                found: String::from_utf8_lossy(&header[..4]).into_owned()
+            });
+        }
+        let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let mut contents = StateReader { bytes: self.take(length)?, position: 0, version: self.version };
+        let value = read(&mut contents)?;
+        contents.finish()?;
+        Ok(value)
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self.bytes.get(self.position..self.position + count).ok_or(StateError::Truncated)?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+
+    /// Reads bytes into memory that's fixed in size, like RAM, which must be the size the state has
+    pub fn read_bytes_into(&mut self, destination: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != destination.len() {
+            return Err(StateError::Incompatible(format!("expected {} bytes of memory but found {}", destination.len(), bytes.len())));
+        }
+        destination.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Checks everything has been read. Anything left over means the state was laid out differently
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.position == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(StateError::Incompatible(format!("{} unexpected bytes", self.bytes.len() - self.position)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.section(b"TEST", |state| {
+            state.write_bool(true);
+            state.write_u16(0x1234);
+            state.write_u64(u64::MAX);
+            state.write_f64(-1.5);
+            state.write_bytes(&[1, 2, 3]);
+        });
+        state.finish()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = test_state();
+        assert_eq!(&bytes[..8], b"RUSTNES\x1A");
+        let mut state = StateReader::open(&bytes).unwrap();
+        state.section(b"TEST", |state| {
+            assert!(state.read_bool()?);
+            assert_eq!(state.read_u16()?, 0x1234);
+            assert_eq!(state.read_u64()?, u64::MAX);
+            assert_eq!(state.read_f64()?, -1.5);
+            let mut memory = [0; 3];
+            state.read_bytes_into(&mut memory)?;
+            assert_eq!(memory, [1, 2, 3]);
+            assert_eq!(state.version(), VERSION);
+            Ok(())
+        }).unwrap();
+        state.finish().unwrap();
+    }
+
+    #[test]
+    fn test_bad_headers_are_rejected() {
+        assert_eq!(StateReader::open(b"NES\x1A").err(), Some(StateError::InvalidMagic));
+        for version in [OLDEST_SUPPORTED_VERSION - 1, VERSION + 1] {
+            let mut bytes = test_state();
+            bytes[8..10].copy_from_slice(&version.to_le_bytes());
+            assert_eq!(StateReader::open(&bytes).err(), Some(StateError::UnsupportedVersion(version)));
+        }
+    }
+
+    #[test]
+    fn test_sections_must_match() {
+        let bytes = test_state();
+        let mut state = StateReader::open(&bytes).unwrap();
+        let error = state.section(b"CPU ", |_| Ok(())).unwrap_err();
+        assert_eq!(error, StateError::UnexpectedSection { expected: "CPU ".into(), found: "TEST".into() });
+
+        // Reading too little of a section, or too much, means the layouts disagree
+        let mut state = StateReader::open(&bytes).unwrap();
+        assert!(matches!(state.section(b"TEST", |state| state.read_bool()), Err(StateError::Incompatible(_))));
+        let mut state = StateReader::open(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(state.section(b"TEST", |_| Ok(())).err(), Some(StateError::Truncated));
+    }
+}
@@ -1,9 +1,9 @@
 /// Addresses are big-endian in our memory array, but are retrieved
 /// little-endian. This function takes as input the pair of bytes
 /// retrieved, and returns the memory address they encode 
-pub fn to_address_from_bytes(bytes: (u8, u8)) -> usize {
-    let big_byte = bytes.1 as usize;
-    let little_byte = bytes.0 as usize;
+pub fn to_address_from_bytes(bytes: (u8, u8)) -> u16 {
+    let big_byte = bytes.1 as u16;
+    let little_byte = bytes.0 as u16;
     let address = big_byte << 8;
     address + little_byte
 }
@@ -20,9 +20,7 @@ pub fn to_bytes_from_address(address: u16) -> (u8, u8) {
 /// The address space is conceived of as consisting of 256-byte pages.
 /// Crossing a page boundary when addressing incurs an additional cycle
 /// depending on the instruction, so we need to know when it happens.
-pub fn was_page_boundary_crossed(address: usize, indexed_address: usize) -> bool {
-    let address = address as u16;
-    let indexed_address = indexed_address as u16;
+pub fn was_page_boundary_crossed(address: u16, indexed_address: u16) -> bool {
     let bitmask: u16 = 0xFF00;
 
     // The high byte of the address (when thought of as two bytes)